@@ -1,10 +1,15 @@
 use crate::clouds::Cloud;
+use crate::rune::v1::rune::base64_bytes;
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct ModelCreate {
     pub name: String,
-    pub cloud: Cloud,
+    /// Name of a registered cloud, or of a provider type to use the
+    /// controller's own configuration for it
+    pub cloud: String,
 }
 
 #[derive(Debug, Deserialize, Serialize)]
@@ -15,11 +20,98 @@ pub struct ModelConfigure {
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RuneAdd {
     pub name: String,
+    #[serde(flatten)]
+    pub source: RuneSource,
+    /// Values of the rune's secrets that the client resolved, such as from
+    /// env files
+    #[serde(default)]
+    pub secrets: BTreeMap<String, String>,
+}
+
+/// Where the rune being added comes from
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RuneSource {
+    /// A rune archive, as built by `uruz build`
+    Rune(#[serde(with = "base64_bytes")] Vec<u8>),
+    /// A rune in the controller's repository, as `name@revision`, `name@tag`,
+    /// or just `name` for its latest revision
+    Reference(String),
+}
+
+/// Uploads a rune archive to the controller's repository as the next revision
+/// of `name`
+#[derive(Debug, Deserialize, Serialize)]
+pub struct RuneUpload {
+    pub name: String,
+    #[serde(with = "base64_bytes")]
     pub rune: Vec<u8>,
+    /// Tag to point at the uploaded revision, moving it from any other
+    #[serde(default)]
+    pub tag: Option<String>,
 }
 
+/// Changes to a rune's config, checked and applied together as one request
 #[derive(Debug, Deserialize, Serialize)]
 pub struct RuneConfigure {
-    pub attribute: String,
-    pub value: String,
+    /// New values by key, each of the config item's type, or a string to
+    /// parse as one, such as `"3306"` for an integer
+    #[serde(default)]
+    pub config: BTreeMap<String, Value>,
+    /// Keys to reset to their defaults from the rune's metadata
+    #[serde(default)]
+    pub reset: Vec<String>,
+}
+
+/// Provider type and how to reach it. Anything left unset falls back to the
+/// controller's configuration for that provider.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
+pub enum CloudCredentials {
+    AWS {
+        region: Option<String>,
+        endpoint: Option<String>,
+        access_key_id: Option<String>,
+        secret_access_key: Option<String>,
+    },
+    Dummy,
+    GCE {
+        project: Option<String>,
+        zone: Option<String>,
+        endpoint: Option<String>,
+        token: Option<String>,
+    },
+    Kubernetes {
+        /// Contents of a kubeconfig file
+        kubeconfig: Option<String>,
+        context: Option<String>,
+    },
+    Local {
+        working_dir: Option<String>,
+        #[serde(default)]
+        binaries: HashMap<String, String>,
+    },
+}
+
+impl CloudCredentials {
+    pub fn cloud(&self) -> Cloud {
+        match self {
+            CloudCredentials::AWS { .. } => Cloud::AWS,
+            CloudCredentials::Dummy => Cloud::Dummy,
+            CloudCredentials::GCE { .. } => Cloud::GCE,
+            CloudCredentials::Kubernetes { .. } => Cloud::Kubernetes,
+            CloudCredentials::Local { .. } => Cloud::Local,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CloudCreate {
+    pub name: String,
+    pub credentials: CloudCredentials,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CloudUpdate {
+    pub credentials: CloudCredentials,
 }
@@ -1,12 +1,41 @@
+use crate::rune::v1::rune::base64_bytes;
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
 use uuid::Uuid;
 
+/// Stands in for secret values in every view of a model, which only shows
+/// whether each secret is set
+pub const REDACTED: &str = "<redacted>";
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Rune {
     pub transformers: Option<String>,
     pub react: Option<String>,
-    pub state: HashMap<String, Option<String>>,
+    /// Config values by key, with `None` for items that don't have one
+    pub state: HashMap<String, Option<Value>>,
+    /// Secret config items, kept apart from `state`, with [`REDACTED`] for
+    /// those that have a value
+    #[serde(default)]
+    pub secrets: HashMap<String, Option<String>>,
+    /// Images built for the rune, by template name
+    #[serde(default)]
+    pub images: HashMap<String, String>,
+}
+
+impl Rune {
+    /// Applies config changes, to `secrets` for secret items and to `state` for
+    /// everything else
+    pub fn configure(&mut self, config: &BTreeMap<String, Option<Value>>) {
+        for (key, value) in config {
+            match self.secrets.get_mut(key) {
+                Some(secret) => *secret = value.as_ref().map(|_| REDACTED.into()),
+                None => {
+                    self.state.insert(key.clone(), value.clone());
+                }
+            }
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
@@ -22,10 +51,10 @@ pub enum Action {
         name: String,
         rune: Rune,
     },
+    /// Sets each key to its value, or leaves it without one for `None`
     ConfigureRune {
         name: String,
-        attribute: String,
-        value: String,
+        config: BTreeMap<String, Option<Value>>,
     },
     RemoveRune {
         name: String,
@@ -39,9 +68,13 @@ pub struct Request {
     pub queued: u128,
     pub started: Option<u128>,
     pub completed: Option<u128>,
+    /// Why the request failed, if it did. Failed requests don't change the
+    /// model.
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
-#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Default, Serialize, Deserialize, Clone, PartialEq)]
 pub struct ModelConfig {
     pub foo: Option<String>,
 }
@@ -68,6 +101,54 @@ pub struct ModelState {
     pub runes: HashMap<String, Rune>,
 }
 
+/// Why a request was rejected, for requests whose errors say more than that
+/// something wasn't found
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct ErrorMessage {
+    pub message: String,
+}
+
+/// A cloud that models can be created in. Credentials are never returned.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RegisteredCloud {
+    pub name: String,
+    pub cloud: String,
+    pub builtin: bool,
+}
+
+/// A revision of a rune in the controller's repository
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RepositoryRune {
+    pub name: String,
+    pub revision: u64,
+    /// Digest of the rune's archive manifest, as printed by `uruz build`
+    pub digest: String,
+    /// Tags currently pointing at this revision
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+/// A revision of a rune along with its archive, as uploaded
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct RuneArchive {
+    pub name: String,
+    pub revision: u64,
+    pub digest: String,
+    #[serde(with = "base64_bytes")]
+    pub archive: Vec<u8>,
+}
+
+impl RuneArchive {
+    pub fn summary(&self) -> RepositoryRune {
+        RepositoryRune {
+            name: self.name.clone(),
+            revision: self.revision,
+            digest: self.digest.clone(),
+            tags: vec![],
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Model {
     pub id: String,
@@ -76,3 +157,32 @@ pub struct Model {
     pub requests: Vec<Request>,
     pub state: ModelState,
 }
+
+/// Faults injected into a model on the dummy cloud
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DummyConfig {
+    /// Delay before each request is handled, in milliseconds
+    #[serde(default)]
+    pub latency_ms: u64,
+    /// Chance between 0 and 1 that any request fails
+    #[serde(default)]
+    pub failure_probability: f64,
+    /// Number of upcoming failures per action type, such as `AddRune`
+    #[serde(default)]
+    pub failures: HashMap<String, u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DummyEvent {
+    pub action: String,
+    pub succeeded: bool,
+}
+
+/// What the dummy cloud would have deployed for a model
+#[derive(Debug, Default, Deserialize, Serialize, Clone, PartialEq)]
+pub struct DummyWorld {
+    pub destroyed: bool,
+    pub config: ModelConfig,
+    pub runes: HashMap<String, Rune>,
+    pub events: Vec<DummyEvent>,
+}
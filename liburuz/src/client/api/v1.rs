@@ -1,11 +1,27 @@
-use crate::api::v1::{Model, ModelConfigure, ModelCreate, RuneAdd, RuneConfigure};
+use crate::api::v1::{
+    CloudCreate, CloudCredentials, CloudUpdate, DummyConfig, DummyWorld, ErrorMessage, Model,
+    ModelConfigure, ModelCreate, RegisteredCloud, RepositoryRune, RuneAdd, RuneArchive,
+    RuneConfigure, RuneSource, RuneUpload,
+};
 use crate::client::error::Error;
+use crate::rune::v1::archive;
 use crate::rune::v1::rune::Rune;
+use crate::server::repository::Selector;
 use async_std::task;
-use reqwest::{Method, RequestBuilder};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use std::collections::BTreeMap;
 use std::time::Duration;
 use uuid::Uuid;
 
+/// Checks that a rune archive has the digest it's expected to
+fn check_digest(expected: &str, actual: &str) -> Result<(), Error> {
+    if expected == actual {
+        Ok(())
+    } else {
+        Err(Error::DigestMismatch(expected.into(), actual.into()))
+    }
+}
+
 pub struct Client {
     endpoint: String,
     req: reqwest::Client,
@@ -26,17 +42,34 @@ impl Client {
     {
         let mut builder = self
             .req
-            .request(method, &format!("{}/api/v1/models/{}", self.endpoint, path));
+            .request(method, &format!("{}/api/v1/{}", self.endpoint, path));
         builder = modifier(builder);
-        Ok(builder.send().await?.error_for_status()?.json().await?)
+        let response = builder.send().await?;
+        match response.status() {
+            StatusCode::BAD_REQUEST => {
+                let error: ErrorMessage = response.json().await?;
+                return Err(Error::BadRequest(error.message));
+            }
+            StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN => {
+                let error: ErrorMessage = response.json().await?;
+                return Err(Error::Unauthorized(error.message));
+            }
+            StatusCode::INTERNAL_SERVER_ERROR => {
+                let error: ErrorMessage = response.json().await?;
+                return Err(Error::ServerError(error.message));
+            }
+            _ => {}
+        }
+        Ok(response.error_for_status()?.json().await?)
     }
 
     pub async fn create_model(&self, args: &ModelCreate) -> Result<Model, Error> {
-        self.send(Method::POST, "", |r| r.json(args)).await
+        self.send(Method::POST, "models", |r| r.json(args)).await
     }
 
     pub async fn get_model(&self, model_id: &str) -> Result<Model, Error> {
-        self.send(Method::GET, model_id, |r| r).await
+        self.send(Method::GET, &format!("models/{}", model_id), |r| r)
+            .await
     }
 
     pub async fn configure_model(
@@ -44,21 +77,60 @@ impl Client {
         model_id: &str,
         args: &ModelConfigure,
     ) -> Result<Uuid, Error> {
-        self.send(Method::POST, &format!("{}/config", model_id), |r| {
+        self.send(Method::POST, &format!("models/{}/config", model_id), |r| {
             r.json(args)
         })
         .await
     }
 
     pub async fn destroy_model(&self, model_id: &str) -> Result<Uuid, Error> {
-        self.send(Method::DELETE, model_id, |r| r).await
+        self.send(Method::DELETE, &format!("models/{}", model_id), |r| r)
+            .await
     }
 
     pub async fn add_rune(&self, model_id: &str, name: &str, rune: &Rune) -> Result<Uuid, Error> {
-        self.send(Method::POST, &format!("{}/runes", model_id), |r| {
+        self.add_rune_archive(model_id, name, rune.zip()?).await
+    }
+
+    /// Adds a rune from an archive as built by `uruz build`, such as a signed
+    /// one
+    pub async fn add_rune_archive(
+        &self,
+        model_id: &str,
+        name: &str,
+        archive: Vec<u8>,
+    ) -> Result<Uuid, Error> {
+        self.add_rune_with_secrets(model_id, name, RuneSource::Rune(archive), &BTreeMap::new())
+            .await
+    }
+
+    /// Adds a rune from the controller's repository, by a reference such as
+    /// `mariadb@3`
+    pub async fn add_rune_reference(
+        &self,
+        model_id: &str,
+        name: &str,
+        reference: &str,
+    ) -> Result<Uuid, Error> {
+        let source = RuneSource::Reference(reference.into());
+        self.add_rune_with_secrets(model_id, name, source, &BTreeMap::new())
+            .await
+    }
+
+    /// Adds a rune along with values for its secrets, such as those resolved
+    /// by [`secrets::resolve_env`](crate::client::secrets::resolve_env)
+    pub async fn add_rune_with_secrets(
+        &self,
+        model_id: &str,
+        name: &str,
+        source: RuneSource,
+        secrets: &BTreeMap<String, String>,
+    ) -> Result<Uuid, Error> {
+        self.send(Method::POST, &format!("models/{}/runes", model_id), |r| {
             r.json(&RuneAdd {
                 name: name.into(),
-                rune: rune.zip().unwrap(),
+                source: source.clone(),
+                secrets: secrets.clone(),
             })
         })
         .await
@@ -72,12 +144,168 @@ impl Client {
     ) -> Result<Uuid, Error> {
         self.send(
             Method::PATCH,
-            &format!("{}/runes/{}/config", model_id, rune_name),
+            &format!("models/{}/runes/{}/config", model_id, rune_name),
             |r| r.json(&config),
         )
         .await
     }
 
+    /// Reads the values of a deployed rune's secrets, which are redacted
+    /// everywhere else, with the controller's secrets token
+    pub async fn get_rune_secrets(
+        &self,
+        model_id: &str,
+        rune_name: &str,
+        token: &str,
+    ) -> Result<BTreeMap<String, Option<String>>, Error> {
+        self.send(
+            Method::GET,
+            &format!("models/{}/runes/{}/secrets", model_id, rune_name),
+            |r| r.bearer_auth(token),
+        )
+        .await
+    }
+
+    pub async fn configure_dummy(&self, model_id: &str, config: &DummyConfig) -> Result<(), Error> {
+        self.send(
+            Method::POST,
+            &format!("models/{}/dummy/config", model_id),
+            |r| r.json(config),
+        )
+        .await
+    }
+
+    pub async fn get_dummy_world(&self, model_id: &str) -> Result<DummyWorld, Error> {
+        self.send(
+            Method::GET,
+            &format!("models/{}/dummy/world", model_id),
+            |r| r,
+        )
+        .await
+    }
+
+    pub async fn list_runes(&self) -> Result<Vec<RepositoryRune>, Error> {
+        self.send(Method::GET, "runes", |r| r).await
+    }
+
+    pub async fn list_rune_revisions(&self, name: &str) -> Result<Vec<RepositoryRune>, Error> {
+        self.send(Method::GET, &format!("runes/{}", name), |r| r)
+            .await
+    }
+
+    pub async fn upload_rune(
+        &self,
+        name: &str,
+        archive: Vec<u8>,
+        tag: Option<&str>,
+    ) -> Result<RepositoryRune, Error> {
+        self.send(Method::POST, "runes", |r| {
+            r.json(&RuneUpload {
+                name: name.into(),
+                rune: archive.clone(),
+                tag: tag.map(Into::into),
+            })
+        })
+        .await
+    }
+
+    /// Uploads a rune archive as the next revision of `name`, checking that
+    /// the controller stored it with the same digest
+    pub async fn push_rune(
+        &self,
+        name: &str,
+        archive: Vec<u8>,
+        tag: Option<&str>,
+    ) -> Result<RepositoryRune, Error> {
+        let digest = archive::digest(&archive::read(&archive)?);
+        let pushed = self.upload_rune(name, archive, tag).await?;
+        check_digest(&digest, &pushed.digest)?;
+        Ok(pushed)
+    }
+
+    /// Downloads the revision of `name` that `selector` refers to, checking
+    /// the archive against its manifest and the digest the controller has
+    /// for it
+    pub async fn pull_rune(&self, name: &str, selector: &Selector) -> Result<RuneArchive, Error> {
+        let revision = match selector {
+            Selector::Revision(revision) => *revision,
+            Selector::Latest => match self.list_rune_revisions(name).await?.last() {
+                Some(latest) => latest.revision,
+                None => return Err(Error::RuneNotFound(name.into())),
+            },
+            Selector::Tag(tag) => match self
+                .list_rune_revisions(name)
+                .await?
+                .into_iter()
+                .find(|rune| rune.tags.contains(tag))
+            {
+                Some(tagged) => tagged.revision,
+                None => return Err(Error::TagNotFound(name.into(), tag.clone())),
+            },
+        };
+        let pulled = self.get_rune(name, revision).await?;
+        check_digest(
+            &pulled.digest,
+            &archive::digest(&archive::read(&pulled.archive)?),
+        )?;
+        Ok(pulled)
+    }
+
+    pub async fn get_rune(&self, name: &str, revision: u64) -> Result<RuneArchive, Error> {
+        self.send(Method::GET, &format!("runes/{}/{}", name, revision), |r| r)
+            .await
+    }
+
+    pub async fn delete_rune(&self, name: &str, revision: u64) -> Result<(), Error> {
+        self.send(
+            Method::DELETE,
+            &format!("runes/{}/{}", name, revision),
+            |r| r,
+        )
+        .await
+    }
+
+    pub async fn list_clouds(&self) -> Result<Vec<RegisteredCloud>, Error> {
+        self.send(Method::GET, "clouds", |r| r).await
+    }
+
+    pub async fn get_cloud(&self, name: &str) -> Result<RegisteredCloud, Error> {
+        self.send(Method::GET, &format!("clouds/{}", name), |r| r)
+            .await
+    }
+
+    pub async fn create_cloud(
+        &self,
+        name: &str,
+        credentials: &CloudCredentials,
+    ) -> Result<RegisteredCloud, Error> {
+        self.send(Method::POST, "clouds", |r| {
+            r.json(&CloudCreate {
+                name: name.into(),
+                credentials: credentials.clone(),
+            })
+        })
+        .await
+    }
+
+    pub async fn update_cloud(
+        &self,
+        name: &str,
+        credentials: &CloudCredentials,
+    ) -> Result<RegisteredCloud, Error> {
+        self.send(Method::PUT, &format!("clouds/{}", name), |r| {
+            r.json(&CloudUpdate {
+                credentials: credentials.clone(),
+            })
+        })
+        .await
+    }
+
+    pub async fn delete_cloud(&self, name: &str) -> Result<(), Error> {
+        self.send(Method::DELETE, &format!("clouds/{}", name), |r| r)
+            .await
+    }
+
     pub async fn configure_model_wait(
         &self,
         model_id: &str,
@@ -105,6 +333,17 @@ impl Client {
         Ok(())
     }
 
+    pub async fn add_rune_reference_wait(
+        &self,
+        model_id: &str,
+        name: &str,
+        reference: &str,
+    ) -> Result<(), Error> {
+        let action_id = self.add_rune_reference(model_id, name, reference).await?;
+        self.wait_for_action(model_id, action_id).await?;
+        Ok(())
+    }
+
     pub async fn configure_rune_wait(
         &self,
         model_id: &str,
@@ -119,15 +358,19 @@ impl Client {
     pub async fn wait_for_action(&self, model_id: &str, uuid: Uuid) -> Result<(), Error> {
         for _ in 0u32..10 {
             let model = self.get_model(model_id).await?;
-
-            if model
+            let request = model
                 .requests
-                .iter()
-                .any(|r| r.id == uuid && r.completed.is_some())
-            {
-                return Ok(());
-            } else {
-                task::sleep(Duration::from_secs(1)).await;
+                .into_iter()
+                .find(|r| r.id == uuid && r.completed.is_some());
+
+            match request {
+                Some(request) => {
+                    return match request.error {
+                        Some(error) => Err(Error::RequestFailed(uuid, error)),
+                        None => Ok(()),
+                    }
+                }
+                None => task::sleep(Duration::from_secs(1)).await,
             }
         }
         Err(Error::TimeoutError(uuid))
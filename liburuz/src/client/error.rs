@@ -1,6 +1,7 @@
 use crate::rune::error::Error as RuneError;
 use reqwest::Error as ReqwestError;
 use serde_yaml::Error as YamlError;
+use std::fmt;
 use std::io::Error as IOError;
 use uuid::Uuid;
 use zip::result::ZipError;
@@ -12,7 +13,49 @@ pub enum Error {
     ZipError(ZipError),
     RequestError(ReqwestError),
     TimeoutError(Uuid),
+    /// A request the controller gave up on, with why it failed
+    RequestFailed(Uuid, String),
     RuneError(RuneError),
+    RuneNotFound(String),
+    /// A rune, and a tag that none of its revisions have
+    TagNotFound(String, String),
+    /// The digest a rune archive was expected to have, and the one it has
+    DigestMismatch(String, String),
+    /// A request the controller rejected, with its reason
+    BadRequest(String),
+    /// A request the controller refused to authorize, with its reason
+    Unauthorized(String),
+    /// A request the controller failed to handle, with its reason
+    ServerError(String),
+    /// A secret whose source couldn't be resolved, and why
+    SecretUnresolved(String, String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(err) => write!(f, "{}", err),
+            Error::YamlError(err) => write!(f, "{}", err),
+            Error::ZipError(err) => write!(f, "{}", err),
+            Error::RequestError(err) => write!(f, "{}", err),
+            Error::TimeoutError(id) => write!(f, "Timed out waiting for request {}", id),
+            Error::RequestFailed(id, error) => write!(f, "Request {} failed: {}", id, error),
+            Error::RuneError(err) => write!(f, "{}", err),
+            Error::RuneNotFound(name) => write!(f, "No revisions of {} in the repository", name),
+            Error::TagNotFound(name, tag) => write!(f, "No revision of {} is tagged {}", name, tag),
+            Error::DigestMismatch(expected, actual) => write!(
+                f,
+                "Rune archive has digest {}, but {} was expected",
+                actual, expected
+            ),
+            Error::BadRequest(message) => write!(f, "{}", message),
+            Error::Unauthorized(message) => write!(f, "{}", message),
+            Error::ServerError(message) => write!(f, "{}", message),
+            Error::SecretUnresolved(key, reason) => {
+                write!(f, "Can't resolve secret {}: {}", key, reason)
+            }
+        }
+    }
 }
 
 impl From<IOError> for Error {
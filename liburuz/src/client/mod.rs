@@ -1,2 +1,3 @@
 pub mod api;
 pub mod error;
+pub mod secrets;
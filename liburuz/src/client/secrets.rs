@@ -0,0 +1,70 @@
+//! Secrets resolved on the client, before a rune is added
+//!
+//! Secrets with an `env` source are read from env files next to wherever the
+//! rune is added from, so that their values never have to be in the rune. Env
+//! files hold a `NAME=value` per line, optionally quoted or prefixed with
+//! `export`, with blank lines and lines starting with `#` ignored.
+
+use crate::client::error::Error;
+use crate::rune::v1::metadata::ConfigItem;
+use crate::rune::v1::rune::Rune;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// Parses the variables in an env file
+pub fn parse_env(contents: &str) -> BTreeMap<String, String> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let line = line.strip_prefix("export ").unwrap_or(line);
+            let (name, value) = line.split_once('=')?;
+            let value = value.trim();
+            let unquoted = ['"', '\'']
+                .iter()
+                .find_map(|q| value.strip_prefix(*q)?.strip_suffix(*q))
+                .unwrap_or(value);
+            Some((name.trim().to_string(), unquoted.to_string()))
+        })
+        .collect()
+}
+
+/// Reads the value of every secret of `rune` with an `env` source, with env
+/// files relative to `dir`
+pub fn resolve_env(rune: &Rune, dir: &Path) -> Result<BTreeMap<String, String>, Error> {
+    let mut files = HashMap::new();
+    let mut secrets = BTreeMap::new();
+
+    for (key, item) in &rune.metadata.config {
+        let (file, variable) = match item {
+            ConfigItem::Secret {
+                source: Some(source),
+                ..
+            } => match source.env_variable(key) {
+                Some(env) => env,
+                None => continue,
+            },
+            _ => continue,
+        };
+
+        if !files.contains_key(file) {
+            let contents = read_to_string(dir.join(file)).map_err(|err| {
+                Error::SecretUnresolved(key.clone(), format!("can't read {}: {}", file, err))
+            })?;
+            files.insert(file, parse_env(&contents));
+        }
+        match files[file].get(&variable) {
+            Some(value) => secrets.insert(key.clone(), value.clone()),
+            None => {
+                return Err(Error::SecretUnresolved(
+                    key.clone(),
+                    format!("{} isn't set in {}", variable, file),
+                ))
+            }
+        };
+    }
+
+    Ok(secrets)
+}
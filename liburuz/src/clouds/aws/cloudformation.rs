@@ -0,0 +1,180 @@
+//! Minimal CloudFormation Query API client
+
+use crate::server::config::{AwsConfig, FargateConfig};
+use crate::server::error::Error;
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::Url;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::env;
+
+const API_VERSION: &str = "2010-05-15";
+
+#[derive(Clone, Debug)]
+struct Credentials {
+    access_key_id: String,
+    secret_access_key: String,
+}
+
+#[derive(Clone, Debug)]
+pub struct CloudFormation {
+    endpoint: String,
+    region: String,
+    credentials: Option<Credentials>,
+    fargate: FargateConfig,
+    req: reqwest::Client,
+}
+
+impl CloudFormation {
+    /// Creates a client from the controller configuration.
+    ///
+    /// Credentials fall back to `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`.
+    /// Without any, requests are sent unsigned, which is only useful against a
+    /// local stand-in.
+    pub fn new(config: &AwsConfig) -> Self {
+        let access_key_id = config
+            .access_key_id
+            .clone()
+            .or_else(|| env::var("AWS_ACCESS_KEY_ID").ok());
+        let secret_access_key = config
+            .secret_access_key
+            .clone()
+            .or_else(|| env::var("AWS_SECRET_ACCESS_KEY").ok());
+
+        Self {
+            endpoint: config.endpoint.clone().unwrap_or_else(|| {
+                format!("https://cloudformation.{}.amazonaws.com", config.region)
+            }),
+            region: config.region.clone(),
+            credentials: match (access_key_id, secret_access_key) {
+                (Some(access_key_id), Some(secret_access_key)) => Some(Credentials {
+                    access_key_id,
+                    secret_access_key,
+                }),
+                _ => None,
+            },
+            fargate: config.fargate.clone(),
+            req: reqwest::Client::new(),
+        }
+    }
+
+    /// How tasks are sized and networked
+    pub fn fargate(&self) -> &FargateConfig {
+        &self.fargate
+    }
+
+    /// Creates the stack, or updates it if it already exists
+    pub async fn deploy_stack(&self, name: &str, template: &Value) -> Result<(), Error> {
+        let body = serde_json::to_string(template)?;
+        let params = [("StackName", name), ("TemplateBody", &body)];
+
+        match self.call("CreateStack", &params).await {
+            Err(Error::CloudFormationError(code, _)) if code == "AlreadyExistsException" => {
+                match self.call("UpdateStack", &params).await {
+                    Err(Error::CloudFormationError(_, message))
+                        if message.contains("No updates are to be performed") =>
+                    {
+                        Ok(())
+                    }
+                    result => result.map(|_| ()),
+                }
+            }
+            result => result.map(|_| ()),
+        }
+    }
+
+    pub async fn delete_stack(&self, name: &str) -> Result<(), Error> {
+        self.call("DeleteStack", &[("StackName", name)]).await?;
+        Ok(())
+    }
+
+    async fn call(&self, action: &str, params: &[(&str, &str)]) -> Result<String, Error> {
+        let body = {
+            let mut serializer = form_urlencoded::Serializer::new(String::new());
+            serializer.append_pair("Action", action);
+            serializer.append_pair("Version", API_VERSION);
+            serializer.extend_pairs(params);
+            serializer.finish()
+        };
+
+        let mut builder = self
+            .req
+            .post(&self.endpoint)
+            .header("Content-Type", "application/x-www-form-urlencoded");
+
+        if let Some(credentials) = &self.credentials {
+            let now = Utc::now();
+            let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+            let authorization = self.sign(credentials, &amz_date, &body)?;
+            builder = builder
+                .header("X-Amz-Date", amz_date)
+                .header("Authorization", authorization);
+        }
+
+        let response = builder.body(body).send().await?;
+        let status = response.status();
+        let text = response.text().await?;
+
+        if status.is_success() {
+            Ok(text)
+        } else {
+            Err(Error::CloudFormationError(
+                xml_tag(&text, "Code").unwrap_or_else(|| status.to_string()),
+                xml_tag(&text, "Message").unwrap_or(text),
+            ))
+        }
+    }
+
+    /// Signs a request with AWS Signature Version 4
+    fn sign(&self, credentials: &Credentials, amz_date: &str, body: &str) -> Result<String, Error> {
+        let url = Url::parse(&self.endpoint)
+            .map_err(|err| Error::CloudFormationError("InvalidEndpoint".into(), err.to_string()))?;
+        let host = match url.port() {
+            Some(port) => format!("{}:{}", url.host_str().unwrap_or_default(), port),
+            None => url.host_str().unwrap_or_default().to_string(),
+        };
+        let date = &amz_date[..8];
+        let scope = format!("{}/{}/cloudformation/aws4_request", date, self.region);
+        let signed_headers = "content-type;host;x-amz-date";
+
+        let canonical_request = format!(
+            "POST\n{}\n\ncontent-type:application/x-www-form-urlencoded\nhost:{}\nx-amz-date:{}\n\n{}\n{}",
+            url.path(),
+            host,
+            amz_date,
+            signed_headers,
+            hex::encode(Sha256::digest(body.as_bytes())),
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes())),
+        );
+
+        let key = format!("AWS4{}", credentials.secret_access_key);
+        let key = hmac(key.as_bytes(), date);
+        let key = hmac(&key, &self.region);
+        let key = hmac(&key, "cloudformation");
+        let key = hmac(&key, "aws4_request");
+        let signature = hex::encode(hmac(&key, &string_to_sign));
+
+        Ok(format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            credentials.access_key_id, scope, signed_headers, signature
+        ))
+    }
+}
+
+fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_varkey(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+fn xml_tag(text: &str, tag: &str) -> Option<String> {
+    let start = text.find(&format!("<{}>", tag))? + tag.len() + 2;
+    let end = start + text[start..].find(&format!("</{}>", tag))?;
+    Some(text[start..end].to_string())
+}
@@ -0,0 +1,190 @@
+//! Deploys models as CloudFormation stacks, with each rune run as an ECS service
+
+pub mod cloudformation;
+
+pub use cloudformation::CloudFormation;
+
+use crate::clouds::merge;
+use crate::rune::v1::rune::Rune;
+use crate::rune::v1::template::Template;
+use crate::server::config::FargateConfig;
+use crate::server::error::Error;
+use crate::server::model::Model;
+use serde_json::{json, to_value, Map, Value};
+use std::collections::BTreeMap;
+
+/// Converts a rune name such as `pipelines-ui` to a CloudFormation logical ID
+/// such as `PipelinesUi`. Names that only differ in punctuation, such as
+/// `pipelines_ui`, convert to the same ID, so can't be in the same stack.
+fn logical_id(name: &str) -> String {
+    name.split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|part| !part.is_empty())
+        .map(|part| {
+            let mut chars = part.chars();
+            match chars.next() {
+                Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn container_definition(rune: &Rune, template: &Template) -> Result<Value, Error> {
+    let mut container = Map::new();
+    container.insert("Name".into(), json!(template.name));
+    container.insert("Essential".into(), json!(true));
+
+    if let Some(image) = rune.image(template) {
+        container.insert("Image".into(), json!(image));
+    }
+    if !template.command.is_empty() {
+        container.insert("EntryPoint".into(), json!(template.command));
+    }
+    if !template.args.is_empty() {
+        container.insert("Command".into(), json!(template.args));
+    }
+
+    let environment: BTreeMap<_, _> = template.environment.iter().collect();
+    container.insert(
+        "Environment".into(),
+        environment
+            .into_iter()
+            .map(|(name, value)| json!({"Name": name, "Value": value}))
+            .collect(),
+    );
+    container.insert(
+        "PortMappings".into(),
+        template
+            .ports
+            .iter()
+            .filter_map(|port| port.container_port.as_port())
+            .map(|port| json!({"ContainerPort": port, "Protocol": "tcp"}))
+            .collect(),
+    );
+
+    let mut container = Value::Object(container);
+    if let Some(include) = template.include_for("aws") {
+        merge(&mut container, &to_value(include)?);
+    }
+    Ok(container)
+}
+
+/// Renders the CloudFormation template for a model containing `runes`, with
+/// each run as a Fargate task as described by `fargate`
+pub fn render(
+    model: &str,
+    fargate: &FargateConfig,
+    runes: &BTreeMap<String, Rune>,
+) -> Result<Value, Error> {
+    let mut resources = Map::new();
+    resources.insert(
+        "Cluster".into(),
+        json!({
+            "Type": "AWS::ECS::Cluster",
+            "Properties": {"ClusterName": model},
+        }),
+    );
+
+    let assign_public_ip = if fargate.assign_public_ip {
+        "ENABLED"
+    } else {
+        "DISABLED"
+    };
+    let mut ids: BTreeMap<String, &str> = BTreeMap::new();
+    for (name, rune) in runes {
+        let id = logical_id(name);
+        if let Some(other) = ids.insert(id.clone(), name) {
+            return Err(Error::RuneNameConflict(other.into(), name.clone()));
+        }
+        let containers = rune
+            .template
+            .iter()
+            .map(|template| container_definition(rune, template))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        resources.insert(
+            format!("{}TaskDefinition", id),
+            json!({
+                "Type": "AWS::ECS::TaskDefinition",
+                "Properties": {
+                    "Family": format!("{}-{}", model, name),
+                    "Cpu": fargate.cpu,
+                    "Memory": fargate.memory,
+                    "NetworkMode": "awsvpc",
+                    "RequiresCompatibilities": ["FARGATE"],
+                    "ContainerDefinitions": containers,
+                },
+            }),
+        );
+        resources.insert(
+            format!("{}Service", id),
+            json!({
+                "Type": "AWS::ECS::Service",
+                "Properties": {
+                    "ServiceName": name,
+                    "Cluster": {"Ref": "Cluster"},
+                    "TaskDefinition": {"Ref": format!("{}TaskDefinition", id)},
+                    "DesiredCount": 1,
+                    "LaunchType": "FARGATE",
+                    "NetworkConfiguration": {
+                        "AwsvpcConfiguration": {
+                            "AssignPublicIp": assign_public_ip,
+                            "SecurityGroups": fargate.security_groups,
+                            "Subnets": fargate.subnets,
+                        },
+                    },
+                },
+            }),
+        );
+    }
+
+    Ok(json!({
+        "AWSTemplateFormatVersion": "2010-09-09",
+        "Description": format!("uruz model {}", model),
+        "Resources": resources,
+    }))
+}
+
+pub async fn create_model(client: CloudFormation, name: &str) -> Result<(), Error> {
+    client
+        .deploy_stack(name, &render(name, client.fargate(), &BTreeMap::new())?)
+        .await
+}
+
+pub async fn configure_model() -> Result<(), Error> {
+    Err(Error::Unsupported("Configuring models on AWS".into()))
+}
+
+pub async fn destroy_model(client: CloudFormation, model: &Model) -> Result<(), Error> {
+    client.delete_stack(&model.name).await
+}
+
+pub async fn add_rune(
+    client: CloudFormation,
+    model: &Model,
+    runes: &BTreeMap<String, Rune>,
+) -> Result<(), Error> {
+    client
+        .deploy_stack(&model.name, &render(&model.name, client.fargate(), runes)?)
+        .await
+}
+
+pub async fn configure_rune(
+    client: CloudFormation,
+    model: &Model,
+    runes: &BTreeMap<String, Rune>,
+) -> Result<(), Error> {
+    client
+        .deploy_stack(&model.name, &render(&model.name, client.fargate(), runes)?)
+        .await
+}
+
+pub async fn remove_rune(
+    client: CloudFormation,
+    model: &Model,
+    runes: &BTreeMap<String, Rune>,
+) -> Result<(), Error> {
+    client
+        .deploy_stack(&model.name, &render(&model.name, client.fargate(), runes)?)
+        .await
+}
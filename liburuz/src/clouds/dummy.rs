@@ -1,26 +1,133 @@
+//! A cloud that deploys nothing
+//!
+//! Each model keeps an in-memory record of what would have been deployed,
+//! along with every request it saw. Latency and failures can be injected per
+//! model to exercise the controller's handling of slow or broken clouds.
+
+use crate::api::v1::{DummyConfig, DummyEvent, DummyWorld};
 use crate::rune::v1::rune::Rune;
 use crate::server::error::Error;
+use crate::server::model::Model;
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::delay_for;
+use uuid::Uuid;
+
+#[derive(Clone, Default)]
+pub struct Dummy {
+    models: Arc<Mutex<HashMap<Uuid, (DummyConfig, DummyWorld)>>>,
+}
+
+impl Dummy {
+    pub fn configure(&self, model_id: &Uuid, config: DummyConfig) {
+        let mut models = self.models.lock().unwrap();
+        models.entry(*model_id).or_default().0 = config;
+    }
+
+    pub fn world(&self, model_id: &Uuid) -> DummyWorld {
+        let models = self.models.lock().unwrap();
+        models
+            .get(model_id)
+            .map(|(_, world)| world.clone())
+            .unwrap_or_default()
+    }
+
+    /// Waits out the injected latency, then decides whether this attempt at
+    /// `action` fails. The attempt is recorded either way, and `update` is
+    /// applied to the world only if it succeeds.
+    async fn attempt<F>(&self, model_id: &Uuid, action: &str, update: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut DummyWorld),
+    {
+        let latency = {
+            let models = self.models.lock().unwrap();
+            models
+                .get(model_id)
+                .map_or(0, |(config, _)| config.latency_ms)
+        };
+        if latency > 0 {
+            delay_for(Duration::from_millis(latency)).await;
+        }
+
+        let mut models = self.models.lock().unwrap();
+        let (config, world) = models.entry(*model_id).or_default();
+        let scripted = match config.failures.get_mut(action) {
+            Some(remaining) if *remaining > 0 => {
+                *remaining -= 1;
+                true
+            }
+            _ => false,
+        };
+        let failed = scripted || rand::random::<f64>() < config.failure_probability;
+
+        world.events.push(DummyEvent {
+            action: action.into(),
+            succeeded: !failed,
+        });
+
+        if failed {
+            Err(Error::InjectedFailure(action.into()))
+        } else {
+            update(world);
+            Ok(())
+        }
+    }
+}
 
-pub async fn create_model(_name: &str) -> Result<(), Error> {
-    Ok(())
+pub async fn create_model(dummy: Dummy, model: &Model) -> Result<(), Error> {
+    dummy.attempt(&model.id, "CreateModel", |_| {}).await
 }
 
-pub async fn configure_model() -> Result<(), Error> {
-    Ok(())
+pub async fn configure_model(
+    dummy: Dummy,
+    model: &Model,
+    foo: &Option<String>,
+) -> Result<(), Error> {
+    dummy
+        .attempt(&model.id, "ConfigureModel", |world| {
+            world.config.foo = foo.clone()
+        })
+        .await
 }
 
-pub async fn destroy_model() -> Result<(), Error> {
-    Ok(())
+pub async fn destroy_model(dummy: Dummy, model: &Model) -> Result<(), Error> {
+    dummy
+        .attempt(&model.id, "DestroyModel", |world| {
+            world.destroyed = true;
+            world.runes.clear();
+        })
+        .await
 }
 
-pub async fn add_rune(_name: &str, _rune: &Rune) -> Result<(), Error> {
-    Ok(())
+pub async fn add_rune(dummy: Dummy, model: &Model, name: &str, rune: &Rune) -> Result<(), Error> {
+    dummy
+        .attempt(&model.id, "AddRune", |world| {
+            world.runes.insert(name.into(), rune.clone().into());
+        })
+        .await
 }
 
-pub async fn configure_rune(_name: &str, _attr: &str, _val: &str) -> Result<(), Error> {
-    Ok(())
+pub async fn configure_rune(
+    dummy: Dummy,
+    model: &Model,
+    name: &str,
+    config: &BTreeMap<String, Option<Value>>,
+) -> Result<(), Error> {
+    dummy
+        .attempt(&model.id, "ConfigureRune", |world| {
+            if let Some(rune) = world.runes.get_mut(name) {
+                rune.configure(config);
+            }
+        })
+        .await
 }
 
-pub async fn remove_rune(_name: &str) -> Result<(), Error> {
-    Ok(())
+pub async fn remove_rune(dummy: Dummy, model: &Model, name: &str) -> Result<(), Error> {
+    dummy
+        .attempt(&model.id, "RemoveRune", |world| {
+            world.runes.remove(name);
+        })
+        .await
 }
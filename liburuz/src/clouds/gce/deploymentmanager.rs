@@ -0,0 +1,98 @@
+//! Minimal Cloud Deployment Manager v2 client
+
+use crate::server::config::GceConfig;
+use crate::server::error::Error;
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde_json::{json, Value};
+use std::env;
+
+#[derive(Clone, Debug)]
+pub struct DeploymentManager {
+    endpoint: String,
+    project: String,
+    zone: String,
+    token: Option<String>,
+    req: reqwest::Client,
+}
+
+impl DeploymentManager {
+    /// Creates a client from the controller configuration.
+    ///
+    /// The OAuth access token falls back to `GOOGLE_OAUTH_ACCESS_TOKEN`.
+    /// Without one, requests are sent unauthenticated, which is only useful
+    /// against a local stand-in.
+    pub fn new(config: &GceConfig) -> Self {
+        Self {
+            endpoint: config
+                .endpoint
+                .clone()
+                .unwrap_or_else(|| "https://www.googleapis.com".into()),
+            project: config.project.clone(),
+            zone: config.zone.clone(),
+            token: config
+                .token
+                .clone()
+                .or_else(|| env::var("GOOGLE_OAUTH_ACCESS_TOKEN").ok()),
+            req: reqwest::Client::new(),
+        }
+    }
+
+    /// The zone that instances are created in
+    pub fn zone(&self) -> &str {
+        &self.zone
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let builder = self.req.request(
+            method,
+            &format!(
+                "{}/deploymentmanager/v2/projects/{}/global/deployments{}",
+                self.endpoint, self.project, path
+            ),
+        );
+        match &self.token {
+            Some(token) => builder.bearer_auth(token),
+            None => builder,
+        }
+    }
+
+    async fn send(&self, builder: RequestBuilder) -> Result<Option<Value>, Error> {
+        let response = builder.send().await?;
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(response.json().await?)),
+            status => Err(Error::DeploymentManagerError(
+                status.to_string(),
+                response.text().await?,
+            )),
+        }
+    }
+
+    /// Creates the deployment, or updates it if it already exists
+    pub async fn deploy(&self, name: &str, config: &Value) -> Result<(), Error> {
+        let mut body = json!({
+            "name": name,
+            "target": {"config": {"content": serde_yaml::to_string(config)?}},
+        });
+
+        let path = format!("/{}", name);
+        match self.send(self.request(Method::GET, &path)).await? {
+            Some(existing) => {
+                body["fingerprint"] = existing["fingerprint"].clone();
+                self.send(self.request(Method::PUT, &path).json(&body))
+                    .await?;
+            }
+            None => {
+                self.send(self.request(Method::POST, "").json(&body))
+                    .await?;
+            }
+        }
+        Ok(())
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), Error> {
+        self.send(self.request(Method::DELETE, &format!("/{}", name)))
+            .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,168 @@
+//! Deploys models as Cloud Deployment Manager deployments, with each rune run
+//! as a Container-Optimized OS instance
+
+pub mod deploymentmanager;
+
+pub use deploymentmanager::DeploymentManager;
+
+use crate::clouds::merge;
+use crate::rune::v1::rune::Rune;
+use crate::rune::v1::template::Template;
+use crate::server::error::Error;
+use crate::server::model::Model;
+use serde_json::{json, to_value, Map, Value};
+use std::collections::BTreeMap;
+
+fn container(rune: &Rune, template: &Template) -> Result<Value, Error> {
+    let mut container = Map::new();
+    container.insert("name".into(), json!(template.name));
+
+    if let Some(image) = rune.image(template) {
+        container.insert("image".into(), json!(image));
+    }
+    if !template.command.is_empty() {
+        container.insert("command".into(), json!(template.command));
+    }
+    if !template.args.is_empty() {
+        container.insert("args".into(), json!(template.args));
+    }
+
+    let environment: BTreeMap<_, _> = template.environment.iter().collect();
+    container.insert(
+        "env".into(),
+        environment
+            .into_iter()
+            .map(|(name, value)| json!({"name": name, "value": value}))
+            .collect(),
+    );
+
+    let mut container = Value::Object(container);
+    if let Some(include) = template.include_for("gce") {
+        merge(&mut container, &to_value(include)?);
+    }
+    Ok(container)
+}
+
+/// Renders the Deployment Manager configuration for a model containing
+/// `runes`, with instances placed in `zone`.
+///
+/// Container-Optimized OS only runs the first container it's declared, so
+/// runes with more than one template can't be deployed. Containers share the
+/// instance's network, with a firewall rule letting the rest of the default
+/// network reach their ports.
+pub fn render(model: &str, zone: &str, runes: &BTreeMap<String, Rune>) -> Result<Value, Error> {
+    let mut resources = vec![];
+
+    for (name, rune) in runes {
+        if rune.template.len() > 1 {
+            return Err(Error::Unsupported(format!(
+                "Deploying rune {}, which has more than one template, to GCE",
+                name
+            )));
+        }
+        let containers = rune
+            .template
+            .iter()
+            .map(|template| container(rune, template))
+            .collect::<Result<Vec<_>, _>>()?;
+        let declaration = json!({
+            "spec": {
+                "containers": containers,
+                "restartPolicy": "Always",
+            },
+        });
+
+        let instance = format!("{}-{}", model, name);
+        let ports: Vec<_> = rune
+            .template
+            .iter()
+            .flat_map(|template| &template.ports)
+            .filter_map(|port| port.container_port.as_port())
+            .map(|port| port.to_string())
+            .collect();
+        if !ports.is_empty() {
+            resources.push(json!({
+                "name": format!("{}-ports", instance),
+                "type": "compute.v1.firewall",
+                "properties": {
+                    "network": "global/networks/default",
+                    "sourceRanges": ["10.128.0.0/9"],
+                    "targetTags": [instance],
+                    "allowed": [{"IPProtocol": "tcp", "ports": ports}],
+                },
+            }));
+        }
+
+        resources.push(json!({
+            "name": instance,
+            "type": "compute.v1.instance",
+            "properties": {
+                "zone": zone,
+                "machineType": format!("zones/{}/machineTypes/e2-small", zone),
+                "labels": {"uruz-model": model, "uruz-rune": name},
+                "tags": {"items": [instance]},
+                "disks": [{
+                    "deviceName": "boot",
+                    "type": "PERSISTENT",
+                    "boot": true,
+                    "autoDelete": true,
+                    "initializeParams": {
+                        "sourceImage": "projects/cos-cloud/global/images/family/cos-stable",
+                    },
+                }],
+                "networkInterfaces": [{
+                    "network": "global/networks/default",
+                    "accessConfigs": [{"name": "External NAT", "type": "ONE_TO_ONE_NAT"}],
+                }],
+                "metadata": {
+                    "items": [{
+                        "key": "gce-container-declaration",
+                        "value": serde_yaml::to_string(&declaration)?,
+                    }],
+                },
+            },
+        }));
+    }
+
+    Ok(json!({ "resources": resources }))
+}
+
+pub async fn create_model(client: DeploymentManager, name: &str) -> Result<(), Error> {
+    let config = render(name, client.zone(), &BTreeMap::new())?;
+    client.deploy(name, &config).await
+}
+
+pub async fn configure_model() -> Result<(), Error> {
+    Err(Error::Unsupported("Configuring models on GCE".into()))
+}
+
+pub async fn destroy_model(client: DeploymentManager, model: &Model) -> Result<(), Error> {
+    client.delete(&model.name).await
+}
+
+pub async fn add_rune(
+    client: DeploymentManager,
+    model: &Model,
+    runes: &BTreeMap<String, Rune>,
+) -> Result<(), Error> {
+    let config = render(&model.name, client.zone(), runes)?;
+    client.deploy(&model.name, &config).await
+}
+
+pub async fn configure_rune(
+    client: DeploymentManager,
+    model: &Model,
+    runes: &BTreeMap<String, Rune>,
+) -> Result<(), Error> {
+    let config = render(&model.name, client.zone(), runes)?;
+    client.deploy(&model.name, &config).await
+}
+
+pub async fn remove_rune(
+    client: DeploymentManager,
+    model: &Model,
+    runes: &BTreeMap<String, Rune>,
+) -> Result<(), Error> {
+    let config = render(&model.name, client.zone(), runes)?;
+    client.deploy(&model.name, &config).await
+}
@@ -1,52 +1,220 @@
+use crate::clouds::merge;
 use crate::rune::v1::rune::Rune;
+use crate::rune::v1::template::Template;
+use crate::server::config::KubernetesConfig;
 use crate::server::error::Error;
-use k8s_openapi::api::core::v1::Namespace;
-use kube::{Api, Client};
+use k8s_openapi::api::apps::v1::{Deployment, DeploymentSpec};
+use k8s_openapi::api::core::v1::{
+    Container, ContainerPort, EnvVar, Namespace, PodSpec, PodTemplateSpec,
+};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, ObjectMeta};
+use kube::api::{DeleteParams, PatchParams};
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use kube::{Api, Client, Config};
+use serde_json::{from_value, to_value, to_vec};
+use std::collections::BTreeMap;
 
-pub async fn create_model(name: &str) -> Result<(), Error> {
-    let kube_client = Client::try_default().await.unwrap();
-    let namespaces: Api<Namespace> = Api::all(kube_client);
+/// Builds a client from the controller configuration.
+///
+/// An explicit kubeconfig path or context must load, otherwise the controller
+/// refuses to start. Without either, the usual kubeconfig inference is tried,
+/// and the Kubernetes cloud is left unconfigured if that fails.
+pub async fn connect(config: &KubernetesConfig) -> Result<Option<Client>, Error> {
+    let kubeconfig = match &config.kubeconfig {
+        Some(path) => Some(Kubeconfig::read_from(path)?),
+        None => None,
+    };
 
-    match namespaces.get(name).await {
-        Ok(ns) => {
-            println!(
-                "Found namespace {:?}\n\n{:?}\n\n{:?}",
-                ns, ns.metadata, ns.status
-            );
-            Err(Error::ModelAlreadyExists(
-                "Namespace already exists!".into(),
-            ))
+    connect_with(kubeconfig, config.context.clone()).await
+}
+
+/// Builds a client from an already loaded kubeconfig, such as one given with a
+/// cloud registration, falling back to the default kubeconfig without one
+pub async fn connect_with(
+    kubeconfig: Option<Kubeconfig>,
+    context: Option<String>,
+) -> Result<Option<Client>, Error> {
+    let options = KubeConfigOptions {
+        context: context.clone(),
+        cluster: None,
+        user: None,
+    };
+
+    let kube_config = match (kubeconfig, context) {
+        (Some(kubeconfig), _) => Config::from_custom_kubeconfig(kubeconfig, &options).await?,
+        (None, Some(_)) => Config::from_kubeconfig(&options).await?,
+        (None, None) => match Config::infer().await {
+            Ok(c) => c,
+            Err(_) => return Ok(None),
+        },
+    };
+
+    Ok(Some(Client::new(kube_config)))
+}
+
+fn labels(rune_name: &str) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    labels.insert("uruz.rune".into(), rune_name.into());
+    labels
+}
+
+/// Builds a template's container, with its `include.kubernetes` section merged
+/// into it
+fn container(rune: &Rune, template: &Template) -> Result<Container, Error> {
+    let container = Container {
+        name: template.name.clone(),
+        image: rune.image(template).map(String::from),
+        command: Some(template.command.clone()),
+        args: Some(template.args.clone()),
+        env: Some(
+            template
+                .environment
+                .iter()
+                .map(|(name, value)| EnvVar {
+                    name: name.clone(),
+                    value: Some(value.clone()),
+                    value_from: None,
+                })
+                .collect(),
+        ),
+        ports: Some(
+            template
+                .ports
+                .iter()
+                .filter_map(|port| {
+                    Some(ContainerPort {
+                        name: Some(port.name.clone()),
+                        container_port: port.container_port.as_port()?.into(),
+                        ..Default::default()
+                    })
+                })
+                .collect(),
+        ),
+        ..Default::default()
+    };
+
+    match template.include_for("kubernetes") {
+        Some(include) => {
+            let mut container = to_value(container)?;
+            merge(&mut container, &to_value(include)?);
+            Ok(from_value(container)?)
         }
+        None => Ok(container),
+    }
+}
+
+fn deployment(name: &str, rune: &Rune) -> Result<Deployment, Error> {
+    let containers = rune
+        .template
+        .iter()
+        .map(|t| container(rune, t))
+        .collect::<Result<_, _>>()?;
+    Ok(Deployment {
+        metadata: Some(ObjectMeta {
+            name: Some(name.into()),
+            labels: Some(labels(name)),
+            ..Default::default()
+        }),
+        spec: Some(DeploymentSpec {
+            selector: LabelSelector {
+                match_labels: Some(labels(name)),
+                match_expressions: None,
+            },
+            template: PodTemplateSpec {
+                metadata: Some(ObjectMeta {
+                    labels: Some(labels(name)),
+                    ..Default::default()
+                }),
+                spec: Some(PodSpec {
+                    containers,
+                    ..Default::default()
+                }),
+            },
+            ..Default::default()
+        }),
+        status: None,
+    })
+}
+
+async fn apply_namespace(client: Client, name: &str) -> Result<(), Error> {
+    let namespaces: Api<Namespace> = Api::all(client);
+    let namespace = Namespace {
+        metadata: Some(ObjectMeta {
+            name: Some(name.into()),
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    namespaces
+        .patch(name, &PatchParams::default_apply(), to_vec(&namespace)?)
+        .await?;
+    Ok(())
+}
+
+pub async fn create_model(client: Client, name: &str) -> Result<(), Error> {
+    let namespaces: Api<Namespace> = Api::all(client.clone());
+
+    match namespaces.get(name).await {
+        Ok(_) => Err(Error::ModelAlreadyExists(
+            "Namespace already exists!".into(),
+        )),
         Err(kube::Error::Api(err)) => match &err.reason[..] {
-            "NotFound" => Ok(()),
-            _ => {
-                println!("Got unhandled error: {:?}", err);
-                Err(err.into())
-            }
+            "NotFound" => apply_namespace(client, name).await,
+            _ => Err(err.into()),
         },
-        Err(err) => {
-            println!("Got unhandled error: {:?}", err);
-            Err(err.into())
-        }
+        Err(err) => Err(err.into()),
     }
 }
 
 pub async fn configure_model() -> Result<(), Error> {
-    unimplemented!()
+    Err(Error::Unsupported(
+        "Configuring models on the kubernetes cloud".into(),
+    ))
 }
 
-pub async fn destroy_model() -> Result<(), Error> {
-    unimplemented!()
+pub async fn destroy_model(client: Client, model: &str) -> Result<(), Error> {
+    let namespaces: Api<Namespace> = Api::all(client);
+
+    match namespaces.delete(model, &DeleteParams::default()).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(err)) if err.reason == "NotFound" => Ok(()),
+        Err(err) => Err(err.into()),
+    }
 }
 
-pub async fn add_rune(_name: &str, _rune: &Rune) -> Result<(), Error> {
-    unimplemented!()
+pub async fn add_rune(client: Client, model: &str, name: &str, rune: &Rune) -> Result<(), Error> {
+    apply_namespace(client.clone(), model).await?;
+
+    let deployments: Api<Deployment> = Api::namespaced(client, model);
+    deployments
+        .patch(
+            name,
+            &PatchParams::default_apply(),
+            to_vec(&deployment(name, rune)?)?,
+        )
+        .await?;
+    Ok(())
 }
 
-pub async fn configure_rune(_name: &str, _attr: &str, _val: &str) -> Result<(), Error> {
-    unimplemented!()
+/// Reapplies every rune, since the configured rune's relations see its
+/// config too. Kubernetes only rolls out deployments that actually changed.
+pub async fn configure_rune(
+    client: Client,
+    model: &str,
+    runes: &BTreeMap<String, Rune>,
+) -> Result<(), Error> {
+    for (name, rune) in runes {
+        add_rune(client.clone(), model, name, rune).await?;
+    }
+    Ok(())
 }
 
-pub async fn remove_rune(_name: &str) -> Result<(), Error> {
-    unimplemented!()
+pub async fn remove_rune(client: Client, model: &str, name: &str) -> Result<(), Error> {
+    let deployments: Api<Deployment> = Api::namespaced(client, model);
+
+    match deployments.delete(name, &DeleteParams::default()).await {
+        Ok(_) => Ok(()),
+        Err(kube::Error::Api(err)) if err.reason == "NotFound" => Ok(()),
+        Err(err) => Err(err.into()),
+    }
 }
@@ -0,0 +1,266 @@
+//! Runs runes as supervised processes on the controller host
+//!
+//! Container images are ignored. Each template is run from its `command`,
+//! with the program optionally replaced by a per-rune binary from the
+//! controller configuration. Processes for a rune run in their own directory
+//! under the configured working directory, and are restarted if they exit.
+
+use crate::rune::v1::rune::Rune;
+use crate::rune::v1::template::Template;
+use crate::server::config::LocalConfig;
+use crate::server::error::Error;
+use crate::server::model::{check_name, Model};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{create_dir_all, OpenOptions};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::process::Command;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use tokio::time::delay_for;
+
+/// How a single template is run
+#[derive(Clone, Debug, PartialEq)]
+struct Process {
+    program: String,
+    args: Vec<String>,
+    environment: BTreeMap<String, String>,
+    directory: PathBuf,
+    log: PathBuf,
+}
+
+impl Process {
+    fn command(&self) -> Result<Command, Error> {
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.log)?;
+        let mut command = Command::new(&self.program);
+        command
+            .args(&self.args)
+            .envs(&self.environment)
+            .current_dir(&self.directory)
+            .stdin(Stdio::null())
+            .stdout(log.try_clone()?)
+            .stderr(log)
+            .kill_on_drop(true);
+        Ok(command)
+    }
+}
+
+/// Restarts `process` whenever it exits, until told to stop
+async fn supervise(process: Process, mut stop: oneshot::Receiver<()>) {
+    let mut backoff = Duration::from_secs(1);
+
+    loop {
+        let child = process
+            .command()
+            .and_then(|mut command| command.spawn().map_err(Error::from));
+
+        match child {
+            Ok(mut child) => {
+                tokio::select! {
+                    _ = &mut child => {}
+                    _ = &mut stop => {
+                        // Waited on, so it doesn't linger as a zombie
+                        child.kill().ok();
+                        (&mut child).await.ok();
+                        return;
+                    }
+                }
+            }
+            Err(err) => eprintln!("Couldn't start {}: {:?}", process.program, err),
+        }
+
+        tokio::select! {
+            _ = delay_for(backoff) => {}
+            _ = &mut stop => return,
+        }
+        backoff = std::cmp::min(backoff * 2, Duration::from_secs(30));
+    }
+}
+
+/// A process being supervised, along with how to stop it
+struct Supervised {
+    process: Process,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+#[derive(Clone)]
+pub struct Local {
+    config: LocalConfig,
+    processes: Arc<Mutex<HashMap<(String, String), Vec<Supervised>>>>,
+}
+
+impl Local {
+    pub fn new(config: &LocalConfig) -> Self {
+        Self {
+            config: config.clone(),
+            processes: Default::default(),
+        }
+    }
+
+    fn process(&self, model: &str, name: &str, template: &Template) -> Result<Process, Error> {
+        let mut command = template.command.iter();
+        let program = match (self.config.binaries.get(name), command.next()) {
+            (Some(binary), _) => binary.clone(),
+            (None, Some(program)) => program.clone(),
+            (None, None) => {
+                return Err(Error::LocalProcessError(format!(
+                    "Template {} of rune {} has no command and no local binary override",
+                    template.name, name
+                )))
+            }
+        };
+
+        let mut environment = template.environment.clone();
+        for port in &template.ports {
+            // Rendering checks ports already, but one that somehow wasn't
+            // rendered mustn't go missing from the environment unnoticed
+            port.check(name, &template.name)?;
+            environment.insert(
+                format!("PORT_{}", port.name.to_uppercase().replace('-', "_")),
+                port.container_port.to_string(),
+            );
+        }
+
+        // Names are checked when models and runes are added, but the working
+        // directory mustn't be escaped by any that somehow weren't
+        for component in &[model, name, &template.name] {
+            check_name(component)?;
+        }
+        let directory = PathBuf::from(&self.config.working_dir)
+            .join(model)
+            .join(name);
+
+        Ok(Process {
+            program,
+            args: command.chain(template.args.iter()).cloned().collect(),
+            environment,
+            log: directory.join(format!("{}.log", template.name)),
+            directory,
+        })
+    }
+
+    fn processes(&self, model: &str, name: &str, rune: &Rune) -> Result<Vec<Process>, Error> {
+        rune.template
+            .iter()
+            .map(|t| self.process(model, name, t))
+            .collect()
+    }
+
+    fn start(&self, model: &str, name: &str, rune: &Rune) -> Result<(), Error> {
+        let processes = self.processes(model, name, rune)?;
+
+        let mut supervised = vec![];
+        for process in processes {
+            create_dir_all(&process.directory)?;
+            let (stop, stopped) = oneshot::channel();
+            let task = tokio::spawn(supervise(process.clone(), stopped));
+            supervised.push(Supervised {
+                process,
+                stop,
+                task,
+            });
+        }
+
+        self.processes
+            .lock()
+            .unwrap()
+            .insert((model.into(), name.into()), supervised);
+        Ok(())
+    }
+
+    /// Stops the processes of `name`, or of every rune in `model`, waiting
+    /// until they've exited
+    async fn stop(&self, model: &str, name: Option<&str>) {
+        let stopped: Vec<_> = {
+            let mut processes = self.processes.lock().unwrap();
+            let keys: Vec<_> = processes
+                .keys()
+                .filter(|(m, n)| m == model && name.map_or(true, |name| n == name))
+                .cloned()
+                .collect();
+            keys.iter()
+                .flat_map(|key| processes.remove(key).unwrap_or_default())
+                .collect()
+        };
+
+        for supervised in stopped {
+            supervised.stop.send(()).ok();
+            supervised.task.await.ok();
+        }
+    }
+
+    /// Starts the processes of every rune in `runes` that isn't already
+    /// running, such as after the controller restarts
+    pub fn restore(&self, model: &str, runes: &BTreeMap<String, Rune>) -> Result<(), Error> {
+        for (name, rune) in runes {
+            let key = (model.to_string(), name.clone());
+            if !self.processes.lock().unwrap().contains_key(&key) {
+                self.start(model, name, rune)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restarts every rune in `runes` that would now run differently than it
+    /// does, such as one whose config or relations changed
+    async fn restart_changed(
+        &self,
+        model: &str,
+        runes: &BTreeMap<String, Rune>,
+    ) -> Result<(), Error> {
+        for (name, rune) in runes {
+            let processes = self.processes(model, name, rune)?;
+            let running: Option<Vec<Process>> = self
+                .processes
+                .lock()
+                .unwrap()
+                .get(&(model.into(), name.clone()))
+                .map(|running| running.iter().map(|s| s.process.clone()).collect());
+
+            if running.as_ref() != Some(&processes) {
+                self.stop(model, Some(name)).await;
+                self.start(model, name, rune)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub async fn create_model(_name: &str) -> Result<(), Error> {
+    Ok(())
+}
+
+pub async fn configure_model() -> Result<(), Error> {
+    Err(Error::Unsupported(
+        "Configuring models on the local cloud".into(),
+    ))
+}
+
+pub async fn destroy_model(local: Local, model: &Model) -> Result<(), Error> {
+    local.stop(&model.name, None).await;
+    Ok(())
+}
+
+pub async fn add_rune(local: Local, model: &Model, name: &str, rune: &Rune) -> Result<(), Error> {
+    local.stop(&model.name, Some(name)).await;
+    local.start(&model.name, name, rune)
+}
+
+pub async fn configure_rune(
+    local: Local,
+    model: &Model,
+    runes: &BTreeMap<String, Rune>,
+) -> Result<(), Error> {
+    local.restart_changed(&model.name, runes).await
+}
+
+pub async fn remove_rune(local: Local, model: &Model, name: &str) -> Result<(), Error> {
+    local.stop(&model.name, Some(name)).await;
+    Ok(())
+}
@@ -1,19 +1,147 @@
 pub mod aws;
 pub mod dummy;
+pub mod gce;
 pub mod kubernetes;
+pub mod local;
 
+use crate::api::v1::CloudCredentials;
+use crate::images::{self, ImageBuilder};
+use crate::rune::v1::rune::Rune;
+use crate::server::config::{AwsConfig, Config, GceConfig, LocalConfig};
 use crate::server::error::Error;
-use crate::server::model::{Action, Active, Completed};
+use crate::server::model::{redact, Action, Active, Completed, Model};
 use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
 use std::future::Future;
 use std::string::ToString;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub enum Cloud {
     AWS,
     Dummy,
+    GCE,
     Kubernetes,
+    Local,
+}
+
+/// Clients for the clouds that need them, built once from the controller
+/// configuration and handed to each provider call.
+#[derive(Clone)]
+pub struct Providers {
+    pub aws: aws::CloudFormation,
+    pub dummy: dummy::Dummy,
+    pub gce: gce::DeploymentManager,
+    pub images: Arc<dyn ImageBuilder>,
+    pub kubernetes: Option<kube::Client>,
+    pub local: local::Local,
+}
+
+impl Providers {
+    pub async fn from_config(config: &Config) -> Result<Self, Error> {
+        Ok(Self {
+            aws: aws::CloudFormation::new(&config.aws),
+            dummy: Default::default(),
+            gce: gce::DeploymentManager::new(&config.gce),
+            images: images::from_config(&config.images),
+            kubernetes: self::kubernetes::connect(&config.kubernetes).await?,
+            local: local::Local::new(&config.local),
+        })
+    }
+
+    /// Builds the providers for a registered cloud, with anything its
+    /// credentials leave unset taken from the controller configuration
+    pub async fn with_credentials(
+        &self,
+        config: &Config,
+        credentials: &CloudCredentials,
+    ) -> Result<Self, Error> {
+        let mut providers = self.clone();
+
+        match credentials {
+            CloudCredentials::AWS {
+                region,
+                endpoint,
+                access_key_id,
+                secret_access_key,
+            } => {
+                providers.aws = aws::CloudFormation::new(&AwsConfig {
+                    endpoint: endpoint.clone().or_else(|| config.aws.endpoint.clone()),
+                    region: region.clone().unwrap_or_else(|| config.aws.region.clone()),
+                    access_key_id: access_key_id
+                        .clone()
+                        .or_else(|| config.aws.access_key_id.clone()),
+                    secret_access_key: secret_access_key
+                        .clone()
+                        .or_else(|| config.aws.secret_access_key.clone()),
+                    fargate: config.aws.fargate.clone(),
+                })
+            }
+            CloudCredentials::Dummy => {}
+            CloudCredentials::GCE {
+                project,
+                zone,
+                endpoint,
+                token,
+            } => {
+                providers.gce = gce::DeploymentManager::new(&GceConfig {
+                    endpoint: endpoint.clone().or_else(|| config.gce.endpoint.clone()),
+                    project: project
+                        .clone()
+                        .unwrap_or_else(|| config.gce.project.clone()),
+                    zone: zone.clone().unwrap_or_else(|| config.gce.zone.clone()),
+                    token: token.clone().or_else(|| config.gce.token.clone()),
+                })
+            }
+            CloudCredentials::Kubernetes {
+                kubeconfig,
+                context,
+            } => {
+                let kubeconfig = match kubeconfig {
+                    Some(contents) => Some(serde_yaml::from_str(contents)?),
+                    None => None,
+                };
+                let context = context
+                    .clone()
+                    .or_else(|| config.kubernetes.context.clone());
+                providers.kubernetes = self::kubernetes::connect_with(kubeconfig, context).await?;
+            }
+            CloudCredentials::Local {
+                working_dir,
+                binaries,
+            } => {
+                providers.local = local::Local::new(&LocalConfig {
+                    working_dir: working_dir
+                        .clone()
+                        .unwrap_or_else(|| config.local.working_dir.clone()),
+                    binaries: binaries.clone(),
+                })
+            }
+        }
+
+        Ok(providers)
+    }
+
+    pub fn kubernetes(&self) -> Result<kube::Client, Error> {
+        self.kubernetes
+            .clone()
+            .ok_or_else(|| Error::CloudNotConfigured("kubernetes".into()))
+    }
+}
+
+/// Recursively merges `include` into `base`, with values from `include`
+/// winning wherever both sides aren't objects
+pub fn merge(base: &mut Value, include: &Value) {
+    match (base, include) {
+        (Value::Object(base), Value::Object(include)) => {
+            for (key, value) in include {
+                merge(base.entry(key.clone()).or_insert(Value::Null), value);
+            }
+        }
+        (base, include) => *base = include.clone(),
+    }
 }
 
 #[derive(Debug)]
@@ -33,78 +161,143 @@ impl Cloud {
         match name {
             b"aws" => Ok(Self::AWS),
             b"dummy" => Ok(Self::Dummy),
+            b"gce" => Ok(Self::GCE),
             b"kubernetes" => Ok(Self::Kubernetes),
+            b"local" => Ok(Self::Local),
             _ => Err(Error::UnknownCloud(format!("Unknown cloud {:?}", name))),
         }
     }
 
     pub fn handle_request(
         &self,
+        providers: &Providers,
+        model: &Model,
         request: Active,
     ) -> impl Future<Output = Result<Completed, Error>> {
         let cloud = self.clone();
+        let providers = providers.clone();
+        let model = model.clone();
         async move {
+            let mut request = request;
+            if let Action::AddRune { name, rune } = &mut request.action {
+                rune.images = images::build_rune(providers.images.as_ref(), name, rune).await?;
+                rune.generate_secrets();
+            }
+            // Runes are rendered before any provider sees them, except by the
+            // dummy cloud, which deploys nothing and shows them as they are
+            let runes = match (&cloud, request.get_action()) {
+                (Self::Dummy, _) => BTreeMap::new(),
+                (_, Action::AddRune { .. })
+                | (_, Action::ConfigureRune { .. })
+                | (_, Action::RemoveRune { .. }) => rendered(&model, &request)?,
+                _ => BTreeMap::new(),
+            };
+
             match (cloud, request.get_action()) {
-                (Self::AWS, Action::CreateModel { name }) => self::aws::create_model(name).await?,
+                (Self::AWS, Action::CreateModel { name }) => {
+                    self::aws::create_model(providers.aws.clone(), name).await?
+                }
                 (Self::AWS, Action::ConfigureModel { foo: _ }) => {
                     self::aws::configure_model().await?
                 }
-                (Self::AWS, Action::DestroyModel) => self::aws::destroy_model().await?,
-                (Self::AWS, Action::AddRune { name, rune }) => {
-                    self::aws::add_rune(name, rune).await?
+                (Self::AWS, Action::DestroyModel) => {
+                    self::aws::destroy_model(providers.aws.clone(), &model).await?
                 }
-                (
-                    Self::AWS,
-                    Action::ConfigureRune {
-                        name,
-                        attribute,
-                        value,
-                    },
-                ) => self::aws::configure_rune(name, attribute, value).await?,
-                (Self::AWS, Action::RemoveRune { name }) => self::aws::remove_rune(name).await?,
-                (Self::Dummy, Action::CreateModel { name }) => {
-                    self::dummy::create_model(name).await?
+                (Self::AWS, Action::AddRune { .. }) => {
+                    self::aws::add_rune(providers.aws.clone(), &model, &runes).await?
+                }
+                (Self::AWS, Action::ConfigureRune { .. }) => {
+                    self::aws::configure_rune(providers.aws.clone(), &model, &runes).await?
+                }
+                (Self::AWS, Action::RemoveRune { .. }) => {
+                    self::aws::remove_rune(providers.aws.clone(), &model, &runes).await?
                 }
-                (Self::Dummy, Action::ConfigureModel { foo: _ }) => {
-                    self::dummy::configure_model().await?
+                (Self::Dummy, Action::CreateModel { .. }) => {
+                    self::dummy::create_model(providers.dummy.clone(), &model).await?
+                }
+                (Self::Dummy, Action::ConfigureModel { foo }) => {
+                    self::dummy::configure_model(providers.dummy.clone(), &model, foo).await?
+                }
+                (Self::Dummy, Action::DestroyModel) => {
+                    self::dummy::destroy_model(providers.dummy.clone(), &model).await?
                 }
-                (Self::Dummy, Action::DestroyModel) => self::dummy::destroy_model().await?,
                 (Self::Dummy, Action::AddRune { name, rune }) => {
-                    self::dummy::add_rune(name, rune).await?
+                    self::dummy::add_rune(providers.dummy.clone(), &model, name, rune).await?
                 }
                 (
                     Self::Dummy,
                     Action::ConfigureRune {
                         name,
-                        attribute,
-                        value,
+                        config,
+                        secrets,
                     },
-                ) => self::dummy::configure_rune(name, attribute, value).await?,
+                ) => {
+                    // The dummy world is only ever shown through the API
+                    let config = redact(config, secrets);
+                    self::dummy::configure_rune(providers.dummy.clone(), &model, name, &config)
+                        .await?
+                }
                 (Self::Dummy, Action::RemoveRune { name }) => {
-                    self::dummy::remove_rune(name).await?
+                    self::dummy::remove_rune(providers.dummy.clone(), &model, name).await?
+                }
+                (Self::GCE, Action::CreateModel { name }) => {
+                    self::gce::create_model(providers.gce.clone(), name).await?
+                }
+                (Self::GCE, Action::ConfigureModel { foo: _ }) => {
+                    self::gce::configure_model().await?
+                }
+                (Self::GCE, Action::DestroyModel) => {
+                    self::gce::destroy_model(providers.gce.clone(), &model).await?
+                }
+                (Self::GCE, Action::AddRune { .. }) => {
+                    self::gce::add_rune(providers.gce.clone(), &model, &runes).await?
+                }
+                (Self::GCE, Action::ConfigureRune { .. }) => {
+                    self::gce::configure_rune(providers.gce.clone(), &model, &runes).await?
+                }
+                (Self::GCE, Action::RemoveRune { .. }) => {
+                    self::gce::remove_rune(providers.gce.clone(), &model, &runes).await?
                 }
                 (Self::Kubernetes, Action::CreateModel { name }) => {
-                    self::kubernetes::create_model(name).await?
+                    self::kubernetes::create_model(providers.kubernetes()?, name).await?
                 }
                 (Self::Kubernetes, Action::ConfigureModel { foo: _ }) => {
                     self::kubernetes::configure_model().await?
                 }
                 (Self::Kubernetes, Action::DestroyModel) => {
-                    self::kubernetes::destroy_model().await?
+                    self::kubernetes::destroy_model(providers.kubernetes()?, &model.name).await?
                 }
-                (Self::Kubernetes, Action::AddRune { name, rune }) => {
-                    self::kubernetes::add_rune(name, rune).await?
+                (Self::Kubernetes, Action::AddRune { name, .. }) => {
+                    let rune = &runes[name];
+                    self::kubernetes::add_rune(providers.kubernetes()?, &model.name, name, rune)
+                        .await?
+                }
+                (Self::Kubernetes, Action::ConfigureRune { .. }) => {
+                    self::kubernetes::configure_rune(providers.kubernetes()?, &model.name, &runes)
+                        .await?
                 }
-                (
-                    Self::Kubernetes,
-                    Action::ConfigureRune {
-                        name,
-                        attribute,
-                        value,
-                    },
-                ) => self::kubernetes::configure_rune(name, attribute, value).await?,
                 (Self::Kubernetes, Action::RemoveRune { name }) => {
-                    self::kubernetes::remove_rune(name).await?
+                    self::kubernetes::remove_rune(providers.kubernetes()?, &model.name, name)
+                        .await?
+                }
+                (Self::Local, Action::CreateModel { name }) => {
+                    self::local::create_model(name).await?
+                }
+                (Self::Local, Action::ConfigureModel { foo: _ }) => {
+                    self::local::configure_model().await?
+                }
+                (Self::Local, Action::DestroyModel) => {
+                    self::local::destroy_model(providers.local.clone(), &model).await?
+                }
+                (Self::Local, Action::AddRune { name, .. }) => {
+                    self::local::add_rune(providers.local.clone(), &model, name, &runes[name])
+                        .await?
+                }
+                (Self::Local, Action::ConfigureRune { .. }) => {
+                    self::local::configure_rune(providers.local.clone(), &model, &runes).await?
+                }
+                (Self::Local, Action::RemoveRune { name }) => {
+                    self::local::remove_rune(providers.local.clone(), &model, name).await?
                 }
             }
 
@@ -119,12 +312,25 @@ impl Cloud {
     }
 }
 
+/// The model's runes as they'll be once `request` is handled, each rendered
+/// against the model's state. Fails if any of them can't be rendered, such as
+/// when a template refers to a relation the model doesn't have.
+pub fn rendered(model: &Model, request: &Active) -> Result<BTreeMap<String, Rune>, Error> {
+    let mut handled = model.clone();
+    handled
+        .history
+        .push(Completed::from_active(request.clone(), 0));
+    handled.rendered_runes()
+}
+
 impl ToString for Cloud {
     fn to_string(&self) -> String {
         match self {
             Cloud::AWS => "aws".into(),
             Cloud::Dummy => "dummy".into(),
+            Cloud::GCE => "gce".into(),
             Cloud::Kubernetes => "kubernetes".into(),
+            Cloud::Local => "local".into(),
         }
     }
 }
@@ -0,0 +1,112 @@
+//! Images built by running a build context's Dockerfile with `docker`
+//!
+//! The context is written to a directory of its own and built with `docker
+//! build`. With a registry configured, the image is pushed to it and referred
+//! to by the digest it was pushed with. Otherwise it's only tagged, so can
+//! only be run wherever it was built. Contexts without a Dockerfile are left
+//! to another builder.
+
+use super::{Context, ImageBuilder, DOCKERFILE};
+use crate::server::error::Error;
+use futures::future::BoxFuture;
+use std::env::temp_dir;
+use std::fs::{create_dir_all, remove_dir_all, set_permissions, write, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path};
+use std::sync::Arc;
+use tokio::process::Command;
+use uuid::Uuid;
+
+pub struct Docker {
+    program: String,
+    /// Host of the registry that images are pushed to, if there is one
+    registry: Option<String>,
+    /// Builds contexts that don't have a Dockerfile
+    fallback: Arc<dyn ImageBuilder>,
+}
+
+impl Docker {
+    pub fn new(program: &str, registry: Option<&str>, fallback: Arc<dyn ImageBuilder>) -> Self {
+        let host = |endpoint: &str| {
+            let host = endpoint.splitn(2, "://").last().unwrap_or(endpoint);
+            host.trim_end_matches('/').to_string()
+        };
+        Self {
+            program: program.into(),
+            registry: registry.map(host),
+            fallback,
+        }
+    }
+
+    /// Runs docker with `args`, returning what it printed
+    async fn run(&self, args: &[&str]) -> Result<String, Error> {
+        let output = Command::new(&self.program).args(args).output().await?;
+        if !output.status.success() {
+            return Err(Error::ImageBuildError(format!(
+                "docker {} failed: {}",
+                args[0],
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Writes `context` into `dir`, keeping which files are executable
+    fn write_context(dir: &Path, context: &Context) -> Result<(), Error> {
+        for (path, file) in context {
+            let relative = Path::new(path);
+            if !relative
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+            {
+                return Err(Error::ImageBuildError(format!(
+                    "Build context path {} is outside the context",
+                    path
+                )));
+            }
+            let path = dir.join(relative);
+            if let Some(parent) = path.parent() {
+                create_dir_all(parent)?;
+            }
+            write(&path, &file.contents)?;
+            let mode = if file.executable { 0o755 } else { 0o644 };
+            set_permissions(&path, Permissions::from_mode(mode))?;
+        }
+        Ok(())
+    }
+
+    async fn build_in(&self, dir: &Path, name: &str, context: &Context) -> Result<String, Error> {
+        Self::write_context(dir, context)?;
+
+        let tag = match &self.registry {
+            Some(host) => format!("{}/{}:latest", host, name),
+            None => format!("{}:latest", name),
+        };
+        self.run(&["build", "--tag", &tag, &dir.to_string_lossy()])
+            .await?;
+        if self.registry.is_none() {
+            return Ok(tag);
+        }
+        self.run(&["push", &tag]).await?;
+        self.run(&["inspect", "--format", "{{index .RepoDigests 0}}", &tag])
+            .await
+    }
+}
+
+impl ImageBuilder for Docker {
+    fn build<'a>(
+        &'a self,
+        name: &'a str,
+        context: &'a Context,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move {
+            if !context.contains_key(DOCKERFILE) {
+                return self.fallback.build(name, context).await;
+            }
+            let dir = temp_dir().join(format!("uruz-build-{}", Uuid::new_v4()));
+            let built = self.build_in(&dir, name, context).await;
+            remove_dir_all(&dir).ok();
+            built
+        })
+    }
+}
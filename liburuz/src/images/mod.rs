@@ -0,0 +1,111 @@
+//! Builds container images from the build contexts packaged in runes
+//!
+//! A template with `image: {build: <dir>}` is built from the files under
+//! `<dir>` in the rune archive. Builders are pluggable through
+//! [`ImageBuilder`]; the controller picks one from its configuration, and the
+//! references it returns are stored with the rune for providers to deploy.
+//!
+//! Contexts with a `Dockerfile` are built by running it with `docker`, which
+//! has to be configured for them. Any other context is packaged as it is.
+
+pub mod docker;
+pub mod oci;
+
+use crate::rune::v1::rune::Rune;
+use crate::rune::v1::template::Image;
+use crate::server::config::ImagesConfig;
+use crate::server::error::Error;
+use futures::future::BoxFuture;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// The file that makes a build context one for `docker build`
+pub const DOCKERFILE: &str = "Dockerfile";
+
+/// A file in a build context
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContextFile {
+    pub contents: Vec<u8>,
+    pub executable: bool,
+}
+
+/// The files in a build context, keyed by path relative to the context
+pub type Context = BTreeMap<String, ContextFile>;
+
+pub trait ImageBuilder: Send + Sync {
+    /// Builds an image called `name` from the files in `context`, returning a
+    /// reference to the image
+    fn build<'a>(
+        &'a self,
+        name: &'a str,
+        context: &'a Context,
+    ) -> BoxFuture<'a, Result<String, Error>>;
+}
+
+/// Picks the builder for the controller configuration: a registry push if
+/// a registry is configured, and otherwise a local OCI image layout, with
+/// contexts that have a Dockerfile built by `docker` if it's configured
+pub fn from_config(config: &ImagesConfig) -> Arc<dyn ImageBuilder> {
+    let packager: Arc<dyn ImageBuilder> = match &config.registry {
+        Some(endpoint) => Arc::new(oci::Registry::new(endpoint)),
+        None => Arc::new(oci::OciLayout::new(&config.oci_layout)),
+    };
+    match &config.docker {
+        Some(program) => Arc::new(docker::Docker::new(
+            program,
+            config.registry.as_deref(),
+            packager,
+        )),
+        None => packager,
+    }
+}
+
+/// Returns the files under `dir` in the rune archive, relative to `dir`
+pub fn context(rune: &Rune, dir: &str) -> Context {
+    let dir = dir.trim_matches('/');
+    let prefix = match dir {
+        "" | "." => String::new(),
+        dir => format!("{}/", dir),
+    };
+
+    rune.files
+        .iter()
+        .filter_map(|(path, contents)| {
+            let file = ContextFile {
+                contents: contents.clone(),
+                executable: rune.executables.contains(path),
+            };
+            path.strip_prefix(&prefix)
+                .map(|relative| (relative.to_string(), file))
+        })
+        .collect()
+}
+
+/// Builds every template image of `rune`, deployed as `name`, that has a build
+/// context, returning image references by template name
+pub async fn build_rune(
+    builder: &dyn ImageBuilder,
+    name: &str,
+    rune: &Rune,
+) -> Result<BTreeMap<String, String>, Error> {
+    let mut images = BTreeMap::new();
+
+    for template in &rune.template {
+        if let Image::Build { build } = &template.image {
+            let context = context(rune, build);
+            if context.is_empty() {
+                return Err(Error::ImageBuildError(format!(
+                    "Build context {} of template {} in rune {} is empty",
+                    build, template.name, name
+                )));
+            }
+            let image_name = format!("{}/{}", name, template.name);
+            images.insert(
+                template.name.clone(),
+                builder.build(&image_name, &context).await?,
+            );
+        }
+    }
+
+    Ok(images)
+}
@@ -0,0 +1,247 @@
+//! OCI images built by packaging a build context as a single layer
+//!
+//! The context becomes the image filesystem as-is, which keeps builds
+//! reproducible and free of a container runtime. The image has no base and no
+//! config of its own, so templates have to give the command to run. Contexts
+//! with a Dockerfile are rejected, as they only make sense once it's run, such
+//! as by [`Docker`](super::docker::Docker). Images are either written to a
+//! local OCI image layout or pushed to a registry over the distribution API.
+
+use super::{Context, ImageBuilder, DOCKERFILE};
+use crate::server::error::Error;
+use futures::future::BoxFuture;
+use reqwest::{Method, StatusCode};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::fs::{create_dir_all, read, write};
+use std::path::PathBuf;
+
+pub const MANIFEST_MEDIA_TYPE: &str = "application/vnd.oci.image.manifest.v1+json";
+const CONFIG_MEDIA_TYPE: &str = "application/vnd.oci.image.config.v1+json";
+const LAYER_MEDIA_TYPE: &str = "application/vnd.oci.image.layer.v1.tar";
+
+pub fn digest(bytes: &[u8]) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(bytes)))
+}
+
+/// The blobs making up an image
+pub struct Image {
+    pub layer: Vec<u8>,
+    pub config: Vec<u8>,
+    pub manifest: Vec<u8>,
+}
+
+impl Image {
+    /// Packages `context` as a tar layer, with fixed timestamps and modes so
+    /// the same context always gives the same digests
+    pub fn from_context(context: &Context) -> Result<Self, Error> {
+        if context.contains_key(DOCKERFILE) {
+            return Err(Error::ImageBuildError(
+                "The build context has a Dockerfile, which can only be built by docker. \
+                 Set images.docker in the controller's config to build it."
+                    .into(),
+            ));
+        }
+
+        let mut builder = tar::Builder::new(vec![]);
+        for (path, file) in context {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(file.contents.len() as u64);
+            header.set_mode(if file.executable { 0o755 } else { 0o644 });
+            header.set_mtime(0);
+            builder.append_data(&mut header, path, file.contents.as_slice())?;
+        }
+        let layer = builder.into_inner()?;
+
+        let config = serde_json::to_vec(&json!({
+            "architecture": "amd64",
+            "os": "linux",
+            "config": {},
+            "rootfs": {"type": "layers", "diff_ids": [digest(&layer)]},
+        }))?;
+
+        let manifest = serde_json::to_vec(&json!({
+            "schemaVersion": 2,
+            "mediaType": MANIFEST_MEDIA_TYPE,
+            "config": {
+                "mediaType": CONFIG_MEDIA_TYPE,
+                "digest": digest(&config),
+                "size": config.len(),
+            },
+            "layers": [{
+                "mediaType": LAYER_MEDIA_TYPE,
+                "digest": digest(&layer),
+                "size": layer.len(),
+            }],
+        }))?;
+
+        Ok(Self {
+            layer,
+            config,
+            manifest,
+        })
+    }
+
+    pub fn digest(&self) -> String {
+        digest(&self.manifest)
+    }
+}
+
+/// Writes images into an OCI image layout directory, tagged with their name
+pub struct OciLayout {
+    path: PathBuf,
+}
+
+impl OciLayout {
+    pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+        Self { path: path.into() }
+    }
+
+    fn write_blob(&self, bytes: &[u8]) -> Result<(), Error> {
+        let blobs = self.path.join("blobs").join("sha256");
+        create_dir_all(&blobs)?;
+        write(blobs.join(&digest(bytes)["sha256:".len()..]), bytes)?;
+        Ok(())
+    }
+
+    fn write(&self, name: &str, image: &Image) -> Result<String, Error> {
+        self.write_blob(&image.layer)?;
+        self.write_blob(&image.config)?;
+        self.write_blob(&image.manifest)?;
+        write(
+            self.path.join("oci-layout"),
+            r#"{"imageLayoutVersion":"1.0.0"}"#,
+        )?;
+
+        let index_path = self.path.join("index.json");
+        let mut index: Value = match read(&index_path) {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(_) => json!({"schemaVersion": 2, "manifests": []}),
+        };
+        let mut manifests: Vec<Value> = serde_json::from_value(index["manifests"].take())?;
+        manifests.retain(|m| m["annotations"]["org.opencontainers.image.ref.name"] != name);
+        manifests.push(json!({
+            "mediaType": MANIFEST_MEDIA_TYPE,
+            "digest": image.digest(),
+            "size": image.manifest.len(),
+            "annotations": {"org.opencontainers.image.ref.name": name},
+        }));
+        index["manifests"] = json!(manifests);
+        write(index_path, serde_json::to_vec(&index)?)?;
+
+        Ok(format!("oci:{}:{}", self.path.display(), name))
+    }
+}
+
+impl ImageBuilder for OciLayout {
+    fn build<'a>(
+        &'a self,
+        name: &'a str,
+        context: &'a Context,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move { self.write(name, &Image::from_context(context)?) })
+    }
+}
+
+/// Pushes images to a registry, returning references pinned by digest
+pub struct Registry {
+    endpoint: String,
+    client: reqwest::Client,
+}
+
+impl Registry {
+    pub fn new(endpoint: &str) -> Self {
+        Self {
+            endpoint: endpoint.trim_end_matches('/').into(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    /// The registry host, as used in image references
+    fn host(&self) -> &str {
+        self.endpoint
+            .splitn(2, "://")
+            .last()
+            .unwrap_or(&self.endpoint)
+    }
+
+    fn check(response: &reqwest::Response, expected: StatusCode) -> Result<(), Error> {
+        if response.status() == expected {
+            Ok(())
+        } else {
+            Err(Error::ImageBuildError(format!(
+                "Registry returned {} for {}",
+                response.status(),
+                response.url()
+            )))
+        }
+    }
+
+    async fn push_blob(&self, name: &str, bytes: &[u8]) -> Result<(), Error> {
+        let digest = digest(bytes);
+        let existing = self
+            .client
+            .request(
+                Method::HEAD,
+                &format!("{}/v2/{}/blobs/{}", self.endpoint, name, digest),
+            )
+            .send()
+            .await?;
+        if existing.status() == StatusCode::OK {
+            return Ok(());
+        }
+
+        let upload = self
+            .client
+            .post(&format!("{}/v2/{}/blobs/uploads/", self.endpoint, name))
+            .send()
+            .await?;
+        Self::check(&upload, StatusCode::ACCEPTED)?;
+        let location = upload
+            .headers()
+            .get("Location")
+            .and_then(|l| l.to_str().ok())
+            .ok_or_else(|| Error::ImageBuildError("Registry gave no upload location".into()))?;
+        let location = if location.starts_with('/') {
+            format!("{}{}", self.endpoint, location)
+        } else {
+            location.to_string()
+        };
+        let separator = if location.contains('?') { '&' } else { '?' };
+
+        let response = self
+            .client
+            .put(&format!("{}{}digest={}", location, separator, digest))
+            .header("Content-Type", "application/octet-stream")
+            .body(bytes.to_vec())
+            .send()
+            .await?;
+        Self::check(&response, StatusCode::CREATED)
+    }
+
+    async fn push(&self, name: &str, image: &Image) -> Result<String, Error> {
+        self.push_blob(name, &image.layer).await?;
+        self.push_blob(name, &image.config).await?;
+
+        let response = self
+            .client
+            .put(&format!("{}/v2/{}/manifests/latest", self.endpoint, name))
+            .header("Content-Type", MANIFEST_MEDIA_TYPE)
+            .body(image.manifest.clone())
+            .send()
+            .await?;
+        Self::check(&response, StatusCode::CREATED)?;
+
+        Ok(format!("{}/{}@{}", self.host(), name, image.digest()))
+    }
+}
+
+impl ImageBuilder for Registry {
+    fn build<'a>(
+        &'a self,
+        name: &'a str,
+        context: &'a Context,
+    ) -> BoxFuture<'a, Result<String, Error>> {
+        Box::pin(async move { self.push(name, &Image::from_context(context)?).await })
+    }
+}
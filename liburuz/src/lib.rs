@@ -1,5 +1,6 @@
 pub mod api;
 pub mod client;
 pub mod clouds;
+pub mod images;
 pub mod rune;
 pub mod server;
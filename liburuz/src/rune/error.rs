@@ -1,16 +1,237 @@
+use crate::rune::v1::files::IGNORE_FILE;
+use crate::rune::v1::render::{normalize_key, Unresolved};
 use reqwest::Error as ReqwestError;
+use serde_json::Error as SerdeJsonError;
 use serde_yaml::Error as YamlError;
+use std::fmt;
 use std::io::Error as IOError;
 use uuid::Uuid;
 use zip::result::ZipError;
 
+/// A problem in one of a rune's files, with as much of where it is as is known
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub file: String,
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    /// Dotted path to the offending key, such as `config.port`
+    pub key: Option<String>,
+    pub message: String,
+}
+
+impl Diagnostic {
+    pub fn new<M: ToString>(file: &str, message: M) -> Self {
+        Self {
+            file: file.into(),
+            line: None,
+            column: None,
+            key: None,
+            message: message.to_string(),
+        }
+    }
+
+    /// Takes the location and key from a YAML error. `serde_yaml` only puts the
+    /// key in its message, as a path before the first `: `, and names missing
+    /// and unknown fields in backticks.
+    pub fn from_yaml(file: &str, err: &YamlError) -> Self {
+        let mut message = err.to_string();
+        let mut diagnostic = Self::new(file, "");
+
+        if let Some(location) = err.location() {
+            let suffix = format!(" at line {} column {}", location.line(), location.column());
+            if message.ends_with(&suffix) {
+                message.truncate(message.len() - suffix.len());
+            }
+            diagnostic.line = Some(location.line());
+            diagnostic.column = Some(location.column());
+
+            if let Some(index) = message.find(": ") {
+                if !message[..index].contains(char::is_whitespace) {
+                    diagnostic.key = Some(message[..index].trim_start_matches('.').to_string());
+                    message = message[index + 2..].to_string();
+                }
+            }
+        }
+
+        for prefix in &["missing field `", "unknown field `", "duplicate field `"] {
+            if let Some(rest) = message.strip_prefix(prefix) {
+                let field = rest.split('`').next().unwrap_or_default();
+                diagnostic.key = Some(match diagnostic.key {
+                    Some(key) => format!("{}.{}", key, field),
+                    None => field.to_string(),
+                });
+            }
+        }
+
+        diagnostic.message = message;
+        diagnostic
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.file)?;
+        if let Some(line) = self.line {
+            write!(f, ":{}", line)?;
+        }
+        if let Some(column) = self.column {
+            write!(f, ":{}", column)?;
+        }
+        if let Some(key) = &self.key {
+            write!(f, ": {}", key)?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     IOError(IOError),
     YamlError(YamlError),
+    SerdeJsonError(SerdeJsonError),
     ZipError(ZipError),
     RequestError(ReqwestError),
     TimeoutError(Uuid),
+    /// A rune format version this build doesn't know how to read
+    UnsupportedVersion(String),
+    /// A key that isn't a hex encoded ed25519 key
+    InvalidKey(String),
+    /// An archive without a signature, where one is required
+    Unsigned,
+    /// An archive signed by a key that isn't trusted
+    UntrustedKey(String),
+    /// A signature that doesn't match the archive's contents
+    InvalidSignature,
+    /// An archive whose files don't match its manifest
+    ManifestMismatch,
+    /// Every problem found while loading the rune's files
+    InvalidRune(Vec<Diagnostic>),
+    UnresolvedReferences(Vec<Unresolved>),
+    /// Template references to config items the rune doesn't declare, including
+    /// secrets referred to as config and config items referred to as secrets
+    UnknownConfigReferences(Vec<Unresolved>),
+    /// Template references to relations that aren't in the rune's `requires`
+    UndeclaredRelations(Vec<Unresolved>),
+    /// Version 1 template references to related runes' config, which can't be
+    /// migrated without knowing which of them are secrets
+    RelationConfigReferences(Vec<String>),
+    /// Two config keys that normalize to the same template reference
+    ConflictingConfigKeys(String, String),
+    /// A pattern in `.runeignore` that isn't a valid glob
+    InvalidIgnorePattern(String, String),
+    /// A config item used somewhere only a value of another type makes sense
+    ConfigTypeMismatch {
+        field: String,
+        key: String,
+        expected: String,
+    },
+    /// A config value or default that isn't of its item's type, or doesn't
+    /// meet its constraints
+    InvalidConfigValue {
+        key: String,
+        message: String,
+    },
+    /// A port that isn't an integer from 1 to 65535 once rendered
+    InvalidPort {
+        rune: String,
+        container: String,
+        port: String,
+        value: String,
+    },
+}
+
+fn join<T: fmt::Display>(items: &[T]) -> String {
+    items
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(err) => write!(f, "{}", err),
+            Error::YamlError(err) => write!(f, "{}", err),
+            Error::SerdeJsonError(err) => write!(f, "{}", err),
+            Error::ZipError(err) => write!(f, "{}", err),
+            Error::RequestError(err) => write!(f, "{}", err),
+            Error::TimeoutError(id) => write!(f, "Timed out waiting for {}", id),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "Unsupported rune format version {}", version)
+            }
+            Error::InvalidKey(err) => write!(f, "Invalid key {}", err),
+            Error::Unsigned => write!(f, "Rune isn't signed"),
+            Error::UntrustedKey(key) => write!(f, "Rune is signed by untrusted key {}", key),
+            Error::InvalidSignature => write!(f, "Rune signature doesn't match its contents"),
+            Error::ManifestMismatch => write!(f, "Rune archive doesn't match its manifest"),
+            Error::InvalidRune(diagnostics) => write!(f, "{}", join(diagnostics)),
+            Error::UnresolvedReferences(refs) => {
+                write!(f, "Unresolved references:\n{}", join(refs))
+            }
+            Error::UnknownConfigReferences(refs) => {
+                write!(f, "References to undeclared config items:\n{}", join(refs))
+            }
+            Error::UndeclaredRelations(refs) => write!(
+                f,
+                "References to relations that aren't in requires:\n{}",
+                join(refs)
+            ),
+            Error::RelationConfigReferences(refs) => write!(
+                f,
+                "Version 1 runes get related runes' secrets as config, so these \
+                 can't be migrated automatically. Change any that refer to \
+                 secrets to state.relations.<relation>.secrets.<key>, then set \
+                 version to 2:\n{}",
+                refs.join("\n")
+            ),
+            Error::ConflictingConfigKeys(a, b) => write!(
+                f,
+                "Config items {} and {} are both referred to as {}",
+                a,
+                b,
+                normalize_key(a)
+            ),
+            Error::InvalidIgnorePattern(pattern, err) => {
+                write!(f, "{}: invalid pattern {}: {}", IGNORE_FILE, pattern, err)
+            }
+            Error::ConfigTypeMismatch {
+                field,
+                key,
+                expected,
+            } => write!(
+                f,
+                "{} refers to config item {}, which must be of type {}",
+                field, key, expected
+            ),
+            Error::InvalidConfigValue { key, message } => {
+                write!(f, "Config item {} {}", key, message)
+            }
+            Error::InvalidPort {
+                rune,
+                container,
+                port,
+                value,
+            } => write!(
+                f,
+                "Port {} of {} in {} is {}, which isn't a valid port",
+                port, container, rune, value
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IOError(err) => Some(err),
+            Error::YamlError(err) => Some(err),
+            Error::SerdeJsonError(err) => Some(err),
+            Error::ZipError(err) => Some(err),
+            Error::RequestError(err) => Some(err),
+            _ => None,
+        }
+    }
 }
 
 impl From<IOError> for Error {
@@ -25,6 +246,12 @@ impl From<YamlError> for Error {
     }
 }
 
+impl From<SerdeJsonError> for Error {
+    fn from(err: SerdeJsonError) -> Self {
+        Error::SerdeJsonError(err)
+    }
+}
+
 impl From<ZipError> for Error {
     fn from(err: ZipError) -> Self {
         Error::ZipError(err)
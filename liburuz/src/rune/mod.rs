@@ -1,4 +1,5 @@
 pub mod error;
 pub mod v1;
+pub mod version;
 
-pub use error::Error;
+pub use error::{Diagnostic, Error};
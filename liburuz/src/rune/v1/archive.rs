@@ -0,0 +1,106 @@
+//! The zip archives that runes are distributed as
+//!
+//! Archives are reproducible: entries are written in path order with fixed
+//! timestamps and permissions, so the same rune always zips to the same bytes.
+//! The only permission kept is whether a file is executable, such as a script
+//! in an image build context.
+//! Each archive carries a [`MANIFEST_FILE`] listing the SHA-256 digest and path
+//! of every file in the rune, in the same format as `sha256sum`. The rune's
+//! digest is the digest of its manifest, which identifies its contents however
+//! the archive was produced, and whether or not it's signed.
+
+use crate::rune::error::Error;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{Cursor, Read, Write};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+pub const MANIFEST_FILE: &str = "MANIFEST";
+pub const SIGNATURE_FILE: &str = "SIGNATURE";
+
+/// Lists each file's digest and path, one per line. The manifest and
+/// signature aren't part of the manifest themselves.
+pub fn manifest(entries: &BTreeMap<String, Vec<u8>>) -> String {
+    entries
+        .iter()
+        .filter(|(name, _)| name.as_str() != MANIFEST_FILE && name.as_str() != SIGNATURE_FILE)
+        .map(|(name, contents)| format!("{}  {}\n", hex::encode(Sha256::digest(contents)), name))
+        .collect()
+}
+
+/// The content digest of the rune made up of `entries`, such as
+/// `sha256:5891b5b5...`
+pub fn digest(entries: &BTreeMap<String, Vec<u8>>) -> String {
+    format!(
+        "sha256:{}",
+        hex::encode(Sha256::digest(manifest(entries).as_bytes()))
+    )
+}
+
+/// Reads every file in `bytes`, keyed by its path in the archive, after
+/// checking them against the archive's manifest if it has one
+pub fn read(bytes: &[u8]) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+    let mut reader = ZipArchive::new(Cursor::new(bytes))?;
+    let mut entries = BTreeMap::new();
+
+    for i in 0..reader.len() {
+        let mut file = reader.by_index(i)?;
+        if file.is_dir() {
+            continue;
+        }
+        let mut contents = vec![];
+        file.read_to_end(&mut contents)?;
+        entries.insert(file.name().to_string(), contents);
+    }
+
+    if let Some(expected) = entries.get(MANIFEST_FILE) {
+        if expected.as_slice() != manifest(&entries).as_bytes() {
+            return Err(Error::ManifestMismatch);
+        }
+    }
+
+    Ok(entries)
+}
+
+/// Paths of the files in `bytes` that are executable
+pub fn executables(bytes: &[u8]) -> Result<BTreeSet<String>, Error> {
+    let mut reader = ZipArchive::new(Cursor::new(bytes))?;
+    let mut executables = BTreeSet::new();
+
+    for i in 0..reader.len() {
+        let file = reader.by_index(i)?;
+        if !file.is_dir() && file.unix_mode().unwrap_or_default() & 0o111 != 0 {
+            executables.insert(file.name().to_string());
+        }
+    }
+
+    Ok(executables)
+}
+
+/// Writes `entries` and their manifest to a new archive, with those listed in
+/// `executables` marked executable
+pub fn write(
+    entries: &BTreeMap<String, Vec<u8>>,
+    executables: &BTreeSet<String>,
+) -> Result<Vec<u8>, Error> {
+    let mut entries = entries.clone();
+    entries.insert(MANIFEST_FILE.into(), manifest(&entries).into_bytes());
+
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .last_modified_time(DateTime::default());
+    let mut writer = ZipWriter::new(Cursor::new(Vec::new()));
+
+    for (name, contents) in &entries {
+        let mode = if executables.contains(name) {
+            0o755
+        } else {
+            0o644
+        };
+        writer.start_file(name.as_str(), options.unix_permissions(mode))?;
+        writer.write_all(contents)?;
+    }
+
+    Ok(writer.finish()?.into_inner())
+}
@@ -0,0 +1,127 @@
+//! Extra files carried in a rune archive, such as image build contexts
+//!
+//! Everything in the rune directory besides the files `Rune` parses itself is
+//! packed, apart from paths matching a pattern in `.runeignore`. Patterns are
+//! globs, one per line, matched against each file's path relative to the rune
+//! directory and every directory above it, so `build/` or `*.log` exclude what
+//! you'd expect. Lines starting with `#` are comments. The archive's own
+//! manifest and signature files are never read from the directory.
+//!
+//! Hidden files and directories, such as `.git`, are never packed either, nor
+//! are env files that secrets are read from, so that secret values don't end
+//! up in archives however they're named.
+
+use super::archive::{MANIFEST_FILE, SIGNATURE_FILE};
+use crate::rune::error::Error;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{read, read_to_string};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Component, Path};
+use walkdir::{DirEntry, WalkDir};
+
+pub const IGNORE_FILE: &str = ".runeignore";
+
+/// Files at the top of a rune directory that `Rune` reads into its own fields
+pub const RUNE_FILES: [&str; 4] = ["metadata.yaml", "rune.yaml", "transformers.py", "rune.py"];
+
+fn ignore_patterns(dir: &Path) -> Result<GlobSet, Error> {
+    let mut builder = GlobSetBuilder::new();
+
+    if let Ok(contents) = read_to_string(dir.join(IGNORE_FILE)) {
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let pattern = line.trim_matches('/');
+            builder.add(
+                Glob::new(pattern)
+                    .map_err(|err| Error::InvalidIgnorePattern(line.into(), err.to_string()))?,
+            );
+        }
+    }
+
+    builder
+        .build()
+        .map_err(|err| Error::InvalidIgnorePattern(IGNORE_FILE.into(), err.to_string()))
+}
+
+/// Whether `path` or any directory containing it matches an ignore pattern
+fn ignored(patterns: &GlobSet, path: &str) -> bool {
+    let mut prefix = String::new();
+    for component in path.split('/') {
+        if !prefix.is_empty() {
+            prefix.push('/');
+        }
+        prefix.push_str(component);
+        if patterns.is_match(component) || patterns.is_match(&prefix) {
+            return true;
+        }
+    }
+    false
+}
+
+/// Joins the components of a path relative to the rune directory with `/`,
+/// dropping any `.`
+fn relative_name(path: &Path) -> String {
+    path.components()
+        .filter(|c| *c != Component::CurDir)
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Files keyed by path, along with the paths of those that are executable
+pub type Files = (BTreeMap<String, Vec<u8>>, BTreeSet<String>);
+
+/// Reads every extra file in the rune directory at `dir`, keyed by its path
+/// relative to `dir` with `/` separators, along with the paths of those that
+/// are executable. `env_files` are the env files the rune's secrets are read
+/// from, which are left out.
+pub fn read_files(dir: &Path, env_files: &[&str]) -> Result<Files, Error> {
+    let patterns = ignore_patterns(dir)?;
+    let env_files: Vec<_> = env_files
+        .iter()
+        .map(|file| relative_name(Path::new(file)))
+        .collect();
+    let mut files = BTreeMap::new();
+    let mut executables = BTreeSet::new();
+
+    let hidden = |entry: &DirEntry| {
+        entry.depth() > 0 && entry.file_name().to_string_lossy().starts_with('.')
+    };
+    for entry in WalkDir::new(dir)
+        .min_depth(1)
+        .into_iter()
+        .filter_entry(|entry| !hidden(entry))
+    {
+        let entry = entry.map_err(io::Error::from)?;
+        if !entry.file_type().is_file() {
+            continue;
+        }
+
+        let name = relative_name(entry.path().strip_prefix(dir).unwrap_or(entry.path()));
+
+        if [IGNORE_FILE, MANIFEST_FILE, SIGNATURE_FILE].contains(&name.as_str())
+            || RUNE_FILES.contains(&name.as_str())
+            || env_files.contains(&name)
+            || ignored(&patterns, &name)
+        {
+            continue;
+        }
+        if entry
+            .metadata()
+            .map_err(io::Error::from)?
+            .permissions()
+            .mode()
+            & 0o111
+            != 0
+        {
+            executables.insert(name.clone());
+        }
+        files.insert(name, read(entry.path())?);
+    }
+
+    Ok((files, executables))
+}
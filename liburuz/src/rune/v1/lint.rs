@@ -0,0 +1,262 @@
+//! Static checks for problems in a rune that loading alone doesn't catch
+
+use super::metadata::ConfigItem;
+use super::render::{normalize_key, references};
+use super::rune::Rune;
+use super::template::TemplateInteger;
+use crate::clouds::Cloud;
+use crate::rune::error::Error;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// A problem found in one of the rune's files
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    pub severity: Severity,
+    pub file: String,
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.file, self.severity, self.message)
+    }
+}
+
+/// Whether `source` defines a top-level Python function called `name`
+fn defines_function(source: &str, name: &str) -> bool {
+    let def = format!("def {}(", name);
+    source
+        .lines()
+        .any(|line| line.starts_with(&def) || line.starts_with(&format!("async {}", def)))
+}
+
+/// Whether `key` is declared, and declared as a secret
+fn is_secret(rune: &Rune, key: Option<&&String>) -> bool {
+    match key {
+        Some(key) => matches!(rune.metadata.config[*key], ConfigItem::Secret { .. }),
+        None => false,
+    }
+}
+
+/// Runs every check against `rune`, returning problems in the order they
+/// were found
+pub fn lint(rune: &Rune) -> Vec<Problem> {
+    let mut problems = vec![];
+    let mut problem = |severity, file: &str, message: String| {
+        problems.push(Problem {
+            severity,
+            file: file.into(),
+            message,
+        })
+    };
+
+    let declared: BTreeMap<_, _> = rune
+        .metadata
+        .config
+        .keys()
+        .map(|key| (normalize_key(key), key))
+        .collect();
+    let relations: BTreeSet<_> = rune
+        .metadata
+        .requires
+        .iter()
+        .map(|r| r.name.as_str())
+        .collect();
+    let mut used = BTreeSet::new();
+
+    for template in &rune.template {
+        let ports: Vec<_> = template
+            .ports
+            .iter()
+            .map(|port| format!("{}.ports.{}.containerPort", template.name, port.name))
+            .collect();
+
+        for reference in references(template) {
+            let mut path = reference.reference.split('.');
+            match (path.next(), path.next(), path.next()) {
+                (Some("state"), Some("config"), Some(key))
+                    if is_secret(rune, declared.get(&normalize_key(key))) =>
+                {
+                    problem(
+                        Severity::Error,
+                        "rune.yaml",
+                        format!(
+                            "{} refers to secret {} as config, rather than as state.secrets.{}",
+                            reference.field,
+                            key,
+                            normalize_key(key)
+                        ),
+                    );
+                }
+                (Some("state"), Some("secrets"), Some(key)) => {
+                    if is_secret(rune, declared.get(&normalize_key(key))) {
+                        used.insert(normalize_key(key));
+                    } else {
+                        problem(
+                            Severity::Error,
+                            "rune.yaml",
+                            format!("{} refers to undeclared secret {}", reference.field, key),
+                        );
+                    }
+                }
+                (Some("state"), Some("config"), Some(key)) => {
+                    if let Some(declared_key) = declared.get(&normalize_key(key)) {
+                        used.insert(normalize_key(key));
+                        let integer = match rune.metadata.config[*declared_key] {
+                            ConfigItem::Integer { .. } => true,
+                            _ => false,
+                        };
+                        if ports.contains(&reference.field) && !integer {
+                            problem(
+                                Severity::Error,
+                                "rune.yaml",
+                                format!(
+                                    "{} refers to config item {}, which isn't an integer",
+                                    reference.field, declared_key
+                                ),
+                            );
+                        }
+                    } else {
+                        problem(
+                            Severity::Error,
+                            "rune.yaml",
+                            format!(
+                                "{} refers to undeclared config item {}",
+                                reference.field, key
+                            ),
+                        );
+                    }
+                }
+                (Some("state"), Some("relations"), Some(name)) => {
+                    if !relations.contains(name) {
+                        problem(
+                            Severity::Error,
+                            "rune.yaml",
+                            format!(
+                                "{} refers to relation {}, which isn't in requires",
+                                reference.field, name
+                            ),
+                        );
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        let mut ports = BTreeSet::new();
+        for port in &template.ports {
+            if let TemplateInteger::Integer(_) = port.container_port {
+                if let Err(Error::InvalidPort { value, .. }) =
+                    port.check(&rune.metadata.name, &template.name)
+                {
+                    problem(
+                        Severity::Error,
+                        "rune.yaml",
+                        format!(
+                            "Port {} of {} is {}, which isn't a valid port",
+                            port.name, template.name, value
+                        ),
+                    );
+                }
+            }
+            if !ports.insert(&port.name) {
+                problem(
+                    Severity::Error,
+                    "rune.yaml",
+                    format!(
+                        "{} has more than one port named {}",
+                        template.name, port.name
+                    ),
+                );
+            }
+        }
+    }
+
+    let mut config: Vec<_> = rune.metadata.config.iter().collect();
+    config.sort_by(|a, b| a.0.cmp(b.0));
+    for (key, item) in config {
+        if !used.contains(&normalize_key(key)) {
+            problem(
+                Severity::Warning,
+                "metadata.yaml",
+                format!("Config item {} isn't used by any template", key),
+            );
+        }
+
+        if let Err(err) = item.check_declaration(key) {
+            problem(Severity::Error, "metadata.yaml", err.to_string());
+        }
+
+        if let Some(transformer) = item.transformer() {
+            match &rune.transformers {
+                Some(source) if defines_function(source, transformer) => {}
+                Some(_) => problem(
+                    Severity::Error,
+                    "transformers.py",
+                    format!(
+                        "Transformer {} for config item {} isn't defined",
+                        transformer, key
+                    ),
+                ),
+                None => problem(
+                    Severity::Error,
+                    "metadata.yaml",
+                    format!(
+                        "Config item {} uses transformer {}, but there's no transformers.py",
+                        key, transformer
+                    ),
+                ),
+            }
+        }
+    }
+
+    if let Some(handler) = &rune.metadata.react {
+        match &rune.react {
+            Some(source) if defines_function(source, handler) => {}
+            Some(_) => problem(
+                Severity::Error,
+                "rune.py",
+                format!("React handler {} isn't defined", handler),
+            ),
+            None => problem(
+                Severity::Error,
+                "metadata.yaml",
+                format!("React handler {} is set, but there's no rune.py", handler),
+            ),
+        }
+    }
+
+    for series in &rune.metadata.series {
+        if Cloud::from_str(series).is_err() {
+            problem(
+                Severity::Warning,
+                "metadata.yaml",
+                format!("Series {} has no include support in any cloud", series),
+            );
+        }
+    }
+
+    problems
+}
+
+impl Rune {
+    pub fn lint(&self) -> Vec<Problem> {
+        lint(self)
+    }
+}
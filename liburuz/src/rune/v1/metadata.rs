@@ -1,14 +1,78 @@
+use crate::rune::error::Error;
+use crate::rune::v1::render::normalize_key;
+use rand::seq::SliceRandom;
+use regex::Regex;
+use schemars::JsonSchema;
+use serde::{Deserialize as _, Deserializer};
 use serde_derive::{Deserialize, Serialize};
-use std::collections::HashMap;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub const DEFAULT_SECRET_LENGTH: usize = 32;
+pub const DEFAULT_SECRET_ALPHABET: &str =
+    "ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+
+/// Where a secret's value comes from, when it isn't set like any other config
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
 #[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
 pub enum SecretSource {
-    Generate,
-    Env { file: String },
+    /// Random characters from `alphabet`, generated by the controller when the
+    /// rune is deployed
+    Generate {
+        length: Option<usize>,
+        alphabet: Option<String>,
+    },
+    /// A variable in an env file, read by `uruz` when the rune is added. The
+    /// variable defaults to the key in upper case, such as `ROOT_PASSWORD` for
+    /// `root-password`.
+    Env {
+        file: String,
+        variable: Option<String>,
+    },
+}
+
+impl SecretSource {
+    /// A new random value, for secrets that are generated
+    pub fn generate(&self) -> Option<String> {
+        match self {
+            SecretSource::Generate { length, alphabet } => {
+                let alphabet: Vec<char> = alphabet
+                    .as_deref()
+                    .unwrap_or(DEFAULT_SECRET_ALPHABET)
+                    .chars()
+                    .collect();
+                let mut rng = rand::thread_rng();
+                Some(
+                    (0..length.unwrap_or(DEFAULT_SECRET_LENGTH))
+                        .filter_map(|_| alphabet.choose(&mut rng))
+                        .collect(),
+                )
+            }
+            SecretSource::Env { .. } => None,
+        }
+    }
+
+    /// The env file and variable to read for secret `key`, for secrets that
+    /// come from one
+    pub fn env_variable(&self, key: &str) -> Option<(&str, String)> {
+        match self {
+            SecretSource::Env { file, variable } => Some((
+                file,
+                variable
+                    .clone()
+                    .unwrap_or_else(|| normalize_key(key).to_uppercase()),
+            )),
+            SecretSource::Generate { .. } => None,
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+/// A config item declared in `metadata.yaml`
+///
+/// Items other than booleans may leave out their default, in which case they
+/// have no value until one is set, and may be marked as `required` to insist
+/// that one is. Regexes have to match the whole of a string value.
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
 #[serde(tag = "type", rename_all = "lowercase", deny_unknown_fields)]
 pub enum ConfigItem {
     Boolean {
@@ -18,12 +82,51 @@ pub enum ConfigItem {
     },
     Integer {
         description: String,
-        default: u32,
+        default: Option<i64>,
+        min: Option<i64>,
+        max: Option<i64>,
+        #[serde(default)]
+        required: bool,
+        transformer: Option<String>,
+    },
+    Float {
+        description: String,
+        default: Option<f64>,
+        min: Option<f64>,
+        max: Option<f64>,
+        #[serde(default)]
+        required: bool,
         transformer: Option<String>,
     },
     String {
         description: String,
-        default: String,
+        default: Option<String>,
+        regex: Option<String>,
+        #[serde(default)]
+        required: bool,
+        transformer: Option<String>,
+    },
+    List {
+        description: String,
+        default: Option<Vec<Value>>,
+        #[serde(default)]
+        required: bool,
+        transformer: Option<String>,
+    },
+    Map {
+        description: String,
+        default: Option<BTreeMap<String, Value>>,
+        #[serde(default)]
+        required: bool,
+        transformer: Option<String>,
+    },
+    /// A string limited to one of `values`
+    Enum {
+        description: String,
+        values: Vec<String>,
+        default: Option<String>,
+        #[serde(default)]
+        required: bool,
         transformer: Option<String>,
     },
     Secret {
@@ -37,25 +140,233 @@ pub enum ConfigItem {
     },
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+fn invalid<T>(key: &str, message: String) -> Result<T, Error> {
+    Err(Error::InvalidConfigValue {
+        key: key.into(),
+        message,
+    })
+}
+
+/// Compiles a regex constraint so that it has to match the whole value
+fn whole_match(regex: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", regex))
+}
+
+impl ConfigItem {
+    /// Name of the function in `transformers.py` that transforms this item
+    pub fn transformer(&self) -> Option<&str> {
+        match self {
+            ConfigItem::Boolean { transformer, .. }
+            | ConfigItem::Integer { transformer, .. }
+            | ConfigItem::Float { transformer, .. }
+            | ConfigItem::String { transformer, .. }
+            | ConfigItem::List { transformer, .. }
+            | ConfigItem::Map { transformer, .. }
+            | ConfigItem::Enum { transformer, .. }
+            | ConfigItem::Secret { transformer, .. }
+            | ConfigItem::Archive { transformer, .. } => transformer.as_deref(),
+        }
+    }
+
+    /// The item's type, as written in `metadata.yaml`
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ConfigItem::Boolean { .. } => "boolean",
+            ConfigItem::Integer { .. } => "integer",
+            ConfigItem::Float { .. } => "float",
+            ConfigItem::String { .. } => "string",
+            ConfigItem::List { .. } => "list",
+            ConfigItem::Map { .. } => "map",
+            ConfigItem::Enum { .. } => "enum",
+            ConfigItem::Secret { .. } => "secret",
+            ConfigItem::Archive { .. } => "archive",
+        }
+    }
+
+    /// Whether the item has to have a value
+    pub fn required(&self) -> bool {
+        match self {
+            ConfigItem::Integer { required, .. }
+            | ConfigItem::Float { required, .. }
+            | ConfigItem::String { required, .. }
+            | ConfigItem::List { required, .. }
+            | ConfigItem::Map { required, .. }
+            | ConfigItem::Enum { required, .. } => *required,
+            ConfigItem::Boolean { .. } | ConfigItem::Secret { .. } | ConfigItem::Archive { .. } => {
+                false
+            }
+        }
+    }
+
+    /// The item's value until another is set, if it has one
+    pub fn default_value(&self) -> Option<Value> {
+        match self {
+            ConfigItem::Boolean { default, .. } => Some(json!(default)),
+            ConfigItem::Integer { default, .. } => default.map(|d| json!(d)),
+            ConfigItem::Float { default, .. } => default.map(|d| json!(d)),
+            ConfigItem::String { default, .. } | ConfigItem::Enum { default, .. } => {
+                default.as_ref().map(|d| json!(d))
+            }
+            ConfigItem::List { default, .. } => default.as_ref().map(|d| json!(d)),
+            ConfigItem::Map { default, .. } => default.as_ref().map(|d| json!(d)),
+            ConfigItem::Secret { .. } | ConfigItem::Archive { .. } => None,
+        }
+    }
+
+    /// Checks that the item's constraints make sense, and that its default
+    /// meets them
+    pub fn check_declaration(&self, key: &str) -> Result<(), Error> {
+        match self {
+            ConfigItem::Integer {
+                min: Some(min),
+                max: Some(max),
+                ..
+            } if min > max => return invalid(key, format!("has min {} above max {}", min, max)),
+            ConfigItem::Float {
+                min: Some(min),
+                max: Some(max),
+                ..
+            } if min > max => return invalid(key, format!("has min {} above max {}", min, max)),
+            ConfigItem::String {
+                regex: Some(regex), ..
+            } => {
+                if let Err(err) = whole_match(regex) {
+                    return invalid(key, format!("has an invalid regex: {}", err));
+                }
+            }
+            ConfigItem::Enum { values, .. } if values.is_empty() => {
+                return invalid(key, "has no allowed values".into())
+            }
+            ConfigItem::Secret {
+                source: Some(SecretSource::Generate { length, alphabet }),
+                ..
+            } => {
+                if *length == Some(0) {
+                    return invalid(key, "can't generate secrets of length 0".into());
+                }
+                if alphabet.as_deref() == Some("") {
+                    return invalid(key, "can't generate secrets from an empty alphabet".into());
+                }
+            }
+            _ => {}
+        }
+
+        match self.default_value() {
+            Some(default) => self.check(key, &default),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that `value` is of the item's type and meets its constraints
+    pub fn check(&self, key: &str, value: &Value) -> Result<(), Error> {
+        let mismatch = || invalid(key, format!("must be of type {}", self.type_name()));
+
+        match self {
+            ConfigItem::Boolean { .. } => match value {
+                Value::Bool(_) => Ok(()),
+                _ => mismatch(),
+            },
+            ConfigItem::Integer { min, max, .. } => match (value.as_i64(), min, max) {
+                (Some(i), Some(min), _) if i < *min => {
+                    invalid(key, format!("must be at least {}, got {}", min, i))
+                }
+                (Some(i), _, Some(max)) if i > *max => {
+                    invalid(key, format!("must be at most {}, got {}", max, i))
+                }
+                (Some(_), _, _) => Ok(()),
+                (None, _, _) => mismatch(),
+            },
+            ConfigItem::Float { min, max, .. } => match (value.as_f64(), min, max) {
+                (Some(f), Some(min), _) if f < *min => {
+                    invalid(key, format!("must be at least {}, got {}", min, f))
+                }
+                (Some(f), _, Some(max)) if f > *max => {
+                    invalid(key, format!("must be at most {}, got {}", max, f))
+                }
+                (Some(_), _, _) => Ok(()),
+                (None, _, _) => mismatch(),
+            },
+            ConfigItem::String { regex, .. } => match (value, regex) {
+                (Value::String(s), Some(regex)) => match whole_match(regex) {
+                    Ok(re) if re.is_match(s) => Ok(()),
+                    Ok(_) => invalid(key, format!("must match {}, got {:?}", regex, s)),
+                    Err(err) => invalid(key, format!("has an invalid regex: {}", err)),
+                },
+                (Value::String(_), None) => Ok(()),
+                _ => mismatch(),
+            },
+            ConfigItem::List { .. } => match value {
+                Value::Array(_) => Ok(()),
+                _ => mismatch(),
+            },
+            ConfigItem::Map { .. } => match value {
+                Value::Object(_) => Ok(()),
+                _ => mismatch(),
+            },
+            ConfigItem::Enum { values, .. } => match value {
+                Value::String(s) if values.contains(s) => Ok(()),
+                Value::String(s) => invalid(
+                    key,
+                    format!("must be one of {}, got {:?}", values.join(", "), s),
+                ),
+                _ => mismatch(),
+            },
+            ConfigItem::Secret { .. } | ConfigItem::Archive { .. } => match value {
+                Value::String(_) => Ok(()),
+                _ => mismatch(),
+            },
+        }
+    }
+
+    /// Parses a value given as a string, such as on the command line, and
+    /// checks it. Lists and maps are given as YAML or JSON.
+    pub fn parse(&self, key: &str, value: &str) -> Result<Value, Error> {
+        let parsed = match self {
+            ConfigItem::Boolean { .. } => value.parse().map(Value::Bool).ok(),
+            ConfigItem::Integer { .. } => value.trim().parse::<i64>().map(|i| json!(i)).ok(),
+            ConfigItem::Float { .. } => value
+                .trim()
+                .parse::<f64>()
+                .ok()
+                .and_then(|f| serde_json::Number::from_f64(f).map(Value::Number)),
+            ConfigItem::List { .. } | ConfigItem::Map { .. } => serde_yaml::from_str(value).ok(),
+            ConfigItem::String { .. }
+            | ConfigItem::Enum { .. }
+            | ConfigItem::Secret { .. }
+            | ConfigItem::Archive { .. } => Some(Value::String(value.into())),
+        };
+
+        match parsed {
+            Some(parsed) => {
+                self.check(key, &parsed)?;
+                Ok(parsed)
+            }
+            None => invalid(key, format!("must be of type {}", self.type_name())),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Provide {
-    name: String,
-    interface: String,
+    pub name: String,
+    pub interface: String,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Require {
-    name: String,
-    interface: String,
-    min: Option<u32>,
-    max: Option<u32>,
+    pub name: String,
+    pub interface: String,
+    pub min: Option<u32>,
+    pub max: Option<u32>,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Metadata {
+    #[serde(deserialize_with = "deserialize_version")]
+    #[schemars(schema_with = "super::schema::version")]
     pub version: String,
     pub name: String,
     pub description: String,
@@ -68,7 +379,38 @@ pub struct Metadata {
     #[serde(default)]
     pub requires: Vec<Require>,
     #[serde(default)]
-    pub dependencies: HashMap<String, String>,
+    pub dependencies: BTreeMap<String, String>,
     pub react: Option<String>,
-    pub config: HashMap<String, ConfigItem>,
+    pub config: BTreeMap<String, ConfigItem>,
+}
+
+impl Metadata {
+    /// Env files that secrets are read from, relative to the rune directory
+    pub fn env_files(&self) -> Vec<&str> {
+        self.config
+            .iter()
+            .filter_map(|(key, item)| match item {
+                ConfigItem::Secret {
+                    source: Some(source),
+                    ..
+                } => Some(source.env_variable(key)?.0),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Accepts the format version written as either a number or a string
+fn deserialize_version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Version {
+        Number(u64),
+        String(String),
+    }
+
+    Ok(match Version::deserialize(deserializer)? {
+        Version::Number(n) => n.to_string(),
+        Version::String(s) => s,
+    })
 }
@@ -1,5 +1,11 @@
+pub mod archive;
+pub mod files;
+pub mod lint;
 pub mod metadata;
+pub mod render;
 pub mod rune;
+pub mod schema;
+pub mod signature;
 pub mod template;
 
 pub use rune::Rune;
@@ -0,0 +1,280 @@
+//! Resolves `{{ state.* }}` expressions in templates
+//!
+//! Expressions are looked up in a [`State`], which is serialized with the same
+//! names the templates use, e.g. `state.config.port` or
+//! `state.relations.mysql.juju.host`. This lives with the rune format rather
+//! than any one cloud so that every provider resolves templates the same way.
+//!
+//! Config keys are normalized with [`normalize_key`] on both sides, so an item
+//! declared as `grpc-port` is referred to as `state.config.grpc_port`. Secret
+//! config items are kept out of `state.config`, and referred to as
+//! `state.secrets.<key>` instead.
+
+use super::template::{Image, Port, Template, TemplateInteger};
+use crate::rune::error::Error;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Where a rune is deployed, available as `state.juju`
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Deployment {
+    pub model_name: String,
+    pub host: Option<String>,
+}
+
+/// A rune on the other end of a relation, available as
+/// `state.relations.<name>`
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct Relation {
+    pub juju: Deployment,
+    pub config: BTreeMap<String, Value>,
+    #[serde(default)]
+    pub secrets: BTreeMap<String, String>,
+}
+
+/// Everything a template can refer to
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct State {
+    pub config: BTreeMap<String, Value>,
+    /// Values of the rune's secret config items, for those that are set
+    #[serde(default)]
+    pub secrets: BTreeMap<String, String>,
+    pub relations: BTreeMap<String, Relation>,
+    pub juju: Deployment,
+}
+
+/// An expression that couldn't be resolved, along with where it was used,
+/// such as `pipelines-api.environment.MYSQL_SERVICE_HOST`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unresolved {
+    pub field: String,
+    pub reference: String,
+}
+
+impl fmt::Display for Unresolved {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {{{{ {} }}}}", self.field, self.reference)
+    }
+}
+
+/// Normalizes a config key for use in templates, turning hyphens into
+/// underscores
+pub fn normalize_key(key: &str) -> String {
+    key.replace('-', "_")
+}
+
+fn normalize_config<V: Clone + Into<Value>>(config: &BTreeMap<String, V>) -> Value {
+    Value::Object(
+        config
+            .iter()
+            .map(|(key, value)| (normalize_key(key), value.clone().into()))
+            .collect::<Map<_, _>>(),
+    )
+}
+
+impl State {
+    /// The state as seen by templates, with config keys normalized
+    fn context(&self) -> Result<Value, Error> {
+        let mut context = serde_json::to_value(self)?;
+        context["config"] = normalize_config(&self.config);
+        context["secrets"] = normalize_config(&self.secrets);
+        for (name, relation) in &self.relations {
+            context["relations"][name]["config"] = normalize_config(&relation.config);
+            context["relations"][name]["secrets"] = normalize_config(&relation.secrets);
+        }
+        Ok(context)
+    }
+}
+
+struct Renderer {
+    state: Value,
+    unresolved: Vec<Unresolved>,
+}
+
+impl Renderer {
+    fn lookup(&self, reference: &str) -> Option<String> {
+        let mut path = reference.split('.');
+        if path.next() != Some("state") {
+            return None;
+        }
+
+        let mut value = &self.state;
+        let mut previous = "state";
+        for key in path {
+            value = match previous {
+                "config" | "secrets" => value.get(normalize_key(key))?,
+                _ => value.get(key)?,
+            };
+            previous = key;
+        }
+
+        match value {
+            Value::String(s) => Some(s.clone()),
+            Value::Number(n) => Some(n.to_string()),
+            Value::Bool(b) => Some(b.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Substitutes every expression in `input`, leaving unresolved ones as
+    /// they were
+    fn string(&mut self, field: &str, input: &str) -> String {
+        let mut output = String::new();
+        let mut rest = input;
+
+        while let Some(start) = rest.find("{{") {
+            let end = match rest[start..].find("}}") {
+                Some(end) => start + end,
+                None => break,
+            };
+            output.push_str(&rest[..start]);
+
+            let reference = rest[start + 2..end].trim();
+            match self.lookup(reference) {
+                Some(value) => output.push_str(&value),
+                None => {
+                    self.unresolved.push(Unresolved {
+                        field: field.into(),
+                        reference: reference.into(),
+                    });
+                    output.push_str(&rest[start..end + 2]);
+                }
+            }
+            rest = &rest[end + 2..];
+        }
+
+        output.push_str(rest);
+        output
+    }
+
+    fn strings(&mut self, field: &str, input: &[String]) -> Vec<String> {
+        input
+            .iter()
+            .enumerate()
+            .map(|(i, s)| self.string(&format!("{}.{}", field, i), s))
+            .collect()
+    }
+
+    fn integer(&mut self, field: &str, input: &TemplateInteger) -> TemplateInteger {
+        match input {
+            TemplateInteger::Integer(i) => TemplateInteger::Integer(*i),
+            TemplateInteger::Template(s) => {
+                let rendered = self.string(field, s);
+                match rendered.trim().parse() {
+                    Ok(i) => TemplateInteger::Integer(i),
+                    Err(_) => TemplateInteger::Template(rendered),
+                }
+            }
+        }
+    }
+
+    fn yaml(&mut self, field: &str, input: &serde_yaml::Value) -> serde_yaml::Value {
+        use serde_yaml::Value as Yaml;
+
+        match input {
+            Yaml::String(s) => Yaml::String(self.string(field, s)),
+            Yaml::Sequence(items) => Yaml::Sequence(
+                items
+                    .iter()
+                    .enumerate()
+                    .map(|(i, item)| self.yaml(&format!("{}.{}", field, i), item))
+                    .collect(),
+            ),
+            Yaml::Mapping(mapping) => Yaml::Mapping(
+                mapping
+                    .iter()
+                    .map(|(key, value)| {
+                        let field = match key {
+                            Yaml::String(key) => format!("{}.{}", field, key),
+                            _ => field.to_string(),
+                        };
+                        (key.clone(), self.yaml(&field, value))
+                    })
+                    .collect(),
+            ),
+            other => other.clone(),
+        }
+    }
+}
+
+/// Renders `template` from `rune` against `state`, failing with every
+/// expression that couldn't be resolved, or else the first port that isn't a
+/// valid port number
+pub fn render(rune: &str, template: &Template, state: &State) -> Result<Template, Error> {
+    let mut renderer = Renderer {
+        state: state.context()?,
+        unresolved: vec![],
+    };
+    let rendered = render_with(&mut renderer, template);
+
+    if !renderer.unresolved.is_empty() {
+        return Err(Error::UnresolvedReferences(renderer.unresolved));
+    }
+    rendered.check_ports(rune)?;
+    Ok(rendered)
+}
+
+/// Returns every expression in `template`, along with where it's used
+pub fn references(template: &Template) -> Vec<Unresolved> {
+    let mut renderer = Renderer {
+        state: Value::Null,
+        unresolved: vec![],
+    };
+    render_with(&mut renderer, template);
+    renderer.unresolved
+}
+
+fn render_with(renderer: &mut Renderer, template: &Template) -> Template {
+    let name = &template.name;
+
+    let environment: BTreeMap<_, _> = template.environment.iter().collect();
+    let environment = environment
+        .into_iter()
+        .map(|(key, value)| {
+            let field = format!("{}.environment.{}", name, key);
+            (key.clone(), renderer.string(&field, value))
+        })
+        .collect();
+
+    let image = match &template.image {
+        Image::Source { source } => Image::Source {
+            source: renderer.string(&format!("{}.image.source", name), source),
+        },
+        Image::Build { build } => Image::Build {
+            build: renderer.string(&format!("{}.image.build", name), build),
+        },
+    };
+
+    let ports = template
+        .ports
+        .iter()
+        .map(|port| Port {
+            name: port.name.clone(),
+            container_port: renderer.integer(
+                &format!("{}.ports.{}.containerPort", name, port.name),
+                &port.container_port,
+            ),
+        })
+        .collect();
+
+    Template {
+        name: name.clone(),
+        command: renderer.strings(&format!("{}.command", name), &template.command),
+        args: renderer.strings(&format!("{}.args", name), &template.args),
+        environment,
+        image,
+        ports,
+        include: template
+            .include
+            .as_ref()
+            .map(|include| renderer.yaml(&format!("{}.include", name), include)),
+    }
+}
+
+impl Template {
+    pub fn render(&self, rune: &str, state: &State) -> Result<Template, Error> {
+        render(rune, self, state)
+    }
+}
@@ -1,16 +1,20 @@
-use super::metadata::{ConfigItem, Metadata};
-use super::template::Template;
-use crate::api::v1::Rune as ApiRune;
-use crate::rune::error::Error;
+use super::archive;
+use super::archive::{MANIFEST_FILE, SIGNATURE_FILE};
+use super::files::read_files;
+use super::metadata::{ConfigItem, Metadata, SecretSource};
+use super::render::{normalize_key, references, State};
+use super::signature;
+use super::template::{Image, Template, TemplateInteger};
+use crate::api::v1::{Rune as ApiRune, REDACTED};
+use crate::rune::error::{Diagnostic, Error};
+use crate::rune::version;
+use ed25519_dalek::Keypair;
 use serde_derive::{Deserialize, Serialize};
-use serde_yaml::{from_slice, to_vec};
-use std::collections::HashMap;
+use serde_json::Value;
+use serde_yaml::{from_slice, from_value, to_vec};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs::read;
-use std::io::{Cursor, Read, Write};
-use std::path::{Path, PathBuf};
-use zip::result::ZipError;
-use zip::write::FileOptions;
-use zip::{ZipArchive, ZipWriter};
+use std::path::PathBuf;
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Rune {
@@ -18,90 +22,541 @@ pub struct Rune {
     pub template: Vec<Template>,
     pub transformers: Option<String>,
     pub react: Option<String>,
+    /// Every other file in the rune directory, keyed by relative path
+    #[serde(default, with = "base64_files")]
+    pub files: BTreeMap<String, Vec<u8>>,
+    /// Paths of those of `files` that are executable
+    #[serde(default)]
+    pub executables: BTreeSet<String>,
+    /// References to images built from the rune's build contexts, by template
+    /// name. Filled in by the controller, and never part of the archive.
+    #[serde(default)]
+    pub images: BTreeMap<String, String>,
+    /// Values of secret config items, by key. Given when the rune is added or
+    /// generated by the controller, and never part of the archive.
+    #[serde(default)]
+    pub secrets: BTreeMap<String, String>,
+}
+
+/// Stores file contents as base64 strings, which are far smaller and quicker
+/// to parse than JSON arrays of bytes when runes are kept in model history
+mod base64_files {
+    use serde::de::Error;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::collections::BTreeMap;
+
+    pub fn serialize<S: Serializer>(
+        files: &BTreeMap<String, Vec<u8>>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        files
+            .iter()
+            .map(|(name, contents)| (name, base64::encode(contents)))
+            .collect::<BTreeMap<_, _>>()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<BTreeMap<String, Vec<u8>>, D::Error> {
+        BTreeMap::<String, String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|(name, contents)| {
+                base64::decode(&contents)
+                    .map(|contents| (name, contents))
+                    .map_err(D::Error::custom)
+            })
+            .collect()
+    }
+}
+
+/// Stores a single blob as a base64 string, as [`base64_files`] does for file
+/// contents. Blobs stored as arrays of bytes before that are still read.
+pub(crate) mod base64_bytes {
+    use serde::de::Error;
+    use serde::{Deserialize as _, Deserializer, Serializer};
+    use serde_derive::Deserialize;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Encoded {
+        Base64(String),
+        Bytes(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&base64::encode(bytes))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        match Encoded::deserialize(deserializer)? {
+            Encoded::Base64(encoded) => base64::decode(&encoded).map_err(D::Error::custom),
+            Encoded::Bytes(bytes) => Ok(bytes),
+        }
+    }
+}
+
+/// Keeps the value if it deserialized, or else records where it went wrong
+fn typed<T>(
+    diagnostics: &mut Vec<Diagnostic>,
+    file: &str,
+    result: Result<T, serde_yaml::Error>,
+) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(err) => {
+            diagnostics.push(Diagnostic::from_yaml(file, &err));
+            None
+        }
+    }
 }
 
 impl Rune {
     pub fn load<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
+        let rune = Self::read(path)?;
+        rune.validate()?;
+        Ok(rune)
+    }
+
+    /// Parses `metadata.yaml` and `rune.yaml` in any supported format,
+    /// converting them to the latest one. Each file is checked even if the
+    /// other couldn't be read, and every problem found is reported together.
+    fn parse(
+        metadata: Result<Vec<u8>, String>,
+        template: Result<Vec<u8>, String>,
+    ) -> Result<(Metadata, Vec<Template>), Error> {
+        let mut diagnostics = vec![];
+
+        let mut yaml = |file: &str, bytes: Result<Vec<u8>, String>| match bytes {
+            Ok(bytes) => match from_slice::<serde_yaml::Value>(&bytes) {
+                Ok(value) => Some((bytes, value)),
+                Err(err) => {
+                    diagnostics.push(Diagnostic::from_yaml(file, &err));
+                    None
+                }
+            },
+            Err(message) => {
+                diagnostics.push(Diagnostic::new(file, message));
+                None
+            }
+        };
+        let metadata = yaml("metadata.yaml", metadata);
+        let template = yaml("rune.yaml", template);
+
+        let original = match &metadata {
+            Some((_, value)) => match version::version(value) {
+                Ok(original) => Some(original),
+                Err(err) => {
+                    let mut diagnostic = Diagnostic::new("metadata.yaml", err);
+                    diagnostic.key = Some("version".into());
+                    diagnostics.push(diagnostic);
+                    None
+                }
+            },
+            None => None,
+        };
+
+        // Files already in the latest format are deserialized from their
+        // source, so that errors keep their line and column
+        let (metadata, template) = match (metadata, template, original) {
+            (Some((_, mut metadata)), Some((_, mut template)), Some(original))
+                if original < version::LATEST =>
+            {
+                version::upgrade(&mut metadata, &mut template)?;
+                (
+                    typed(&mut diagnostics, "metadata.yaml", from_value(metadata)),
+                    typed(&mut diagnostics, "rune.yaml", from_value(template)),
+                )
+            }
+            (metadata, template, _) => (
+                metadata.and_then(|(bytes, _)| {
+                    typed(&mut diagnostics, "metadata.yaml", from_slice(&bytes))
+                }),
+                template.and_then(|(bytes, _)| {
+                    typed(&mut diagnostics, "rune.yaml", from_slice(&bytes))
+                }),
+            ),
+        };
+
+        diagnostics.sort_by(|a, b| a.file.cmp(&b.file));
+        match (metadata, template) {
+            (Some(metadata), Some(template)) if diagnostics.is_empty() => Ok((metadata, template)),
+            _ => Err(Error::InvalidRune(diagnostics)),
+        }
+    }
+
+    /// Loads a rune directory without validating it, for tools that report
+    /// problems themselves
+    pub fn read<P: Into<PathBuf>>(path: P) -> Result<Self, Error> {
         let path = path.into();
-        let metadata = from_slice(&read(path.join("metadata.yaml"))?)?;
-        let template = from_slice(&read(path.join("rune.yaml"))?)?;
+        let read_file = |name| read(path.join(name)).map_err(|err| err.to_string());
+        let (metadata, template) = Self::parse(read_file("metadata.yaml"), read_file("rune.yaml"))?;
         let transformers = read(path.join("transformers.py"))
             .and_then(|bytes| Ok(String::from_utf8_lossy(&bytes).to_string()))
             .ok();
         let react = read(path.join("rune.py"))
             .and_then(|bytes| Ok(String::from_utf8_lossy(&bytes).to_string()))
             .ok();
+        let (files, executables) = read_files(&path, &metadata.env_files())?;
 
         Ok(Self {
             metadata,
             template,
             transformers,
             react,
+            files,
+            executables,
+            images: BTreeMap::new(),
+            secrets: BTreeMap::new(),
         })
     }
 
-    pub fn zip(&self) -> Result<Vec<u8>, Error> {
-        let buffer = Cursor::new(Vec::new());
-        let mut writer = ZipWriter::new(buffer);
+    /// Checks that config item declarations make sense, that every
+    /// `state.config.*` and `state.secrets.*` reference in the templates names
+    /// a declared config item or secret once keys are normalized, that every
+    /// `state.relations.*` reference names a relation in `requires`, and that
+    /// ports are either valid port numbers or refer to integer config items
+    pub fn validate(&self) -> Result<(), Error> {
+        let mut declared: HashMap<String, &String> = HashMap::new();
+        let mut keys: Vec<_> = self.metadata.config.keys().collect();
+        keys.sort();
+        for key in &keys {
+            if let Some(other) = declared.insert(normalize_key(key), *key) {
+                return Err(Error::ConflictingConfigKeys(other.clone(), (*key).clone()));
+            }
+        }
 
-        writer.start_file_from_path(Path::new("metadata.yaml"), FileOptions::default())?;
-        writer.write_all(&to_vec(&self.metadata)?)?;
-        writer.start_file_from_path(Path::new("rune.yaml"), FileOptions::default())?;
-        writer.write_all(&to_vec(&self.template)?)?;
+        for key in &keys {
+            self.metadata.config[*key].check_declaration(key)?;
+        }
 
-        if let Some(tfs) = &self.transformers {
-            writer.start_file_from_path(Path::new("transformers.py"), FileOptions::default())?;
-            writer.write_all(tfs.as_bytes())?;
+        let unknown: Vec<_> = self
+            .template
+            .iter()
+            .flat_map(references)
+            .filter(|r| {
+                let mut path = r.reference.split('.');
+                let (section, key) = match (path.next(), path.next(), path.next()) {
+                    (Some("state"), Some(section), Some(key)) => (section, key),
+                    _ => return false,
+                };
+                let secret = match declared.get(&normalize_key(key)) {
+                    Some(key) => matches!(self.metadata.config[*key], ConfigItem::Secret { .. }),
+                    None => return section == "config" || section == "secrets",
+                };
+                match section {
+                    "config" => secret,
+                    "secrets" => !secret,
+                    _ => false,
+                }
+            })
+            .collect();
+
+        if !unknown.is_empty() {
+            return Err(Error::UnknownConfigReferences(unknown));
         }
 
-        if let Some(react) = &self.react {
-            writer.start_file_from_path(Path::new("rune.py"), FileOptions::default())?;
-            writer.write_all(react.as_bytes())?;
+        let undeclared: Vec<_> = self
+            .template
+            .iter()
+            .flat_map(references)
+            .filter(|r| {
+                let mut path = r.reference.split('.');
+                match (path.next(), path.next(), path.next()) {
+                    (Some("state"), Some("relations"), Some(name)) => {
+                        !self.metadata.requires.iter().any(|r| r.name == name)
+                    }
+                    _ => false,
+                }
+            })
+            .collect();
+
+        if !undeclared.is_empty() {
+            return Err(Error::UndeclaredRelations(undeclared));
         }
 
-        let finished = writer.finish()?;
-        Ok(finished.into_inner())
+        for template in &self.template {
+            let ports: Vec<_> = template
+                .ports
+                .iter()
+                .map(|port| format!("{}.ports.{}.containerPort", template.name, port.name))
+                .collect();
+            for reference in references(template) {
+                if !ports.contains(&reference.field) {
+                    continue;
+                }
+                let mut path = reference.reference.split('.');
+                if let (Some("state"), Some("config"), Some(key)) =
+                    (path.next(), path.next(), path.next())
+                {
+                    let declared_key = declared[&normalize_key(key)];
+                    match self.metadata.config[declared_key] {
+                        ConfigItem::Integer { .. } => {}
+                        _ => {
+                            return Err(Error::ConfigTypeMismatch {
+                                field: reference.field,
+                                key: declared_key.clone(),
+                                expected: "integer".into(),
+                            })
+                        }
+                    }
+                }
+            }
+
+            for port in &template.ports {
+                if let TemplateInteger::Integer(_) = port.container_port {
+                    port.check(&self.metadata.name, &template.name)?;
+                }
+            }
+        }
+
+        Ok(())
     }
 
-    pub fn unzip(bytes: &[u8]) -> Result<Rune, Error> {
-        let buffer = Cursor::new(bytes);
-        let mut reader = ZipArchive::new(buffer).unwrap();
-
-        let mut metadata = String::new();
-        reader
-            .by_name("metadata.yaml")?
-            .read_to_string(&mut metadata)?;
-        let metadata = from_slice(metadata.as_bytes())?;
-
-        let mut template = String::new();
-        reader.by_name("rune.yaml")?.read_to_string(&mut template)?;
-        let template = from_slice(template.as_bytes())?;
-
-        let transformers = match reader.by_name("transformers.py") {
-            Ok(mut f) => {
-                let mut buf = String::new();
-                f.read_to_string(&mut buf)?;
-                Ok(Some(buf))
+    /// The defaults of the rune's config items other than secrets, which is
+    /// its config until it's configured
+    pub fn default_config(&self) -> BTreeMap<String, Value> {
+        self.metadata
+            .config
+            .iter()
+            .filter(|(key, _)| !self.secret_keys().any(|secret| secret == *key))
+            .filter_map(|(key, item)| Some((key.clone(), item.default_value()?)))
+            .collect()
+    }
+
+    /// Checks a full set of config values against the rune's config items,
+    /// including that every required item has a value
+    pub fn check_config(&self, config: &BTreeMap<String, Value>) -> Result<(), Error> {
+        for (key, value) in config {
+            match self.metadata.config.get(key) {
+                Some(item) => item.check(key, value)?,
+                None => {
+                    return Err(Error::InvalidConfigValue {
+                        key: key.clone(),
+                        message: "isn't declared".into(),
+                    })
+                }
             }
-            Err(ZipError::FileNotFound) => Ok(None),
-            Err(err) => Err(Error::ZipError(err)),
-        }?;
-
-        let react = match reader.by_name("rune.py") {
-            Ok(mut f) => {
-                let mut buf = String::new();
-                f.read_to_string(&mut buf)?;
-                Ok(Some(buf))
+        }
+        for (key, item) in &self.metadata.config {
+            if item.required() && !config.contains_key(key) {
+                return Err(Error::InvalidConfigValue {
+                    key: key.clone(),
+                    message: "is required".into(),
+                });
             }
-            Err(ZipError::FileNotFound) => Ok(None),
-            Err(err) => Err(Error::ZipError(err)),
-        }?;
+        }
+        Ok(())
+    }
+
+    /// Checks a value for config item `key`, parsing it first if it's a
+    /// string given for an item of another type
+    pub fn config_value(&self, key: &str, value: Value) -> Result<Value, Error> {
+        let item = self
+            .metadata
+            .config
+            .get(key)
+            .ok_or_else(|| Error::InvalidConfigValue {
+                key: key.into(),
+                message: "isn't declared".into(),
+            })?;
+        match value {
+            Value::String(s) => item.parse(key, &s),
+            other => {
+                item.check(key, &other)?;
+                Ok(other)
+            }
+        }
+    }
+
+    /// Checks a batch of config changes, as with [`Rune::config_value`],
+    /// resolving each key in `reset` to its default. Keys without a default
+    /// are left without a value, unless they're required.
+    pub fn config_changes(
+        &self,
+        config: BTreeMap<String, Value>,
+        reset: &[String],
+    ) -> Result<BTreeMap<String, Option<Value>>, Error> {
+        let invalid = |key: &str, message: &str| Error::InvalidConfigValue {
+            key: key.into(),
+            message: message.into(),
+        };
+
+        let mut changes = BTreeMap::new();
+        for (key, value) in config {
+            let value = self.config_value(&key, value)?;
+            changes.insert(key, Some(value));
+        }
+        for key in reset {
+            let item = self
+                .metadata
+                .config
+                .get(key)
+                .ok_or_else(|| invalid(key, "isn't declared"))?;
+            if changes.contains_key(key) {
+                return Err(invalid(key, "can't be both set and reset"));
+            }
+            let default = item.default_value();
+            if default.is_none() && item.required() {
+                return Err(invalid(key, "is required, and has no default to reset to"));
+            }
+            changes.insert(key.clone(), default);
+        }
+        Ok(changes)
+    }
+
+    /// Keys of the rune's secret config items
+    pub fn secret_keys(&self) -> impl Iterator<Item = &String> {
+        self.metadata
+            .config
+            .iter()
+            .filter(|(_, item)| matches!(item, ConfigItem::Secret { .. }))
+            .map(|(key, _)| key)
+    }
+
+    /// Checks that every key in `secrets` is a secret config item
+    pub fn check_secrets(&self, secrets: &BTreeMap<String, String>) -> Result<(), Error> {
+        for key in secrets.keys() {
+            match self.metadata.config.get(key) {
+                Some(ConfigItem::Secret { .. }) => {}
+                Some(_) => {
+                    return Err(Error::InvalidConfigValue {
+                        key: key.clone(),
+                        message: "isn't a secret".into(),
+                    })
+                }
+                None => {
+                    return Err(Error::InvalidConfigValue {
+                        key: key.clone(),
+                        message: "isn't declared".into(),
+                    })
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether any of the rune's secrets are generated by the controller
+    pub fn generates_secrets(&self) -> bool {
+        self.metadata.config.values().any(|item| {
+            matches!(
+                item,
+                ConfigItem::Secret {
+                    source: Some(SecretSource::Generate { .. }),
+                    ..
+                }
+            )
+        })
+    }
+
+    /// Generates a value for each secret with a `generate` source that doesn't
+    /// have one yet
+    pub fn generate_secrets(&mut self) {
+        for (key, item) in &self.metadata.config {
+            if self.secrets.contains_key(key) {
+                continue;
+            }
+            if let ConfigItem::Secret {
+                source: Some(source),
+                ..
+            } = item
+            {
+                if let Some(value) = source.generate() {
+                    self.secrets.insert(key.clone(), value);
+                }
+            }
+        }
+    }
+
+    /// The image to deploy for `template`, which for built images is only
+    /// known once the controller has built them
+    pub fn image<'a>(&'a self, template: &'a Template) -> Option<&'a str> {
+        match &template.image {
+            Image::Source { source } => Some(source),
+            Image::Build { .. } => self.images.get(&template.name).map(String::as_str),
+        }
+    }
+
+    /// Renders every template against `state`, reporting all unresolved
+    /// expressions together
+    pub fn render(&self, state: &State) -> Result<Vec<Template>, Error> {
+        let mut rendered = vec![];
+        let mut unresolved = vec![];
+
+        for template in &self.template {
+            match template.render(&self.metadata.name, state) {
+                Ok(template) => rendered.push(template),
+                Err(Error::UnresolvedReferences(refs)) => unresolved.extend(refs),
+                Err(err) => return Err(err),
+            }
+        }
+
+        if unresolved.is_empty() {
+            Ok(rendered)
+        } else {
+            Err(Error::UnresolvedReferences(unresolved))
+        }
+    }
+
+    /// Every file in the rune's archive, keyed by path
+    pub fn entries(&self) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+        let mut entries = self.files.clone();
+        entries.insert("metadata.yaml".into(), to_vec(&self.metadata)?);
+        entries.insert("rune.yaml".into(), to_vec(&self.template)?);
+        if let Some(tfs) = &self.transformers {
+            entries.insert("transformers.py".into(), tfs.as_bytes().to_vec());
+        }
+        if let Some(react) = &self.react {
+            entries.insert("rune.py".into(), react.as_bytes().to_vec());
+        }
+        Ok(entries)
+    }
+
+    /// The rune's content digest, as described in [`archive`]
+    pub fn digest(&self) -> Result<String, Error> {
+        Ok(archive::digest(&self.entries()?))
+    }
+
+    pub fn zip(&self) -> Result<Vec<u8>, Error> {
+        archive::write(&self.entries()?, &self.executables)
+    }
+
+    /// Zips the rune along with a signature over its contents by `key`
+    pub fn zip_signed(&self, key: &Keypair) -> Result<Vec<u8>, Error> {
+        let mut entries = self.entries()?;
+        let signature = signature::sign(&entries, key)?;
+        entries.insert(SIGNATURE_FILE.into(), signature);
+        archive::write(&entries, &self.executables)
+    }
+
+    pub fn unzip(bytes: &[u8]) -> Result<Rune, Error> {
+        let mut entries = archive::read(bytes)?;
+        entries.remove(MANIFEST_FILE);
+        entries.remove(SIGNATURE_FILE);
+
+        let mut take = |name| {
+            entries
+                .remove(name)
+                .ok_or_else(|| "Not found in archive".to_string())
+        };
+        let (metadata, template) = Self::parse(take("metadata.yaml"), take("rune.yaml"))?;
+        let text = |bytes: Vec<u8>| String::from_utf8_lossy(&bytes).to_string();
+        let transformers = entries.remove("transformers.py").map(text);
+        let react = entries.remove("rune.py").map(text);
+        let executables = archive::executables(bytes)?
+            .into_iter()
+            .filter(|name| entries.contains_key(name))
+            .collect();
 
         let rune = Self {
             metadata,
             template,
             transformers,
             react,
+            files: entries,
+            executables,
+            images: BTreeMap::new(),
+            secrets: BTreeMap::new(),
         };
         Ok(rune)
     }
@@ -110,24 +565,26 @@ impl Rune {
 impl Into<ApiRune> for Rune {
     fn into(self) -> ApiRune {
         let mut state = HashMap::new();
+        let mut secrets = HashMap::new();
 
         for (name, item) in &self.metadata.config {
-            state.insert(
-                name.clone(),
-                match item {
-                    ConfigItem::Boolean { default, .. } => Some(default.to_string()),
-                    ConfigItem::Integer { default, .. } => Some(default.to_string()),
-                    ConfigItem::String { default, .. } => Some(default.to_string()),
-                    ConfigItem::Secret { .. } => None,
-                    ConfigItem::Archive { .. } => None,
-                },
-            );
+            match item {
+                ConfigItem::Secret { .. } => {
+                    let redacted = self.secrets.get(name).map(|_| REDACTED.into());
+                    secrets.insert(name.clone(), redacted);
+                }
+                _ => {
+                    state.insert(name.clone(), item.default_value());
+                }
+            }
         }
 
         ApiRune {
             transformers: self.transformers.clone(),
             react: self.react.clone(),
             state,
+            secrets,
+            images: self.images.into_iter().collect(),
         }
     }
 }
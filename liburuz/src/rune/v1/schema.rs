@@ -0,0 +1,31 @@
+//! JSON Schemas for the files rune authors write
+//!
+//! The schemas are derived from the same types that parse `metadata.yaml` and
+//! `rune.yaml`, so they follow any change to the format without being edited
+//! by hand. Editors that understand JSON Schema can use them for completion
+//! and validation of either file.
+
+use super::metadata::Metadata;
+use super::template::Template;
+use schemars::gen::SchemaGenerator;
+use schemars::schema::{InstanceType, RootSchema, Schema, SchemaObject};
+use schemars::schema_for;
+
+/// Schema for `metadata.yaml`
+pub fn metadata() -> RootSchema {
+    schema_for!(Metadata)
+}
+
+/// Schema for `rune.yaml`
+pub fn template() -> RootSchema {
+    schema_for!(Vec<Template>)
+}
+
+/// The format version may be written as a number or a string
+pub(crate) fn version(_: &mut SchemaGenerator) -> Schema {
+    SchemaObject {
+        instance_type: Some(vec![InstanceType::Integer, InstanceType::String].into()),
+        ..Default::default()
+    }
+    .into()
+}
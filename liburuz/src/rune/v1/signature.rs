@@ -0,0 +1,85 @@
+//! Detached ed25519 signatures over rune archives
+//!
+//! A signed archive carries a [`SIGNATURE_FILE`] holding the signer's public
+//! key and a signature over the archive's manifest, which lists the SHA-256
+//! digest and path of every file in the rune. Adding, removing or changing any
+//! file changes the manifest, so the signature no longer verifies.
+//!
+//! Keys are stored hex encoded: secret keys as the 32 byte seed in a file of
+//! their own, and public keys wherever they're trusted.
+
+pub use super::archive::SIGNATURE_FILE;
+use super::archive::{self, manifest};
+use crate::rune::error::Error;
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature as Ed25519Signature, Signer};
+use serde_derive::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::read_to_string;
+use std::path::Path;
+
+/// Contents of [`SIGNATURE_FILE`]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+pub struct Signature {
+    pub key: String,
+    pub signature: String,
+}
+
+pub fn generate_key() -> Keypair {
+    Keypair::generate(&mut rand::rngs::OsRng)
+}
+
+/// Reads a hex encoded secret key from `path`
+pub fn read_key(path: &Path) -> Result<Keypair, Error> {
+    let encoded = read_to_string(path)?;
+    let invalid = |err: String| Error::InvalidKey(format!("{}: {}", path.display(), err));
+    let bytes = hex::decode(encoded.trim()).map_err(|err| invalid(err.to_string()))?;
+    let secret = SecretKey::from_bytes(&bytes).map_err(|err| invalid(err.to_string()))?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+/// Parses a hex encoded public key
+pub fn public_key(encoded: &str) -> Result<PublicKey, Error> {
+    let invalid = |err: String| Error::InvalidKey(format!("{}: {}", encoded, err));
+    let bytes = hex::decode(encoded.trim()).map_err(|err| invalid(err.to_string()))?;
+    PublicKey::from_bytes(&bytes).map_err(|err| invalid(err.to_string()))
+}
+
+/// Signs the manifest of `entries`, returning the contents of
+/// [`SIGNATURE_FILE`]
+pub fn sign(entries: &BTreeMap<String, Vec<u8>>, key: &Keypair) -> Result<Vec<u8>, Error> {
+    let signature = key.sign(manifest(entries).as_bytes());
+    Ok(serde_json::to_vec(&Signature {
+        key: hex::encode(key.public.as_bytes()),
+        signature: hex::encode(signature.to_bytes().as_ref()),
+    })?)
+}
+
+/// Checks that `entries` are signed by one of the `trusted` keys and haven't
+/// been changed since, returning the key they were signed with
+pub fn verify(
+    entries: &BTreeMap<String, Vec<u8>>,
+    trusted: &[PublicKey],
+) -> Result<PublicKey, Error> {
+    let signature: Signature =
+        serde_json::from_slice(entries.get(SIGNATURE_FILE).ok_or(Error::Unsigned)?)?;
+
+    let key = public_key(&signature.key)?;
+    if !trusted.contains(&key) {
+        return Err(Error::UntrustedKey(signature.key));
+    }
+
+    let bytes = hex::decode(&signature.signature).map_err(|_| Error::InvalidSignature)?;
+    let signature =
+        Ed25519Signature::try_from(bytes.as_slice()).map_err(|_| Error::InvalidSignature)?;
+    key.verify_strict(manifest(entries).as_bytes(), &signature)
+        .map_err(|_| Error::InvalidSignature)?;
+
+    Ok(key)
+}
+
+/// Verifies the rune archive in `bytes`, as with [`verify`]
+pub fn verify_archive(bytes: &[u8], trusted: &[PublicKey]) -> Result<PublicKey, Error> {
+    verify(&archive::read(bytes)?, trusted)
+}
@@ -1,29 +1,68 @@
+use crate::rune::error::Error;
+use schemars::JsonSchema;
+use serde::{Deserialize as _, Deserializer};
 use serde_derive::{Deserialize, Serialize};
 use serde_yaml::Value;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
+use std::fmt;
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
 #[serde(rename_all = "lowercase", untagged)]
 pub enum TemplateInteger {
     Integer(u32),
     Template(String),
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+impl TemplateInteger {
+    /// Returns the value if it's a valid port number
+    pub fn as_port(&self) -> Option<u16> {
+        match self {
+            TemplateInteger::Integer(i) if *i >= 1 && *i <= 65535 => Some(*i as u16),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for TemplateInteger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TemplateInteger::Integer(i) => write!(f, "{}", i),
+            TemplateInteger::Template(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
 #[serde(rename_all = "lowercase", untagged)]
 pub enum Image {
     Source { source: String },
     Build { build: String },
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
 pub struct Port {
-    name: String,
+    pub name: String,
     #[serde(rename = "containerPort")]
-    container_port: TemplateInteger,
+    pub container_port: TemplateInteger,
+}
+
+impl Port {
+    /// Checks that the port is a valid port number, naming the rune and
+    /// container it belongs to if it isn't
+    pub fn check(&self, rune: &str, container: &str) -> Result<(), Error> {
+        match self.container_port.as_port() {
+            Some(_) => Ok(()),
+            None => Err(Error::InvalidPort {
+                rune: rune.into(),
+                container: container.into(),
+                port: self.name.clone(),
+                value: self.container_port.to_string(),
+            }),
+        }
+    }
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema, Clone, PartialEq)]
 pub struct Template {
     pub name: String,
     #[serde(default)]
@@ -31,8 +70,61 @@ pub struct Template {
     #[serde(default)]
     pub args: Vec<String>,
     #[serde(default)]
-    pub environment: HashMap<String, String>,
+    pub environment: BTreeMap<String, String>,
     pub image: Image,
     pub ports: Vec<Port>,
+    #[serde(default, deserialize_with = "deserialize_sorted")]
+    #[schemars(with = "Option<serde_json::Value>")]
     pub include: Option<Value>,
 }
+
+/// Reads `include` with its mappings sorted by key, so templates serialize the
+/// same whichever format they were last parsed from
+fn deserialize_sorted<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Value>, D::Error> {
+    Ok(Option::<Value>::deserialize(deserializer)?
+        .as_ref()
+        .map(sorted))
+}
+
+fn sorted(value: &Value) -> Value {
+    let key = |value: &Value| match value {
+        Value::String(s) => s.clone(),
+        other => serde_yaml::to_string(other).unwrap_or_default(),
+    };
+
+    match value {
+        Value::Mapping(mapping) => {
+            let mut entries: Vec<_> = mapping.iter().collect();
+            entries.sort_by_key(|(k, _)| key(k));
+            Value::Mapping(
+                entries
+                    .into_iter()
+                    .map(|(k, v)| (k.clone(), sorted(v)))
+                    .collect(),
+            )
+        }
+        Value::Sequence(items) => Value::Sequence(items.iter().map(sorted).collect()),
+        other => other.clone(),
+    }
+}
+
+impl Template {
+    /// Checks that every port is a valid port number, as it will be once
+    /// the template is rendered
+    pub fn check_ports(&self, rune: &str) -> Result<(), Error> {
+        for port in &self.ports {
+            port.check(rune, &self.name)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the cloud-specific section of `include`, if there is one
+    pub fn include_for(&self, cloud: &str) -> Option<&Value> {
+        self.include
+            .as_ref()
+            .and_then(|include| include.get(cloud))
+            .filter(|include| !include.is_null())
+    }
+}
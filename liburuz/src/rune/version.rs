@@ -0,0 +1,219 @@
+//! Rune format versions, and conversion of older formats to the latest
+//!
+//! `metadata.yaml` declares the format its rune is written in. Loaders read
+//! `metadata.yaml` and `rune.yaml` as plain YAML first, then step them through
+//! [`MIGRATIONS`] one version at a time until they reach [`LATEST`], so the
+//! types in `rune::v1` only ever need to parse the latest format. Adding a
+//! format means adding its module, bumping [`LATEST`], and adding a migration
+//! from the previous version.
+
+use crate::rune::error::Error;
+use crate::rune::v1::render::normalize_key;
+use regex::{escape, Captures, Regex};
+use serde_yaml::Value;
+use std::fs::{read_to_string, write};
+use std::path::Path;
+
+/// The format that `rune::v1` parses
+pub const LATEST: u32 = 2;
+
+/// Rewrites every string in a rune's `rune.yaml` from one format to the next
+pub type Rewrite = Box<dyn Fn(&str) -> String>;
+
+/// Checks that `metadata` and `template` can be converted from the format
+/// they're in to the next, returning how to rewrite the template. Migrations
+/// only rewrite strings, so that [`migrate`] can apply them to the files' text
+/// and keep their comments and layout.
+pub type Migration = fn(metadata: &Value, template: &Value) -> Result<Rewrite, Error>;
+
+/// Migrations to each format, indexed by the version they migrate from. The
+/// first entry migrates from version 1.
+pub const MIGRATIONS: &[Migration] = &[secrets_apart];
+
+/// Version 2 gives templates a rune's secrets as `state.secrets.*`, apart from
+/// the rest of its config, so version 1 references to them as
+/// `state.config.*` are moved there. Related runes' secrets moved the same
+/// way, but which of their config items are secrets can't be told from this
+/// rune, so version 1 references to `state.relations.*.config.*` are rejected
+/// for their authors to sort out.
+fn secrets_apart(metadata: &Value, template: &Value) -> Result<Rewrite, Error> {
+    let relation_config = Regex::new(r"state\.relations\.[\w-]+\.config\.[\w-]+").unwrap();
+    let mut references = vec![];
+    visit_strings(template, &mut |s| {
+        for found in relation_config.find_iter(s) {
+            references.push(found.as_str().to_string());
+        }
+    });
+    if !references.is_empty() {
+        references.sort();
+        references.dedup();
+        return Err(Error::RelationConfigReferences(references));
+    }
+
+    let mut secrets = vec![];
+    if let Some(Value::Mapping(config)) = metadata.get("config") {
+        for (key, item) in config {
+            match (key, item.get("type")) {
+                (Value::String(key), Some(Value::String(kind))) if kind == "secret" => {
+                    secrets.push(key.clone());
+                    secrets.push(normalize_key(key));
+                }
+                _ => {}
+            }
+        }
+    }
+    if secrets.is_empty() {
+        return Ok(Box::new(str::to_string));
+    }
+
+    // Longest first, so that `password` doesn't match part of `password-hash`
+    secrets.sort_by_key(|key| std::cmp::Reverse(key.len()));
+    let keys: Vec<_> = secrets.iter().map(|key| escape(key)).collect();
+    let pattern = Regex::new(&format!(r"state\.config\.({})([^\w-]|$)", keys.join("|"))).unwrap();
+    Ok(Box::new(move |s| {
+        pattern
+            .replace_all(s, |captures: &Captures| {
+                format!(
+                    "state.secrets.{}{}",
+                    normalize_key(&captures[1]),
+                    &captures[2]
+                )
+            })
+            .into_owned()
+    }))
+}
+
+/// Calls `visit` with every string in `value`
+fn visit_strings(value: &Value, visit: &mut dyn FnMut(&str)) {
+    match value {
+        Value::String(s) => visit(s),
+        Value::Sequence(items) => items.iter().for_each(|item| visit_strings(item, visit)),
+        Value::Mapping(mapping) => mapping
+            .iter()
+            .for_each(|(_, item)| visit_strings(item, visit)),
+        _ => {}
+    }
+}
+
+/// Replaces every string in `value` with `rewrite` of it
+fn rewrite_strings(value: &mut Value, rewrite: &dyn Fn(&str) -> String) {
+    match value {
+        Value::String(s) => *s = rewrite(s),
+        Value::Sequence(items) => items
+            .iter_mut()
+            .for_each(|item| rewrite_strings(item, rewrite)),
+        Value::Mapping(mapping) => mapping
+            .iter_mut()
+            .for_each(|(_, item)| rewrite_strings(item, rewrite)),
+        _ => {}
+    }
+}
+
+/// Returns the format version declared in `metadata`
+pub fn version(metadata: &Value) -> Result<u32, Error> {
+    let declared = match metadata.get("version") {
+        Some(Value::Number(n)) => n.to_string(),
+        Some(Value::String(s)) => s.clone(),
+        Some(other) => serde_yaml::to_string(other)?,
+        None => return Err(Error::UnsupportedVersion("missing".into())),
+    };
+
+    match declared.trim().parse() {
+        Ok(version) if (1..=LATEST).contains(&version) => Ok(version),
+        _ => Err(Error::UnsupportedVersion(declared)),
+    }
+}
+
+/// Converts `metadata` and `template` to the latest format, returning the
+/// version they were in
+pub fn upgrade(metadata: &mut Value, template: &mut Value) -> Result<u32, Error> {
+    let original = version(metadata)?;
+
+    for migration in MIGRATIONS.iter().skip(original as usize - 1) {
+        let rewrite = migration(metadata, template)?;
+        rewrite_strings(template, &rewrite);
+    }
+    if let Value::Mapping(mapping) = metadata {
+        mapping.insert("version".into(), Value::String(LATEST.to_string()));
+    }
+
+    Ok(original)
+}
+
+/// What [`migrate`] did to a rune directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct Migrated {
+    /// The format version the rune was in
+    pub version: u32,
+    /// Files that couldn't be rewritten in place, and so were re-serialized
+    /// from the migrated YAML, losing their comments and layout
+    pub reformatted: Vec<String>,
+}
+
+/// Rewrites the rune directory at `dir` in the latest format. Migrations are
+/// applied to the files' text and `version:` is replaced where it's declared,
+/// so comments and layout are kept. A file whose rewritten text doesn't parse
+/// to the migrated rune, such as one with a `version` in a flow mapping, is
+/// re-serialized instead and listed in [`Migrated::reformatted`]. Files
+/// already in the latest format are left untouched.
+pub fn migrate(dir: &Path) -> Result<Migrated, Error> {
+    let metadata_text = read_to_string(dir.join("metadata.yaml"))?;
+    let template_text = read_to_string(dir.join("rune.yaml"))?;
+    let mut metadata: Value = serde_yaml::from_str(&metadata_text)?;
+    let mut template: Value = serde_yaml::from_str(&template_text)?;
+    let original = version(&metadata)?;
+
+    let mut migrated = Migrated {
+        version: original,
+        reformatted: vec![],
+    };
+    if original == LATEST {
+        return Ok(migrated);
+    }
+
+    let mut rewritten = template_text;
+    for migration in MIGRATIONS.iter().skip(original as usize - 1) {
+        let rewrite = migration(&metadata, &template)?;
+        rewritten = rewrite(&rewritten);
+        rewrite_strings(&mut template, &rewrite);
+    }
+    // Inserting moves `version` to the end of the mapping, so it's inserted
+    // into the rewritten text's metadata too before comparing them
+    let bump = |metadata: &mut Value| {
+        if let Value::Mapping(mapping) = metadata {
+            mapping.insert("version".into(), Value::Number(LATEST.into()));
+        }
+    };
+    bump(&mut metadata);
+
+    let declared = Regex::new(r#"(?m)^(version:[ \t]*)(?:\d+|"\d+"|'\d+')"#).unwrap();
+    let versioned = declared
+        .replacen(&metadata_text, 1, |captures: &Captures| {
+            format!("{}{}", &captures[1], LATEST)
+        })
+        .into_owned();
+
+    for (file, text, value) in [
+        ("metadata.yaml", versioned, metadata),
+        ("rune.yaml", rewritten, template),
+    ] {
+        let mut parsed = serde_yaml::from_str::<Value>(&text).ok();
+        if file == "metadata.yaml" {
+            parsed = parsed
+                .filter(|parsed| version(parsed).ok() == Some(LATEST))
+                .map(|mut parsed| {
+                    bump(&mut parsed);
+                    parsed
+                });
+        }
+        let text = if parsed.as_ref() == Some(&value) {
+            text
+        } else {
+            migrated.reformatted.push(file.into());
+            serde_yaml::to_string(&value)?
+        };
+        write(dir.join(file), text)?;
+    }
+
+    Ok(migrated)
+}
@@ -1,8 +1,10 @@
 use crate::api::v1;
-use crate::rune::v1::rune::Rune;
+use crate::rune::error::Error as RuneError;
 use crate::server::controller::Controller;
+use crate::server::error::Error;
 use crate::server::model::Action;
 use uuid::Uuid;
+use warp::http::StatusCode;
 use warp::Filter;
 
 async fn list_models(_controller: Controller) -> Result<impl warp::Reply, warp::Rejection> {
@@ -14,7 +16,10 @@ async fn get_model(
     controller: Controller,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     match controller.get_model(&Uuid::parse_str(&id).unwrap()) {
-        Ok(model) => Ok(warp::reply::json::<v1::Model>(&model.into())),
+        Ok(model) => Ok(warp::reply::with_status(
+            warp::reply::json::<v1::Model>(&model.into()),
+            StatusCode::OK,
+        )),
         Err(_) => Err(warp::reject::not_found()),
     }
 }
@@ -23,8 +28,23 @@ async fn create_model(
     mut controller: Controller,
     args: v1::ModelCreate,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    match controller.create_model(args.cloud, &args.name) {
-        Ok(model) => Ok(warp::reply::json::<v1::Model>(&model.into())),
+    match controller.create_model(&args.cloud, &args.name) {
+        Ok(model) => Ok(warp::reply::with_status(
+            warp::reply::json::<v1::Model>(&model.into()),
+            StatusCode::OK,
+        )),
+        Err(Error::ModelAlreadyExists(name)) => Ok(warp::reply::with_status(
+            warp::reply::json(&v1::ErrorMessage {
+                message: format!("A model named {} already exists", name),
+            }),
+            StatusCode::BAD_REQUEST,
+        )),
+        Err(err @ Error::InvalidName(_)) => Ok(warp::reply::with_status(
+            warp::reply::json(&v1::ErrorMessage {
+                message: err.to_string(),
+            }),
+            StatusCode::BAD_REQUEST,
+        )),
         Err(_) => Err(warp::reject::not_found()),
     }
 }
@@ -53,14 +73,78 @@ async fn delete_model(
     }
 }
 
+/// Why a request that stores secrets failed, when the controller has no master
+/// key to encrypt them with
+fn master_key_missing() -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&v1::ErrorMessage {
+            message: "Secrets can't be stored without a master key. Set master_key_path in \
+                      the controller's config, or the URUZ_MASTER_KEY environment variable."
+                .into(),
+        }),
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+}
+
+/// Replies to a rune archive or reference that couldn't be opened: 403 if its
+/// signature isn't accepted, 400 if it's invalid, and 404 otherwise, such as
+/// for a reference to a rune that isn't in the repository
+fn rune_rejected(
+    err: Error,
+) -> Result<warp::reply::WithStatus<warp::reply::Json>, warp::Rejection> {
+    let status = match &err {
+        Error::RuneError(RuneError::Unsigned)
+        | Error::RuneError(RuneError::UntrustedKey(_))
+        | Error::RuneError(RuneError::InvalidSignature) => StatusCode::FORBIDDEN,
+        Error::RuneError(_) | Error::InvalidRuneReference(_) | Error::InvalidName(_) => {
+            StatusCode::BAD_REQUEST
+        }
+        _ => return Err(warp::reject::not_found()),
+    };
+    Ok(warp::reply::with_status(
+        warp::reply::json(&v1::ErrorMessage {
+            message: err.to_string(),
+        }),
+        status,
+    ))
+}
+
 async fn add_rune(
     id: String,
     controller: Controller,
     args: v1::RuneAdd,
 ) -> Result<impl warp::Reply, warp::Rejection> {
-    let rune = Rune::unzip(&args.rune).unwrap();
-    match controller.add_rune(&Uuid::parse_str(&id).unwrap(), args.name, rune) {
-        Ok(id) => Ok(warp::reply::json(&id)),
+    let rune = match args.source {
+        v1::RuneSource::Rune(archive) => controller.open_rune(&archive),
+        v1::RuneSource::Reference(reference) => controller.resolve_rune(&reference),
+    };
+    let rune = match rune {
+        Ok(rune) => rune,
+        Err(err) => return rune_rejected(err),
+    };
+    match controller.add_rune(
+        &Uuid::parse_str(&id).unwrap(),
+        args.name,
+        rune,
+        args.secrets,
+    ) {
+        Ok(id) => Ok(warp::reply::with_status(
+            warp::reply::json(&id),
+            StatusCode::OK,
+        )),
+        Err(Error::RuneError(err)) => Ok(warp::reply::with_status(
+            warp::reply::json(&v1::ErrorMessage {
+                message: err.to_string(),
+            }),
+            StatusCode::BAD_REQUEST,
+        )),
+        Err(err @ Error::InvalidName(_)) => Ok(warp::reply::with_status(
+            warp::reply::json(&v1::ErrorMessage {
+                message: err.to_string(),
+            }),
+            StatusCode::BAD_REQUEST,
+        )),
+        Err(Error::MasterKeyMissing) => Ok(master_key_missing()),
         Err(_) => Err(warp::reject::not_found()),
     }
 }
@@ -72,16 +156,172 @@ async fn configure_rune(
     args: v1::RuneConfigure,
 ) -> Result<impl warp::Reply, warp::Rejection> {
     let model_id = Uuid::parse_str(&model_id).unwrap();
-    let result = controller.update_model(
-        &model_id,
-        Action::ConfigureRune {
-            name: rune_name,
-            attribute: args.attribute,
-            value: args.value,
-        },
-    );
-    match result {
-        Ok(id) => Ok(warp::reply::json(&id)),
+    match controller.configure_rune(&model_id, &rune_name, args.config, &args.reset) {
+        Ok(id) => Ok(warp::reply::with_status(
+            warp::reply::json(&id),
+            StatusCode::OK,
+        )),
+        Err(Error::RuneError(err)) => Ok(warp::reply::with_status(
+            warp::reply::json(&v1::ErrorMessage {
+                message: err.to_string(),
+            }),
+            StatusCode::BAD_REQUEST,
+        )),
+        Err(Error::MasterKeyMissing) => Ok(master_key_missing()),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn get_rune_secrets(
+    model_id: String,
+    rune_name: String,
+    authorization: Option<String>,
+    controller: Controller,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let model_id = Uuid::parse_str(&model_id).unwrap();
+    let refuse = |message: &str, status| {
+        let message = v1::ErrorMessage {
+            message: message.into(),
+        };
+        Ok(warp::reply::with_status(
+            warp::reply::json(&message),
+            status,
+        ))
+    };
+    match controller.rune_secrets(&model_id, &rune_name, authorization.as_deref()) {
+        Ok(secrets) => Ok(warp::reply::with_status(
+            warp::reply::json(&secrets),
+            StatusCode::OK,
+        )),
+        Err(Error::SecretsDisabled) => refuse(
+            "Reading secrets isn't enabled on this controller",
+            StatusCode::FORBIDDEN,
+        ),
+        Err(Error::Unauthorized) => refuse(
+            "Reading secrets requires the controller's secrets token",
+            StatusCode::UNAUTHORIZED,
+        ),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn list_runes(controller: Controller) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.list_runes(None) {
+        Ok(runes) => Ok(warp::reply::json(&runes)),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn list_rune_revisions(
+    name: String,
+    controller: Controller,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.list_runes(Some(&name)) {
+        Ok(runes) => Ok(warp::reply::json(&runes)),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn upload_rune(
+    controller: Controller,
+    args: v1::RuneUpload,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.upload_rune(&args.name, &args.rune, args.tag.as_deref()) {
+        Ok(rune) => Ok(warp::reply::with_status(
+            warp::reply::json(&rune),
+            StatusCode::OK,
+        )),
+        Err(err) => rune_rejected(err),
+    }
+}
+
+async fn get_rune(
+    name: String,
+    revision: u64,
+    controller: Controller,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.get_rune(&name, revision) {
+        Ok(rune) => Ok(warp::reply::json(&rune)),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn delete_rune(
+    name: String,
+    revision: u64,
+    controller: Controller,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.delete_rune(&name, revision) {
+        Ok(()) => Ok(warp::reply::json(&())),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn configure_dummy(
+    id: String,
+    controller: Controller,
+    config: v1::DummyConfig,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.configure_dummy(&Uuid::parse_str(&id).unwrap(), config) {
+        Ok(()) => Ok(warp::reply::json(&())),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn get_dummy_world(
+    id: String,
+    controller: Controller,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.get_dummy_world(&Uuid::parse_str(&id).unwrap()) {
+        Ok(world) => Ok(warp::reply::json(&world)),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn list_clouds(controller: Controller) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.list_clouds() {
+        Ok(clouds) => Ok(warp::reply::json(&clouds)),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn get_cloud(
+    name: String,
+    controller: Controller,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.get_cloud(&name) {
+        Ok(cloud) => Ok(warp::reply::json(&cloud)),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn create_cloud(
+    controller: Controller,
+    args: v1::CloudCreate,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.create_cloud(&args.name, &args.credentials) {
+        Ok(cloud) => Ok(warp::reply::json(&cloud)),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn update_cloud(
+    name: String,
+    controller: Controller,
+    args: v1::CloudUpdate,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.update_cloud(&name, &args.credentials) {
+        Ok(cloud) => Ok(warp::reply::json(&cloud)),
+        Err(_) => Err(warp::reject::not_found()),
+    }
+}
+
+async fn delete_cloud(
+    name: String,
+    controller: Controller,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    match controller.delete_cloud(&name) {
+        Ok(()) => Ok(warp::reply::json(&())),
         Err(_) => Err(warp::reject::not_found()),
     }
 }
@@ -91,7 +331,9 @@ pub fn build(
 ) -> impl Filter<Extract = impl warp::Reply, Error = warp::Rejection> + Clone {
     let controller = warp::any().map(move || controller.clone());
 
-    warp::path!("api" / "v1" / "models")
+    // Boxed in groups, as one long chain of filters overflows the stack in
+    // debug builds
+    let models = warp::path!("api" / "v1" / "models")
         .and(warp::get())
         .and(controller.clone())
         .and_then(list_models)
@@ -125,4 +367,74 @@ pub fn build(
                 .and(warp::body::json())
                 .and_then(configure_rune),
         )
+        .or(
+            warp::path!("api" / "v1" / "models" / String / "runes" / String / "secrets")
+                .and(warp::get())
+                .and(warp::header::optional("authorization"))
+                .and(controller.clone())
+                .and_then(get_rune_secrets),
+        )
+        .or(
+            warp::path!("api" / "v1" / "models" / String / "dummy" / "config")
+                .and(warp::post())
+                .and(controller.clone())
+                .and(warp::body::json())
+                .and_then(configure_dummy),
+        )
+        .or(
+            warp::path!("api" / "v1" / "models" / String / "dummy" / "world")
+                .and(warp::get())
+                .and(controller.clone())
+                .and_then(get_dummy_world),
+        )
+        .boxed();
+
+    let runes = warp::path!("api" / "v1" / "runes")
+        .and(warp::get())
+        .and(controller.clone())
+        .and_then(list_runes)
+        .or(warp::path!("api" / "v1" / "runes")
+            .and(warp::post())
+            .and(controller.clone())
+            .and(warp::body::json())
+            .and_then(upload_rune))
+        .or(warp::path!("api" / "v1" / "runes" / String)
+            .and(warp::get())
+            .and(controller.clone())
+            .and_then(list_rune_revisions))
+        .or(warp::path!("api" / "v1" / "runes" / String / u64)
+            .and(warp::get())
+            .and(controller.clone())
+            .and_then(get_rune))
+        .or(warp::path!("api" / "v1" / "runes" / String / u64)
+            .and(warp::delete())
+            .and(controller.clone())
+            .and_then(delete_rune))
+        .boxed();
+
+    let clouds = warp::path!("api" / "v1" / "clouds")
+        .and(warp::get())
+        .and(controller.clone())
+        .and_then(list_clouds)
+        .or(warp::path!("api" / "v1" / "clouds" / String)
+            .and(warp::get())
+            .and(controller.clone())
+            .and_then(get_cloud))
+        .or(warp::path!("api" / "v1" / "clouds")
+            .and(warp::post())
+            .and(controller.clone())
+            .and(warp::body::json())
+            .and_then(create_cloud))
+        .or(warp::path!("api" / "v1" / "clouds" / String)
+            .and(warp::put())
+            .and(controller.clone())
+            .and(warp::body::json())
+            .and_then(update_cloud))
+        .or(warp::path!("api" / "v1" / "clouds" / String)
+            .and(warp::delete())
+            .and(controller.clone())
+            .and_then(delete_cloud))
+        .boxed();
+
+    models.or(runes).or(clouds)
 }
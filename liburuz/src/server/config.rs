@@ -1,7 +1,162 @@
+use std::collections::HashMap;
+
+#[derive(Clone)]
+pub struct LocalConfig {
+    pub working_dir: String,
+    pub binaries: HashMap<String, String>,
+}
+
+impl Default for LocalConfig {
+    fn default() -> Self {
+        Self {
+            working_dir: "uruz-local".into(),
+            binaries: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct KubernetesConfig {
+    pub kubeconfig: Option<String>,
+    pub context: Option<String>,
+}
+
+#[derive(Clone)]
+pub struct AwsConfig {
+    pub endpoint: Option<String>,
+    pub region: String,
+    pub access_key_id: Option<String>,
+    pub secret_access_key: Option<String>,
+    pub fargate: FargateConfig,
+}
+
+impl Default for AwsConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            region: "us-east-1".into(),
+            access_key_id: None,
+            secret_access_key: None,
+            fargate: Default::default(),
+        }
+    }
+}
+
+/// How each rune's ECS task is sized and networked, running on Fargate
+#[derive(Clone, Debug)]
+pub struct FargateConfig {
+    /// CPU units for each task, such as `256` for a quarter of a vCPU
+    pub cpu: String,
+    /// Memory for each task in MiB, which must suit `cpu`
+    pub memory: String,
+    /// Subnets that tasks are placed in, of which Fargate needs at least one
+    pub subnets: Vec<String>,
+    pub security_groups: Vec<String>,
+    pub assign_public_ip: bool,
+}
+
+impl Default for FargateConfig {
+    fn default() -> Self {
+        Self {
+            cpu: "256".into(),
+            memory: "512".into(),
+            subnets: vec![],
+            security_groups: vec![],
+            assign_public_ip: true,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct GceConfig {
+    pub endpoint: Option<String>,
+    pub project: String,
+    pub zone: String,
+    pub token: Option<String>,
+}
+
+impl Default for GceConfig {
+    fn default() -> Self {
+        Self {
+            endpoint: None,
+            project: "uruz".into(),
+            zone: "us-central1-a".into(),
+            token: None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct ImagesConfig {
+    /// OCI image layout directory that images are written to by default
+    pub oci_layout: String,
+    /// Registry endpoint to push images to instead, such as
+    /// `http://localhost:5000`
+    pub registry: Option<String>,
+    /// Command to build contexts that have a Dockerfile with, such as
+    /// `docker`. The images it builds are pushed to `registry` if one is set,
+    /// and otherwise only tagged wherever they were built.
+    pub docker: Option<String>,
+}
+
+impl Default for ImagesConfig {
+    fn default() -> Self {
+        Self {
+            oci_layout: "uruz-images".into(),
+            registry: None,
+            docker: None,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct SignaturesConfig {
+    /// Hex encoded ed25519 public keys that runes may be signed with
+    pub trusted_keys: Vec<String>,
+    /// Whether to reject runes that aren't signed by a trusted key
+    pub required: bool,
+}
+
+/// How requests that fail are retried before they're recorded as failed
+#[derive(Clone)]
+pub struct RetriesConfig {
+    /// Attempts at each request, including the first
+    pub attempts: u32,
+    /// Delay before the first retry, doubled before each one after it
+    pub backoff_ms: u64,
+    /// Longest delay between attempts
+    pub max_backoff_ms: u64,
+}
+
+impl Default for RetriesConfig {
+    fn default() -> Self {
+        Self {
+            attempts: 5,
+            backoff_ms: 1000,
+            max_backoff_ms: 30_000,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct Config {
     pub database_path: String,
     pub api_host: [u8; 4],
     pub api_port: u16,
+    pub master_key_path: Option<String>,
+    /// Keys that were rotated away from, as described in
+    /// [`Cipher`](crate::server::crypto::Cipher)
+    pub previous_master_key_paths: Vec<String>,
+    /// Bearer token required to read secret values, which can't be read
+    /// through the API at all without one
+    pub secrets_token: Option<String>,
+    pub aws: AwsConfig,
+    pub gce: GceConfig,
+    pub images: ImagesConfig,
+    pub kubernetes: KubernetesConfig,
+    pub local: LocalConfig,
+    pub retries: RetriesConfig,
+    pub signatures: SignaturesConfig,
 }
 
 impl Default for Config {
@@ -10,6 +165,16 @@ impl Default for Config {
             database_path: "uruz.sled".into(),
             api_host: [0, 0, 0, 0],
             api_port: 8000,
+            master_key_path: None,
+            previous_master_key_paths: Vec::new(),
+            secrets_token: None,
+            aws: Default::default(),
+            gce: Default::default(),
+            images: Default::default(),
+            kubernetes: Default::default(),
+            local: Default::default(),
+            retries: Default::default(),
+            signatures: Default::default(),
         }
     }
 }
@@ -1,13 +1,24 @@
-use crate::clouds::Cloud;
+use crate::api::v1::{
+    CloudCredentials, DummyConfig, DummyWorld, RegisteredCloud, RepositoryRune, RuneArchive,
+};
+use crate::clouds::{Cloud, Providers};
+use crate::rune::v1::archive;
 use crate::rune::v1::rune::Rune;
+use crate::rune::v1::signature;
+use crate::server::config::Config;
+use crate::server::crypto::Cipher;
 use crate::server::error::Error;
-use crate::server::model::{Action, Active, Completed, Model, ModelStatus, Queued};
+use crate::server::model::{check_name, Action, Active, Completed, Model, ModelStatus, Queued};
+use crate::server::registry::{Registry, BUILTIN};
+use crate::server::repository::Repository;
+use crate::server::store::{self, RuneStore};
 use async_std::task;
-use serde_json::{from_slice, to_vec};
-use sled::transaction::abort;
-use std::collections::{HashMap, VecDeque};
+use serde_json::to_vec;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use sled::transaction::{abort, Transactional};
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::future::Future;
-use std::path::Path;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll};
@@ -19,6 +30,15 @@ type ModelState = (Vec<Completed>, Option<Active>, VecDeque<Queued>);
 #[derive(Clone)]
 pub struct Controller {
     database: sled::Db,
+    config: Arc<Config>,
+    providers: Providers,
+    registry: Registry,
+    runes: RuneStore,
+    repository: Repository,
+    /// The ID of the model each name was last given to
+    names: sled::Tree,
+    /// Providers built for registered clouds, dropped when a registration changes
+    registered: Arc<Mutex<HashMap<String, Providers>>>,
     futures: Arc<
         Mutex<
             HashMap<Uuid, Pin<Box<dyn Future<Output = Result<Option<Completed>, Error>> + Send>>>,
@@ -27,46 +47,251 @@ pub struct Controller {
 }
 
 impl Controller {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
-        Ok(Self {
-            database: sled::open(path)?,
+    pub fn new(config: Config, providers: Providers) -> Result<Self, Error> {
+        let database = sled::open(&config.database_path)?;
+        let cipher = Cipher::from_config(&config)?;
+        let registry = Registry::new(&database, cipher.clone())?;
+        let runes = RuneStore::new(&database, cipher)?;
+        let repository = Repository::new(&database)?;
+        let names = database.open_tree("model-names")?;
+        let controller = Self {
+            database,
+            config: Arc::new(config),
+            providers,
+            registry,
+            runes,
+            repository,
+            names,
+            registered: Arc::new(Mutex::new(HashMap::new())),
             futures: Arc::new(Mutex::new(HashMap::new())),
+        };
+
+        if controller.runes.cipher().rotating() {
+            controller.rotate_master_key()?;
+        }
+
+        // Names given to models that were never saved, such as when the
+        // controller stopped partway through creating one, are free again
+        let ids = controller.model_ids();
+        for item in controller.names.iter() {
+            let (name, id) = item?;
+            if !ids.iter().any(|saved| saved.as_bytes() == &id[..]) {
+                controller.names.remove(name)?;
+            }
+        }
+
+        // Fails now, rather than on every poll, if secrets stored by a model
+        // can't be decrypted, such as when the master key is missing. Each
+        // model's requests wait until what it had deployed is restored.
+        for id in ids {
+            let model = controller.get_model(&id)?;
+            if model.get_status() == ModelStatus::Destroyed {
+                continue;
+            }
+            // Models saved before names were indexed
+            controller
+                .names
+                .compare_and_swap(&model.name, None as Option<&[u8]>, Some(id.as_bytes()))?
+                .ok();
+            let restore = controller.restore(model)?;
+            controller
+                .futures
+                .lock()
+                .unwrap()
+                .insert(id, Box::pin(restore));
+        }
+        Ok(controller)
+    }
+
+    /// Restarts the runes of a model on a local cloud, whose processes don't
+    /// outlive the controller. Other clouds keep running without it.
+    fn restore(
+        &self,
+        model: Model,
+    ) -> Result<impl Future<Output = Result<Option<Completed>, Error>> + Send, Error> {
+        let (cloud, providers) = self.providers_for(&model.cloud)?;
+
+        Ok(async move {
+            if cloud == Cloud::Local {
+                let runes = model.rendered_runes()?;
+                providers.await?.local.restore(&model.name, &runes)?;
+            }
+            Ok(None)
         })
     }
 
-    pub fn create_model(&mut self, cloud: Cloud, name: &str) -> Result<Model, Error> {
-        for tree_name in self.database.tree_names() {
-            if self.database.open_tree(tree_name)?.get("name")? == Some(name.into()) {
-                return Err(Error::ModelAlreadyExists(name.into()));
+    /// Re-encrypts everything stored encrypted with the current master key,
+    /// including anything still encrypted with a previous one
+    fn rotate_master_key(&self) -> Result<(), Error> {
+        self.registry.rotate()?;
+        let cipher = self.runes.cipher();
+        for id in self.model_ids() {
+            let tree = self.database.open_tree(id.as_bytes())?;
+            (&tree, self.runes.tree()).transaction(|(t, runes)| {
+                for key in &["backlog", "active", "history"] {
+                    if let Some(bytes) = t.get(key)? {
+                        let requests: Value = store::from_slice(runes, cipher, &bytes)?;
+                        t.insert(*key, store::to_vec(runes, cipher, &requests)?)?;
+                    }
+                }
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    /// IDs of all models, skipping trees that don't belong to one
+    fn model_ids(&self) -> Vec<Uuid> {
+        self.database
+            .tree_names()
+            .iter()
+            .filter_map(|name| Uuid::from_slice(name).ok())
+            .collect()
+    }
+
+    /// Creates a model, as long as no other model that hasn't been destroyed
+    /// has the same name
+    pub fn create_model(&mut self, cloud: &str, name: &str) -> Result<Model, Error> {
+        check_name(name)?;
+        self.registry.resolve(cloud)?;
+
+        let model = Model::with_name(name.to_string(), cloud.to_string());
+        // The name is claimed before the model is saved, so that of two
+        // requests for the same name, only one can get it
+        loop {
+            let claimed = self.names.get(name)?;
+            if let Some(id) = &claimed {
+                // A model that's claimed the name but not been saved yet
+                // counts as taking it
+                let saved = self
+                    .model_ids()
+                    .into_iter()
+                    .find(|s| s.as_bytes() == &id[..]);
+                let destroyed = match saved {
+                    Some(id) => self.get_model(&id)?.get_status() == ModelStatus::Destroyed,
+                    None => false,
+                };
+                if !destroyed {
+                    return Err(Error::ModelAlreadyExists(name.into()));
+                }
+            }
+            let swapped = self
+                .names
+                .compare_and_swap(name, claimed, Some(model.id.as_bytes()))?;
+            if swapped.is_ok() {
+                break;
             }
         }
 
-        let model = Model::with_name(name.to_string(), cloud);
         self.save_model(&model)?;
         self.get_model(&model.id)
     }
 
     fn save_model(&self, model: &Model) -> Result<(), Error> {
         let tree = self.database.open_tree(model.id.as_bytes())?;
-        tree.transaction(|t| {
-            if t.get("id")?.is_some() {
-                return abort(Error::ModelAlreadyExists(model.id.to_string()));
-            }
-            t.insert("id", model.id.as_bytes())?;
-            t.insert("name", to_vec(&model.name).unwrap())?;
-            t.insert("cloud", to_vec(&model.cloud).unwrap())?;
-            t.insert("backlog", to_vec(&model.backlog).unwrap())?;
-            t.insert("active", to_vec(&model.active).unwrap())?;
-            t.insert("history", to_vec(&model.history).unwrap())?;
-            Ok(())
-        })
-        .unwrap();
+        let cipher = self.runes.cipher();
+        (&tree, self.runes.tree())
+            .transaction(|(t, runes)| {
+                if t.get("id")?.is_some() {
+                    return abort(Error::ModelAlreadyExists(model.id.to_string()));
+                }
+                t.insert("id", model.id.as_bytes())?;
+                t.insert("name", to_vec(&model.name).unwrap())?;
+                t.insert("cloud", to_vec(&model.cloud).unwrap())?;
+                t.insert("backlog", store::to_vec(runes, cipher, &model.backlog)?)?;
+                t.insert("active", store::to_vec(runes, cipher, &model.active)?)?;
+                t.insert("history", store::to_vec(runes, cipher, &model.history)?)?;
+                Ok(())
+            })
+            .unwrap();
         Ok(())
     }
 
-    fn get_cloud(&self, model_id: &Uuid) -> Result<Cloud, Error> {
-        let tree = self.database.open_tree(model_id.as_bytes())?;
-        Ok(from_slice(&tree.get("cloud")?.unwrap())?)
+    /// Looks up the provider type of a model's cloud, along with a future
+    /// resolving to the providers to handle its requests with
+    fn providers_for(
+        &self,
+        name: &str,
+    ) -> Result<(Cloud, impl Future<Output = Result<Providers, Error>> + Send), Error> {
+        let (cloud, credentials) = self.registry.resolve(name)?;
+        let base = self.providers.clone();
+        let config = self.config.clone();
+        let registered = self.registered.clone();
+        let name = name.to_string();
+
+        Ok((cloud, async move {
+            let credentials = match credentials {
+                Some(credentials) => credentials,
+                None => return Ok(base),
+            };
+            let cached = registered.lock().unwrap().get(&name).cloned();
+            if let Some(providers) = cached {
+                return Ok(providers);
+            }
+            let providers = base.with_credentials(&config, &credentials).await?;
+            registered.lock().unwrap().insert(name, providers.clone());
+            Ok(providers)
+        }))
+    }
+
+    pub fn list_clouds(&self) -> Result<Vec<RegisteredCloud>, Error> {
+        let mut clouds: Vec<_> = BUILTIN
+            .iter()
+            .map(|cloud| RegisteredCloud {
+                name: cloud.to_string(),
+                cloud: cloud.to_string(),
+                builtin: true,
+            })
+            .collect();
+        for (name, credentials) in self.registry.list()? {
+            clouds.push(RegisteredCloud {
+                name,
+                cloud: credentials.cloud().to_string(),
+                builtin: false,
+            });
+        }
+        Ok(clouds)
+    }
+
+    pub fn get_cloud(&self, name: &str) -> Result<RegisteredCloud, Error> {
+        let (cloud, credentials) = self.registry.resolve(name)?;
+        Ok(RegisteredCloud {
+            name: name.into(),
+            cloud: cloud.to_string(),
+            builtin: credentials.is_none(),
+        })
+    }
+
+    pub fn create_cloud(
+        &self,
+        name: &str,
+        credentials: &CloudCredentials,
+    ) -> Result<RegisteredCloud, Error> {
+        self.registry.create(name, credentials)?;
+        self.get_cloud(name)
+    }
+
+    pub fn update_cloud(
+        &self,
+        name: &str,
+        credentials: &CloudCredentials,
+    ) -> Result<RegisteredCloud, Error> {
+        self.registry.update(name, credentials)?;
+        self.registered.lock().unwrap().remove(name);
+        self.get_cloud(name)
+    }
+
+    /// Removes a registered cloud, as long as no live model still uses it
+    pub fn delete_cloud(&self, name: &str) -> Result<(), Error> {
+        for id in self.model_ids() {
+            let model = self.get_model(&id)?;
+            if model.cloud == name && model.get_status() != ModelStatus::Destroyed {
+                return Err(Error::CloudInUse(name.into()));
+            }
+        }
+        self.registry.delete(name)?;
+        self.registered.lock().unwrap().remove(name);
+        Ok(())
     }
 
     fn transaction<F>(&self, model_id: &Uuid, func: F) -> Result<ModelState, Error>
@@ -74,18 +299,22 @@ impl Controller {
         F: Fn(ModelState) -> Result<ModelState, Error>,
     {
         let tree = self.database.open_tree(model_id.as_bytes())?;
-        Ok(tree.transaction(|t| {
-            let history: Vec<Completed> = from_slice(&t.get("history")?.unwrap()).unwrap();
-            let backlog = from_slice(&t.get("backlog")?.unwrap()).unwrap();
-            let active = from_slice(&t.get("active")?.unwrap()).unwrap();
-            let model_destroyed = history.iter().any(|h| h.action == Action::DestroyModel);
+        let cipher = self.runes.cipher();
+        Ok((&tree, self.runes.tree()).transaction(|(t, runes)| {
+            let history: Vec<Completed> =
+                store::from_slice(runes, cipher, &t.get("history")?.unwrap())?;
+            let backlog = store::from_slice(runes, cipher, &t.get("backlog")?.unwrap())?;
+            let active = store::from_slice(runes, cipher, &t.get("active")?.unwrap())?;
+            let model_destroyed = history
+                .iter()
+                .any(|h| h.succeeded() && h.action == Action::DestroyModel);
             if model_destroyed {
                 return abort(Error::ModelAlreadyDeleted("".into()));
             }
             let (history, active, backlog) = func((history, active, backlog)).unwrap();
-            t.insert("history", to_vec(&history).unwrap())?;
-            t.insert("active", to_vec(&active).unwrap())?;
-            t.insert("backlog", to_vec(&backlog).unwrap())?;
+            t.insert("history", store::to_vec(runes, cipher, &history)?)?;
+            t.insert("active", store::to_vec(runes, cipher, &active)?)?;
+            t.insert("backlog", store::to_vec(runes, cipher, &backlog)?)?;
             Ok((history, active, backlog))
         })?)
     }
@@ -99,6 +328,9 @@ impl Controller {
         Ok(queued.id)
     }
 
+    /// Records `completed` in the model's history, then starts on the next
+    /// request. The active request is resumed instead if nothing completed,
+    /// such as when the controller restarted partway through it.
     fn get_next_task(
         &self,
         model_id: &Uuid,
@@ -107,6 +339,7 @@ impl Controller {
         let (_, active, _) = self.transaction(model_id, |(mut history, active, mut backlog)| {
             match (&active, &completed) {
                 (Some(a), Some(c)) => assert_eq!(a.id, c.id),
+                (Some(_), None) => return Ok((history, active, backlog)),
                 _ => {}
             }
             if let Some(c) = &completed {
@@ -126,20 +359,60 @@ impl Controller {
             }
         })?;
 
-        let cloud = self.get_cloud(model_id)?;
+        // The task is kept among the controller's own, so it mustn't hold on
+        // to them in turn, or the controller would never be dropped
+        let controller = Self {
+            futures: Default::default(),
+            ..self.clone()
+        };
+        let model = self.get_model(model_id)?;
         Ok(Box::new(async move {
-            if let Some(a) = active {
-                Ok(Some(cloud.handle_request(a).await?))
-            } else {
-                task::sleep(Duration::from_secs(1)).await;
-                Ok(None)
+            match active {
+                Some(a) => Ok(Some(controller.handle_with_retries(model, a).await)),
+                None => {
+                    task::sleep(Duration::from_secs(1)).await;
+                    Ok(None)
+                }
             }
         }))
     }
 
+    /// Handles `active`, retrying with exponential backoff until it succeeds
+    /// or runs out of attempts, in which case it completes with the error
+    async fn handle_with_retries(&self, model: Model, active: Active) -> Completed {
+        let retries = &self.config.retries;
+        let mut backoff = Duration::from_millis(retries.backoff_ms);
+        let mut attempt = 1;
+
+        let error = loop {
+            let err = match self.handle_request(&model, active.clone()).await {
+                Ok(completed) => return completed,
+                Err(err) => err,
+            };
+            if err.is_permanent() || attempt >= retries.attempts {
+                break err;
+            }
+            task::sleep(backoff).await;
+            backoff = std::cmp::min(backoff * 2, Duration::from_millis(retries.max_backoff_ms));
+            attempt += 1;
+        };
+
+        let completed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        Completed::failed(active, completed, error.to_string())
+    }
+
+    async fn handle_request(&self, model: &Model, active: Active) -> Result<Completed, Error> {
+        let (cloud, providers) = self.providers_for(&model.cloud)?;
+        let providers = providers.await?;
+        cloud.handle_request(&providers, model, active).await
+    }
+
     pub fn get_model(&self, id: &Uuid) -> Result<Model, Error> {
         match self.database.open_tree(id.as_bytes()) {
-            Ok(tree) => Model::from_tree(&tree),
+            Ok(tree) => Model::from_tree(&tree, &self.runes),
             Err(_) => Err(Error::ModelLoad(id.to_simple().to_string())),
         }
     }
@@ -166,7 +439,142 @@ impl Controller {
         self.add_to_backlog(id, queued)
     }
 
-    pub fn add_rune(&self, id: &Uuid, name: String, rune: Rune) -> Result<Uuid, Error> {
+    /// Unpacks an uploaded rune archive, first checking that it's signed by a
+    /// trusted key if the server requires signed runes
+    pub fn open_rune(&self, archive: &[u8]) -> Result<Rune, Error> {
+        let signatures = &self.config.signatures;
+        if signatures.required {
+            let trusted = signatures
+                .trusted_keys
+                .iter()
+                .map(|key| signature::public_key(key))
+                .collect::<Result<Vec<_>, _>>()?;
+            signature::verify_archive(archive, &trusted)?;
+        }
+        Ok(Rune::unzip(archive)?)
+    }
+
+    /// Queues changes to a rune's config as one request, once every value, and
+    /// the config they result in, is checked against the rune's config items.
+    /// Strings given for items of other types, such as `"3306"` for an
+    /// integer, are parsed first.
+    pub fn configure_rune(
+        &self,
+        id: &Uuid,
+        name: &str,
+        config: BTreeMap<String, Value>,
+        reset: &[String],
+    ) -> Result<Uuid, Error> {
+        let model = self.get_model(id)?;
+        let rune = model
+            .pending_rune(name)
+            .ok_or_else(|| Error::RuneNotFound(name.into()))?;
+        let (secrets, config): (BTreeMap<_, _>, BTreeMap<_, _>) = rune
+            .config_changes(config, reset)?
+            .into_iter()
+            .partition(|(key, _)| rune.secret_keys().any(|secret| secret == key));
+
+        // Checked as a whole, so that a required item can't be left unset
+        let mut merged = model.pending_config(name).unwrap_or_default();
+        for (key, value) in &config {
+            match value {
+                Some(value) => merged.insert(key.clone(), value.clone()),
+                None => merged.remove(key),
+            };
+        }
+        rune.check_config(&merged)?;
+        if !secrets.is_empty() {
+            self.runes.cipher().check()?;
+        }
+        self.update_model(
+            id,
+            Action::ConfigureRune {
+                name: name.into(),
+                config,
+                secrets: secrets
+                    .into_iter()
+                    .map(|(key, value)| (key, value.and_then(|v| v.as_str().map(Into::into))))
+                    .collect(),
+            },
+        )
+    }
+
+    /// The values of a deployed rune's secrets, for requests authorized with
+    /// the controller's secrets token
+    pub fn rune_secrets(
+        &self,
+        id: &Uuid,
+        name: &str,
+        authorization: Option<&str>,
+    ) -> Result<BTreeMap<String, Option<String>>, Error> {
+        let token = self
+            .config
+            .secrets_token
+            .as_ref()
+            .ok_or(Error::SecretsDisabled)?;
+        let given = authorization
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        // Compared as digests, so the time taken says nothing about the token
+        if Sha256::digest(given.as_bytes()) != Sha256::digest(token.as_bytes()) {
+            return Err(Error::Unauthorized);
+        }
+        self.get_model(id)?
+            .secrets(name)
+            .ok_or_else(|| Error::RuneNotFound(name.into()))
+    }
+
+    /// Uploads a rune archive to the repository, checking it the same way as
+    /// one added to a model directly, and tagging the revision if given a tag.
+    /// It's stored under the digest of the archive's files as uploaded, which
+    /// is what clients check when pushing and pulling it.
+    pub fn upload_rune(
+        &self,
+        name: &str,
+        archive: &[u8],
+        tag: Option<&str>,
+    ) -> Result<RepositoryRune, Error> {
+        self.open_rune(archive)?;
+        let digest = archive::digest(&archive::read(archive)?);
+        self.repository.upload(name, &digest, archive, tag)
+    }
+
+    pub fn list_runes(&self, name: Option<&str>) -> Result<Vec<RepositoryRune>, Error> {
+        self.repository.list(name)
+    }
+
+    pub fn get_rune(&self, name: &str, revision: u64) -> Result<RuneArchive, Error> {
+        self.repository.get(name, revision)
+    }
+
+    pub fn delete_rune(&self, name: &str, revision: u64) -> Result<(), Error> {
+        self.repository.delete(name, revision)
+    }
+
+    /// Unpacks the rune a reference such as `mariadb@3` or `mariadb@stable`
+    /// refers to in the repository
+    pub fn resolve_rune(&self, reference: &str) -> Result<Rune, Error> {
+        self.open_rune(&self.repository.resolve(reference)?.archive)
+    }
+
+    /// Queues a rune to be added, along with the values of any of its secrets
+    /// given by the client, once its defaults are checked to be a valid config.
+    /// Secrets with a `generate` source are filled in once it's deployed.
+    pub fn add_rune(
+        &self,
+        id: &Uuid,
+        name: String,
+        mut rune: Rune,
+        secrets: BTreeMap<String, String>,
+    ) -> Result<Uuid, Error> {
+        check_name(&name)?;
+        rune.check_config(&rune.default_config())?;
+        rune.check_secrets(&secrets)?;
+        // Secrets are stored encrypted, so fail now rather than once deployed
+        if !secrets.is_empty() || rune.generates_secrets() {
+            self.runes.cipher().check()?;
+        }
+        rune.secrets = secrets;
         let queued = Queued::from_action(
             Action::AddRune { name, rune },
             SystemTime::now()
@@ -176,6 +584,29 @@ impl Controller {
         );
         self.add_to_backlog(id, queued)
     }
+
+    pub fn configure_dummy(&self, id: &Uuid, config: DummyConfig) -> Result<(), Error> {
+        match self.registry.resolve(&self.get_model(id)?.cloud)?.0 {
+            Cloud::Dummy => {
+                self.providers.dummy.configure(id, config);
+                Ok(())
+            }
+            cloud => Err(Error::UnknownCloud(format!(
+                "Model uses the {} cloud, not dummy",
+                cloud.to_string()
+            ))),
+        }
+    }
+
+    pub fn get_dummy_world(&self, id: &Uuid) -> Result<DummyWorld, Error> {
+        match self.registry.resolve(&self.get_model(id)?.cloud)?.0 {
+            Cloud::Dummy => Ok(self.providers.dummy.world(id)),
+            cloud => Err(Error::UnknownCloud(format!(
+                "Model uses the {} cloud, not dummy",
+                cloud.to_string()
+            ))),
+        }
+    }
 }
 
 impl Future for Controller {
@@ -185,11 +616,7 @@ impl Future for Controller {
         let futures = self.futures.clone();
         let mut tasks = futures.lock().unwrap();
 
-        for id in self.database.tree_names() {
-            if id == b"__sled__default" {
-                continue;
-            }
-            let id = Uuid::from_slice(&id).unwrap();
+        for id in self.model_ids() {
             let model = self.get_model(&id).unwrap();
             if tasks.contains_key(&id) {
                 if model.get_status() == ModelStatus::Destroyed {
@@ -200,19 +627,21 @@ impl Future for Controller {
             }
         }
 
+        // Requests that fail are recorded in history by the task itself, so a
+        // task only fails if the model can't be saved or loaded. It's dropped,
+        // to be started again on the next poll, resuming its active request.
+        let mut failed = vec![];
         for (mid, task) in tasks.iter_mut() {
             if let Poll::Ready(result) = task.as_mut().poll(ctx) {
-                match result {
-                    Ok(completed) => {
-                        let next = self.get_next_task(mid, completed).unwrap();
-                        *task = Box::pin(*next);
-                    }
-                    Err(err) => {
-                        panic!("{:?}", err);
-                    }
+                match result.and_then(|completed| self.get_next_task(mid, completed)) {
+                    Ok(next) => *task = Box::pin(*next),
+                    Err(_) => failed.push(*mid),
                 }
             }
         }
+        for mid in failed {
+            tasks.remove(&mid);
+        }
 
         ctx.waker().wake_by_ref();
         Poll::Pending
@@ -0,0 +1,115 @@
+//! Encryption of sensitive values stored in the controller database
+
+use crate::server::config::Config;
+use crate::server::error::Error;
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use std::env;
+use std::fs::read_to_string;
+
+const NONCE_LENGTH: usize = 12;
+
+/// Encrypts values with the controller master key.
+///
+/// The key is 32 bytes, hex encoded, read from `Config.master_key_path` or
+/// else the `URUZ_MASTER_KEY` environment variable. The controller runs
+/// without one, but anything that needs encrypting will then fail.
+///
+/// Keys are rotated by moving the current key to
+/// `Config.previous_master_key_paths` and setting a new one. Values encrypted
+/// with a previous key can still be decrypted, and the controller re-encrypts
+/// them all with the new key when it starts, after which the previous keys can
+/// be dropped.
+#[derive(Clone)]
+pub struct Cipher {
+    key: Option<Key>,
+    previous: Vec<Key>,
+}
+
+fn parse_key(hex_key: &str) -> Result<Key, Error> {
+    let bytes =
+        hex::decode(hex_key.trim()).map_err(|err| Error::InvalidMasterKey(err.to_string()))?;
+    if bytes.len() != 32 {
+        return Err(Error::InvalidMasterKey(format!(
+            "Expected 32 bytes, got {}",
+            bytes.len()
+        )));
+    }
+    Ok(*Key::from_slice(&bytes))
+}
+
+impl Cipher {
+    pub fn from_config(config: &Config) -> Result<Self, Error> {
+        let hex_key = match &config.master_key_path {
+            Some(path) => Some(read_to_string(path)?),
+            None => env::var("URUZ_MASTER_KEY").ok(),
+        };
+        let previous = config
+            .previous_master_key_paths
+            .iter()
+            .map(read_to_string)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::from_hex(
+            hex_key.as_deref(),
+            &previous.iter().map(String::as_str).collect::<Vec<_>>(),
+        )
+    }
+
+    /// Builds a cipher from hex encoded keys, as they're stored in key files
+    pub fn from_hex(key: Option<&str>, previous: &[&str]) -> Result<Self, Error> {
+        Ok(Self {
+            key: key.map(parse_key).transpose()?,
+            previous: previous
+                .iter()
+                .map(|key| parse_key(key))
+                .collect::<Result<_, _>>()?,
+        })
+    }
+
+    fn cipher(&self) -> Result<ChaCha20Poly1305, Error> {
+        match &self.key {
+            Some(key) => Ok(ChaCha20Poly1305::new(key)),
+            None => Err(Error::MasterKeyMissing),
+        }
+    }
+
+    /// Checks that there's a master key to encrypt with
+    pub fn check(&self) -> Result<(), Error> {
+        self.cipher().map(|_| ())
+    }
+
+    /// Whether there are previous keys to re-encrypt values away from
+    pub fn rotating(&self) -> bool {
+        !self.previous.is_empty()
+    }
+
+    /// Encrypts `plaintext`, returning the random nonce followed by the ciphertext
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce: [u8; NONCE_LENGTH] = rand::random();
+        let mut encrypted = nonce.to_vec();
+        encrypted.extend(
+            self.cipher()?
+                .encrypt(Nonce::from_slice(&nonce), plaintext)
+                .map_err(|_| Error::EncryptionFailed)?,
+        );
+        Ok(encrypted)
+    }
+
+    /// Decrypts a value encrypted with the current key, or with any previous
+    /// one
+    pub fn decrypt(&self, encrypted: &[u8]) -> Result<Vec<u8>, Error> {
+        if encrypted.len() < NONCE_LENGTH {
+            return Err(Error::DecryptionFailed);
+        }
+        let (nonce, ciphertext) = encrypted.split_at(NONCE_LENGTH);
+        let nonce = Nonce::from_slice(nonce);
+        let decrypted = self.cipher()?.decrypt(nonce, ciphertext);
+        self.previous
+            .iter()
+            .fold(decrypted, |decrypted, key| {
+                decrypted.or_else(|_| ChaCha20Poly1305::new(key).decrypt(nonce, ciphertext))
+            })
+            .map_err(|_| Error::DecryptionFailed)
+    }
+}
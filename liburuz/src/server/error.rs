@@ -1,9 +1,13 @@
+use crate::rune::Error as RuneError;
 use crate::server::model::Active;
 use k8s_openapi::RequestError as K8sError;
 use kube::error::{Error as KubeError, ErrorResponse as KubeErrorResponse};
+use reqwest::Error as ReqwestError;
 use serde_json::Error as SerdeJsonError;
+use serde_yaml::Error as YamlError;
 use sled::transaction::TransactionError;
 use sled::Error as SledError;
+use std::fmt;
 use std::io::Error as IOError;
 use uuid::Error as UuidError;
 
@@ -11,16 +15,116 @@ use uuid::Error as UuidError;
 pub enum Error {
     IOError(IOError),
     UnknownCloud(String),
+    CloudNotConfigured(String),
     UnexpectedShutdown(String),
     SledError(SledError),
     ModelLoad(String),
     ModelAlreadyExists(String),
+    InvalidName(String),
     ModelAlreadyDeleted(String),
     SerdeJsonError(SerdeJsonError),
+    YamlError(YamlError),
     K8sError(K8sError),
     KubeError(KubeError),
     KubeErrorResponse(KubeErrorResponse),
+    CloudFormationError(String, String),
+    DeploymentManagerError(String, String),
+    LocalProcessError(String),
+    InjectedFailure(String),
+    ImageBuildError(String),
+    RuneError(RuneError),
+    RuneNotFound(String),
+    InvalidRuneReference(String),
+    RuneNameConflict(String, String),
+    CloudAlreadyExists(String),
+    CloudNotFound(String),
+    CloudInUse(String),
+    MasterKeyMissing,
+    InvalidMasterKey(String),
+    DecryptionFailed,
+    EncryptionFailed,
+    SecretsDisabled,
+    Unauthorized,
+    RequestError(ReqwestError),
     ExistingActiveTask(Active),
+    Unsupported(String),
+}
+
+impl Error {
+    /// Whether retrying the request that failed with this error can't help,
+    /// such as when the rune itself is at fault
+    pub fn is_permanent(&self) -> bool {
+        matches!(
+            self,
+            Error::RuneError(_)
+                | Error::RuneNotFound(_)
+                | Error::InvalidRuneReference(_)
+                | Error::RuneNameConflict(_, _)
+                | Error::InvalidName(_)
+                | Error::UnknownCloud(_)
+                | Error::CloudNotConfigured(_)
+                | Error::MasterKeyMissing
+                | Error::Unsupported(_)
+        )
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(err) => write!(f, "{}", err),
+            Error::UnknownCloud(message) => write!(f, "{}", message),
+            Error::CloudNotConfigured(cloud) => write!(f, "The {} cloud isn't configured", cloud),
+            Error::UnexpectedShutdown(message) => write!(f, "{}", message),
+            Error::SledError(err) => write!(f, "Database error: {}", err),
+            Error::ModelLoad(message) => write!(f, "Can't load model: {}", message),
+            Error::ModelAlreadyExists(name) => write!(f, "Model {} already exists", name),
+            Error::InvalidName(name) => write!(
+                f,
+                "Invalid name {:?}, which can only have letters, digits, '-', '_' and '.'",
+                name
+            ),
+            Error::ModelAlreadyDeleted(name) => write!(f, "Model {} was destroyed", name),
+            Error::SerdeJsonError(err) => write!(f, "{}", err),
+            Error::YamlError(err) => write!(f, "{}", err),
+            Error::K8sError(err) => write!(f, "Kubernetes request error: {}", err),
+            Error::KubeError(err) => write!(f, "Kubernetes error: {}", err),
+            Error::KubeErrorResponse(err) => write!(f, "Kubernetes error: {}", err.message),
+            Error::CloudFormationError(code, message) => {
+                write!(f, "CloudFormation error {}: {}", code, message)
+            }
+            Error::DeploymentManagerError(code, message) => {
+                write!(f, "Deployment Manager error {}: {}", code, message)
+            }
+            Error::LocalProcessError(message) => write!(f, "{}", message),
+            Error::InjectedFailure(action) => write!(f, "Injected failure of {}", action),
+            Error::ImageBuildError(message) => write!(f, "Can't build image: {}", message),
+            Error::RuneError(err) => write!(f, "{}", err),
+            Error::RuneNotFound(name) => write!(f, "Rune {} not found", name),
+            Error::InvalidRuneReference(reference) => {
+                write!(f, "Invalid rune reference {}", reference)
+            }
+            Error::RuneNameConflict(first, second) => write!(
+                f,
+                "Runes {} and {} can't both be deployed, as their names only differ in punctuation",
+                first, second
+            ),
+            Error::CloudAlreadyExists(name) => write!(f, "Cloud {} already exists", name),
+            Error::CloudNotFound(name) => write!(f, "Cloud {} not found", name),
+            Error::CloudInUse(name) => write!(f, "Cloud {} is used by a model", name),
+            Error::MasterKeyMissing => write!(f, "The controller has no master key"),
+            Error::InvalidMasterKey(message) => write!(f, "Invalid master key: {}", message),
+            Error::DecryptionFailed => write!(f, "Can't decrypt secrets with the master key"),
+            Error::EncryptionFailed => write!(f, "Can't encrypt secrets with the master key"),
+            Error::SecretsDisabled => write!(f, "Reading secrets is disabled"),
+            Error::Unauthorized => write!(f, "Unauthorized"),
+            Error::RequestError(err) => write!(f, "{}", err),
+            Error::ExistingActiveTask(active) => {
+                write!(f, "Request {} is already active", active.id)
+            }
+            Error::Unsupported(what) => write!(f, "{} isn't supported", what),
+        }
+    }
 }
 
 impl From<IOError> for Error {
@@ -41,6 +145,18 @@ impl From<SerdeJsonError> for Error {
     }
 }
 
+impl From<YamlError> for Error {
+    fn from(err: YamlError) -> Self {
+        Error::YamlError(err)
+    }
+}
+
+impl From<RuneError> for Error {
+    fn from(err: RuneError) -> Self {
+        Error::RuneError(err)
+    }
+}
+
 impl From<K8sError> for Error {
     fn from(err: K8sError) -> Self {
         Error::K8sError(err)
@@ -59,6 +175,12 @@ impl From<KubeErrorResponse> for Error {
     }
 }
 
+impl From<ReqwestError> for Error {
+    fn from(err: ReqwestError) -> Self {
+        Error::RequestError(err)
+    }
+}
+
 impl From<UuidError> for Error {
     fn from(err: UuidError) -> Self {
         Error::ModelLoad(format!("Error loading UUID: {}", err))
@@ -1,20 +1,27 @@
 pub mod api;
 pub mod config;
 pub mod controller;
+pub mod crypto;
 pub mod error;
 pub mod model;
+pub mod registry;
+pub mod repository;
+pub mod store;
 
 use self::config::Config;
 use self::controller::Controller;
 use self::error::Error;
+use crate::clouds::Providers;
 
 use futures::join;
 
 pub async fn start(c: Config) -> Result<(), Error> {
-    let controller = Controller::new(c.database_path)?;
+    let providers = Providers::from_config(&c).await?;
+    let address = (c.api_host, c.api_port);
+    let controller = Controller::new(c, providers)?;
     let api_v1 = api::v1::build(controller.clone());
 
-    let api = warp::serve(api_v1).run((c.api_host, c.api_port));
+    let api = warp::serve(api_v1).run(address);
 
     join!(api, controller);
 
@@ -1,10 +1,11 @@
 use crate::api::v1 as apiv1;
-use crate::clouds::Cloud;
+use crate::rune::v1::render::{Deployment, Relation, State};
 use crate::rune::v1::rune::Rune;
 use crate::server::error::Error;
+use crate::server::store::RuneStore;
 use serde_derive::{Deserialize, Serialize};
-use serde_json::from_slice;
-use std::collections::VecDeque;
+use serde_json::{from_slice, json, Value};
+use std::collections::{BTreeMap, VecDeque};
 use uuid::Uuid;
 
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
@@ -20,10 +21,13 @@ pub enum Action {
         name: String,
         rune: Rune,
     },
+    /// Sets each key to its value, or leaves it without one for `None`. Values
+    /// of secret config items are kept apart, so they can be stored encrypted.
     ConfigureRune {
         name: String,
-        attribute: String,
-        value: String,
+        config: BTreeMap<String, Option<Value>>,
+        #[serde(default)]
+        secrets: BTreeMap<String, Option<String>>,
     },
     RemoveRune {
         name: String,
@@ -42,18 +46,76 @@ impl Into<apiv1::Action> for Action {
             },
             Action::ConfigureRune {
                 name,
-                attribute,
-                value,
+                config,
+                secrets,
             } => apiv1::Action::ConfigureRune {
                 name,
-                attribute,
-                value,
+                config: redact(&config, &secrets),
             },
             Action::RemoveRune { name } => apiv1::Action::RemoveRune { name },
         }
     }
 }
 
+/// The config of rune `name` after `actions`, as described by
+/// [`Model::config`]
+/// Checks that a model, rune or template name can't be mistaken for more than
+/// one path component, where clouds such as the local one use it as a path
+pub fn check_name(name: &str) -> Result<(), Error> {
+    let valid = name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    if !valid || name.chars().all(|c| c == '.') {
+        return Err(Error::InvalidName(name.into()));
+    }
+    Ok(())
+}
+
+fn config<'a>(
+    actions: impl Iterator<Item = &'a Action>,
+    name: &str,
+) -> Option<BTreeMap<String, Value>> {
+    let mut config: Option<BTreeMap<String, Value>> = None;
+
+    for action in actions {
+        match action {
+            Action::AddRune { name: added, rune } if added == name => {
+                config = Some(rune.default_config());
+            }
+            Action::ConfigureRune {
+                name: configured,
+                config: changes,
+                ..
+            } if configured == name => {
+                if let Some(config) = &mut config {
+                    for (key, value) in changes {
+                        match value {
+                            Some(value) => config.insert(key.clone(), value.clone()),
+                            None => config.remove(key),
+                        };
+                    }
+                }
+            }
+            Action::RemoveRune { name: removed } if removed == name => config = None,
+            _ => {}
+        }
+    }
+
+    config
+}
+
+/// Config changes as shown through the API, with secrets merged in but redacted
+pub fn redact(
+    config: &BTreeMap<String, Option<Value>>,
+    secrets: &BTreeMap<String, Option<String>>,
+) -> BTreeMap<String, Option<Value>> {
+    let mut redacted = config.clone();
+    for (key, secret) in secrets {
+        redacted.insert(key.clone(), secret.as_ref().map(|_| json!(apiv1::REDACTED)));
+    }
+    redacted
+}
+
 #[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct Queued {
     pub id: Uuid,
@@ -101,6 +163,10 @@ pub struct Completed {
     pub queued: u128,
     pub started: u128,
     pub completed: u128,
+    /// Why the request failed, once it ran out of attempts. Failed requests
+    /// are kept in history, but have no effect on the model.
+    #[serde(default)]
+    pub error: Option<String>,
 }
 
 impl Completed {
@@ -111,8 +177,20 @@ impl Completed {
             queued: active.queued,
             started: active.started,
             completed,
+            error: None,
         }
     }
+
+    pub fn failed(active: Active, completed: u128, error: String) -> Self {
+        Self {
+            error: Some(error),
+            ..Self::from_active(active, completed)
+        }
+    }
+
+    pub fn succeeded(&self) -> bool {
+        self.error.is_none()
+    }
 }
 
 #[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
@@ -139,14 +217,15 @@ impl Default for ModelStatus {
 pub struct Model {
     pub id: Uuid,
     pub name: String,
-    pub cloud: Cloud,
+    /// Name of the registered cloud, or provider type, the model runs on
+    pub cloud: String,
     pub backlog: VecDeque<Queued>,
     pub active: Option<Active>,
     pub history: Vec<Completed>,
 }
 
 impl Model {
-    pub fn with_name(name: String, cloud: Cloud) -> Self {
+    pub fn with_name(name: String, cloud: String) -> Self {
         Self {
             id: Uuid::new_v4(),
             name,
@@ -157,7 +236,8 @@ impl Model {
         }
     }
 
-    pub fn from_tree(tree: &sled::Tree) -> Result<Self, Error> {
+    /// Loads a model, filling in the runes its requests add from `runes`
+    pub fn from_tree(tree: &sled::Tree, runes: &RuneStore) -> Result<Self, Error> {
         macro_rules! get {
             ($attr:literal) => {
                 &tree
@@ -167,18 +247,169 @@ impl Model {
         }
         Ok(Self {
             id: Uuid::from_slice(get!("id"))?,
-            name: String::from_utf8_lossy(get!("name")).to_string(),
+            name: from_slice(get!("name"))?,
             cloud: from_slice(get!("cloud"))?,
-            backlog: from_slice(get!("backlog"))?,
-            active: from_slice(get!("active"))?,
-            history: from_slice(get!("history"))?,
+            backlog: runes.from_slice(get!("backlog"))?,
+            active: runes.from_slice(get!("active"))?,
+            history: runes.from_slice(get!("history"))?,
         })
     }
 
+    /// The rune `name` will be once every request so far is handled, whether
+    /// or not it's been deployed yet
+    pub fn pending_rune(&self, name: &str) -> Option<Rune> {
+        let actions = self
+            .succeeded()
+            .map(|c| &c.action)
+            .chain(self.active.iter().map(|a| &a.action))
+            .chain(self.backlog.iter().map(|q| &q.action));
+
+        let mut pending = None;
+        for action in actions {
+            match action {
+                Action::AddRune { name: added, rune } if added == name => {
+                    pending = Some(rune.clone())
+                }
+                Action::RemoveRune { name: removed } if removed == name => pending = None,
+                _ => {}
+            }
+        }
+        pending
+    }
+
+    /// The requests in this model's history that didn't fail
+    pub fn succeeded(&self) -> impl Iterator<Item = &Completed> {
+        self.history.iter().filter(|c| c.succeeded())
+    }
+
+    /// The runes deployed by the completed requests in this model's history
+    pub fn runes(&self) -> BTreeMap<String, Rune> {
+        let mut runes = BTreeMap::new();
+
+        for item in self.succeeded() {
+            match &item.action {
+                Action::AddRune { name, rune } => {
+                    runes.insert(name.clone(), rune.clone());
+                }
+                Action::RemoveRune { name } => {
+                    runes.remove(name);
+                }
+                _ => {}
+            }
+        }
+
+        runes
+    }
+
+    /// The values of a deployed rune's secrets, unlike the redacted ones in
+    /// every other view of the model
+    pub fn secrets(&self, name: &str) -> Option<BTreeMap<String, Option<String>>> {
+        let mut secrets: Option<BTreeMap<String, Option<String>>> = None;
+
+        for item in self.succeeded() {
+            match &item.action {
+                Action::AddRune { name: added, rune } if added == name => {
+                    secrets = Some(
+                        rune.secret_keys()
+                            .map(|key| (key.clone(), rune.secrets.get(key).cloned()))
+                            .collect(),
+                    );
+                }
+                Action::ConfigureRune {
+                    name: configured,
+                    secrets: changes,
+                    ..
+                } if configured == name => {
+                    if let Some(secrets) = &mut secrets {
+                        secrets.extend(changes.clone());
+                    }
+                }
+                Action::RemoveRune { name: removed } if removed == name => secrets = None,
+                _ => {}
+            }
+        }
+
+        secrets
+    }
+
+    /// The values of a deployed rune's config items other than secrets, with
+    /// defaults for those that were never configured
+    pub fn config(&self, name: &str) -> Option<BTreeMap<String, Value>> {
+        config(self.succeeded().map(|c| &c.action), name)
+    }
+
+    /// A rune's config once every queued request is handled, like
+    /// [`pending_rune`](Self::pending_rune)
+    pub fn pending_config(&self, name: &str) -> Option<BTreeMap<String, Value>> {
+        let actions = self
+            .succeeded()
+            .map(|c| &c.action)
+            .chain(self.active.iter().map(|a| &a.action))
+            .chain(self.backlog.iter().map(|q| &q.action));
+        config(actions, name)
+    }
+
+    /// What the templates of a deployed rune can refer to. Each of the rune's
+    /// relations is to the deployed rune that provides it, if there is one.
+    pub fn state(&self, name: &str) -> Option<State> {
+        let runes = self.runes();
+        let rune = runes.get(name)?;
+        let deployment = |host: &str| Deployment {
+            model_name: self.name.clone(),
+            host: Some(host.into()),
+        };
+        let secrets = |name: &str| -> BTreeMap<String, String> {
+            self.secrets(name)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|(key, value)| Some((key, value?)))
+                .collect()
+        };
+
+        let mut relations = BTreeMap::new();
+        for require in &rune.metadata.requires {
+            let provider = runes.iter().find(|(other, provider)| {
+                *other != name
+                    && provider
+                        .metadata
+                        .provides
+                        .iter()
+                        .any(|p| p.name == require.name && p.interface == require.interface)
+            });
+            if let Some((other, _)) = provider {
+                relations.insert(
+                    require.name.clone(),
+                    Relation {
+                        juju: deployment(other),
+                        config: self.config(other).unwrap_or_default(),
+                        secrets: secrets(other),
+                    },
+                );
+            }
+        }
+
+        Some(State {
+            config: self.config(name).unwrap_or_default(),
+            secrets: secrets(name),
+            relations,
+            juju: deployment(name),
+        })
+    }
+
+    /// The deployed runes, each with its templates rendered against its state
+    pub fn rendered_runes(&self) -> Result<BTreeMap<String, Rune>, Error> {
+        let mut runes = self.runes();
+        for (name, rune) in &mut runes {
+            let state = self.state(name).unwrap_or_default();
+            rune.template = rune.render(&state)?;
+        }
+        Ok(runes)
+    }
+
     pub fn get_status(&self) -> ModelStatus {
         let mut status = ModelStatus::Ready;
 
-        for item in &self.history {
+        for item in self.succeeded() {
             match item.action {
                 Action::CreateModel { .. } => status = ModelStatus::Ready,
                 Action::DestroyModel => status = ModelStatus::Destroyed,
@@ -194,7 +425,7 @@ impl Into<apiv1::Model> for Model {
     fn into(self) -> apiv1::Model {
         let mut state: apiv1::ModelState = Default::default();
 
-        for item in &self.history {
+        for item in self.succeeded() {
             match &item.action {
                 Action::CreateModel { .. } => state.status = apiv1::ModelStatus::Ready,
                 Action::ConfigureModel { foo } => state.config.foo = foo.clone(),
@@ -204,11 +435,11 @@ impl Into<apiv1::Model> for Model {
                 }
                 Action::ConfigureRune {
                     name,
-                    attribute,
-                    value,
+                    config,
+                    secrets,
                 } => {
                     let rune = state.runes.get_mut(name).unwrap();
-                    rune.state.insert(attribute.clone(), Some(value.clone()));
+                    rune.configure(&redact(config, secrets));
                 }
                 Action::RemoveRune { name } => {
                     state.runes.remove(name).unwrap();
@@ -224,6 +455,7 @@ impl Into<apiv1::Model> for Model {
                 queued: h.queued,
                 started: Some(h.started),
                 completed: Some(h.completed),
+                error: h.error,
             })
             .collect();
         if let Some(a) = self.active {
@@ -233,6 +465,7 @@ impl Into<apiv1::Model> for Model {
                 queued: a.queued,
                 started: Some(a.started),
                 completed: None,
+                error: None,
             });
         }
         requests.extend(self.backlog.into_iter().map(|h| apiv1::Request {
@@ -241,11 +474,12 @@ impl Into<apiv1::Model> for Model {
             queued: h.queued,
             started: None,
             completed: None,
+            error: None,
         }));
         apiv1::Model {
             id: self.id.to_string(),
             name: self.name,
-            cloud: self.cloud.to_string(),
+            cloud: self.cloud,
             requests,
             state,
         }
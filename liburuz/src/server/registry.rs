@@ -0,0 +1,106 @@
+//! Clouds registered with the controller
+//!
+//! Each provider type is always available under its own name, such as
+//! `kubernetes`, using the controller's configuration for it. Registrations add
+//! further named clouds with their own credentials, which are stored encrypted
+//! with the controller master key.
+
+use crate::api::v1::CloudCredentials;
+use crate::clouds::Cloud;
+use crate::server::crypto::Cipher;
+use crate::server::error::Error;
+use serde_json::{from_slice, to_vec};
+
+pub const BUILTIN: [Cloud; 5] = [
+    Cloud::AWS,
+    Cloud::Dummy,
+    Cloud::GCE,
+    Cloud::Kubernetes,
+    Cloud::Local,
+];
+
+#[derive(Clone)]
+pub struct Registry {
+    tree: sled::Tree,
+    cipher: Cipher,
+}
+
+impl Registry {
+    pub fn new(database: &sled::Db, cipher: Cipher) -> Result<Self, Error> {
+        Ok(Self {
+            tree: database.open_tree("clouds")?,
+            cipher,
+        })
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<CloudCredentials, Error> {
+        Ok(from_slice(&self.cipher.decrypt(bytes)?)?)
+    }
+
+    /// Returns the registered credentials for `name`, if there are any
+    pub fn get(&self, name: &str) -> Result<Option<CloudCredentials>, Error> {
+        match self.tree.get(name)? {
+            Some(bytes) => Ok(Some(self.decode(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Looks up the provider type of a cloud, along with its credentials if
+    /// it isn't a builtin one
+    pub fn resolve(&self, name: &str) -> Result<(Cloud, Option<CloudCredentials>), Error> {
+        match self.get(name)? {
+            Some(credentials) => Ok((credentials.cloud(), Some(credentials))),
+            None => match Cloud::from_str(name) {
+                Ok(cloud) => Ok((cloud, None)),
+                Err(_) => Err(Error::CloudNotFound(name.into())),
+            },
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<(String, CloudCredentials)>, Error> {
+        self.tree
+            .iter()
+            .map(|item| {
+                let (name, bytes) = item?;
+                Ok((
+                    String::from_utf8_lossy(&name).to_string(),
+                    self.decode(&bytes)?,
+                ))
+            })
+            .collect()
+    }
+
+    pub fn create(&self, name: &str, credentials: &CloudCredentials) -> Result<(), Error> {
+        if Cloud::from_str(name).is_ok() {
+            return Err(Error::CloudAlreadyExists(name.into()));
+        }
+        let encrypted = self.cipher.encrypt(&to_vec(credentials)?)?;
+        self.tree
+            .compare_and_swap(name, None as Option<&[u8]>, Some(encrypted))?
+            .map_err(|_| Error::CloudAlreadyExists(name.into()))
+    }
+
+    pub fn update(&self, name: &str, credentials: &CloudCredentials) -> Result<(), Error> {
+        if self.tree.get(name)?.is_none() {
+            return Err(Error::CloudNotFound(name.into()));
+        }
+        self.tree
+            .insert(name, self.cipher.encrypt(&to_vec(credentials)?)?)?;
+        Ok(())
+    }
+
+    /// Re-encrypts every registration with the current master key
+    pub fn rotate(&self) -> Result<(), Error> {
+        for (name, credentials) in self.list()? {
+            self.update(&name, &credentials)?;
+        }
+        Ok(())
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), Error> {
+        match self.tree.remove(name)? {
+            Some(_) => Ok(()),
+            None => Err(Error::CloudNotFound(name.into())),
+        }
+    }
+}
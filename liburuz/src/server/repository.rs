@@ -0,0 +1,217 @@
+//! Runes uploaded to the controller, by name and revision
+//!
+//! Each upload of a name becomes its next revision, starting from 1, unless
+//! it's identical to the latest one. Revisions are never reused, even once
+//! deleted, so a reference such as `mariadb@3` can't come to mean another
+//! rune. Archives are kept as uploaded, signature
+//! and all, so they can be fetched again or added to models by a reference
+//! such as `mariadb@3`, or just `mariadb` for the latest revision.
+//!
+//! An upload can also tag the revision it results in, such as `stable`, so
+//! that `mariadb@stable` refers to it until the tag is moved by another upload.
+//! Tags can't be numbers, which would read as revisions.
+
+use crate::api::v1::{RepositoryRune, RuneArchive};
+use crate::server::error::Error;
+use serde_json::{from_slice, to_vec};
+
+#[derive(Clone)]
+pub struct Repository {
+    tree: sled::Tree,
+    tags: sled::Tree,
+    /// The last revision given out for each name
+    revisions: sled::Tree,
+}
+
+/// Which revision of a rune a reference refers to
+#[derive(Clone, Debug, PartialEq)]
+pub enum Selector {
+    Latest,
+    Revision(u64),
+    Tag(String),
+}
+
+/// Checks that `tag` can be told apart from a revision, and from the rest of a
+/// reference
+pub fn check_tag(tag: &str) -> Result<(), Error> {
+    let valid = tag
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || "-_.".contains(c));
+    if tag.is_empty() || !valid || tag.parse::<u64>().is_ok() {
+        return Err(Error::InvalidRuneReference(tag.into()));
+    }
+    Ok(())
+}
+
+/// Splits a reference into the rune name and which of its revisions it refers
+/// to
+pub fn parse_reference(reference: &str) -> Result<(&str, Selector), Error> {
+    let invalid = || Error::InvalidRuneReference(reference.into());
+    let (name, selector) = match reference.find('@') {
+        Some(index) => {
+            let selected = &reference[index + 1..];
+            let selector = match selected.parse() {
+                Ok(revision) => Selector::Revision(revision),
+                Err(_) => {
+                    check_tag(selected).map_err(|_| invalid())?;
+                    Selector::Tag(selected.into())
+                }
+            };
+            (&reference[..index], selector)
+        }
+        None => (reference, Selector::Latest),
+    };
+    if name.is_empty() || name.contains('/') {
+        return Err(invalid());
+    }
+    Ok((name, selector))
+}
+
+fn key(name: &str, revision: u64) -> String {
+    // Padded so that revisions sort numerically
+    format!("{}@{:020}", name, revision)
+}
+
+fn tag_key(name: &str, tag: &str) -> String {
+    format!("{}@{}", name, tag)
+}
+
+impl Repository {
+    pub fn new(database: &sled::Db) -> Result<Self, Error> {
+        Ok(Self {
+            tree: database.open_tree("repository")?,
+            tags: database.open_tree("repository-tags")?,
+            revisions: database.open_tree("repository-revisions")?,
+        })
+    }
+
+    fn latest(&self, name: &str) -> Result<Option<RuneArchive>, Error> {
+        match self.tree.scan_prefix(format!("{}@", name)).next_back() {
+            Some(item) => Ok(Some(from_slice(&item?.1)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Tags of `name` along with the revisions they point at
+    fn tags(&self, name: &str) -> Result<Vec<(String, u64)>, Error> {
+        let prefix = format!("{}@", name);
+        self.tags
+            .scan_prefix(&prefix)
+            .map(|item| {
+                let (key, revision) = item?;
+                let tag = String::from_utf8_lossy(&key[prefix.len()..]).to_string();
+                Ok((tag, from_slice(&revision)?))
+            })
+            .collect()
+    }
+
+    fn summary(&self, rune: &RuneArchive) -> Result<RepositoryRune, Error> {
+        let mut summary = rune.summary();
+        summary.tags = self
+            .tags(&rune.name)?
+            .into_iter()
+            .filter(|(_, revision)| *revision == rune.revision)
+            .map(|(tag, _)| tag)
+            .collect();
+        Ok(summary)
+    }
+
+    /// Stores `archive` as the next revision of `name`, or returns the latest
+    /// revision if it has the same digest, then points `tag` at it
+    pub fn upload(
+        &self,
+        name: &str,
+        digest: &str,
+        archive: &[u8],
+        tag: Option<&str>,
+    ) -> Result<RepositoryRune, Error> {
+        if let (_, Selector::Revision(_)) | (_, Selector::Tag(_)) = parse_reference(name)? {
+            return Err(Error::InvalidRuneReference(name.into()));
+        }
+        if let Some(tag) = tag {
+            check_tag(tag)?;
+        }
+
+        let stored = loop {
+            let latest = self.latest(name)?;
+            let counted = self.revisions.get(name)?;
+            // Repositories from before revisions were counted only have their
+            // latest revision to go on
+            let last = match &counted {
+                Some(bytes) => from_slice(bytes)?,
+                None => 0,
+            };
+            let revision = match latest {
+                Some(latest) if latest.digest == digest => break latest,
+                Some(latest) => latest.revision.max(last) + 1,
+                None => last + 1,
+            };
+            // Another upload may have taken this revision in the meantime
+            let swapped =
+                self.revisions
+                    .compare_and_swap(name, counted, Some(to_vec(&revision)?))?;
+            if swapped.is_err() {
+                continue;
+            }
+            let rune = RuneArchive {
+                name: name.into(),
+                revision,
+                digest: digest.into(),
+                archive: archive.to_vec(),
+            };
+            self.tree.insert(key(name, revision), to_vec(&rune)?)?;
+            break rune;
+        };
+
+        if let Some(tag) = tag {
+            self.tags
+                .insert(tag_key(name, tag), to_vec(&stored.revision)?)?;
+        }
+        self.summary(&stored)
+    }
+
+    /// Lists every revision of every rune, or of just `name`
+    pub fn list(&self, name: Option<&str>) -> Result<Vec<RepositoryRune>, Error> {
+        let prefix = name.map(|name| format!("{}@", name)).unwrap_or_default();
+        self.tree
+            .scan_prefix(prefix)
+            .map(|item| self.summary(&from_slice::<RuneArchive>(&item?.1)?))
+            .collect()
+    }
+
+    pub fn get(&self, name: &str, revision: u64) -> Result<RuneArchive, Error> {
+        match self.tree.get(key(name, revision))? {
+            Some(bytes) => Ok(from_slice(&bytes)?),
+            None => Err(Error::RuneNotFound(format!("{}@{}", name, revision))),
+        }
+    }
+
+    /// Looks up a rune by reference, as described in [`parse_reference`]
+    pub fn resolve(&self, reference: &str) -> Result<RuneArchive, Error> {
+        match parse_reference(reference)? {
+            (name, Selector::Revision(revision)) => self.get(name, revision),
+            (name, Selector::Tag(tag)) => match self.tags.get(tag_key(name, &tag))? {
+                Some(revision) => self.get(name, from_slice(&revision)?),
+                None => Err(Error::RuneNotFound(reference.into())),
+            },
+            (name, Selector::Latest) => self
+                .latest(name)?
+                .ok_or_else(|| Error::RuneNotFound(name.into())),
+        }
+    }
+
+    /// Deletes a revision, along with any tags pointing at it
+    pub fn delete(&self, name: &str, revision: u64) -> Result<(), Error> {
+        match self.tree.remove(key(name, revision))? {
+            Some(_) => {
+                for (tag, tagged) in self.tags(name)? {
+                    if tagged == revision {
+                        self.tags.remove(tag_key(name, &tag))?;
+                    }
+                }
+                Ok(())
+            }
+            None => Err(Error::RuneNotFound(format!("{}@{}", name, revision))),
+        }
+    }
+}
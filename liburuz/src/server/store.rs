@@ -0,0 +1,260 @@
+//! Content-addressed storage for the runes added to models
+//!
+//! Each rune is stored once, under its digest, no matter how many models it's
+//! added to. Models keep only the digest in their requests, along with the
+//! images built and secrets given for that deployment of the rune, and the rune
+//! is filled back in whenever the model is loaded.
+//!
+//! Secrets, whether those of added runes or those set by configuring them, are
+//! encrypted with the controller master key, so that they aren't exposed by a
+//! copy of the database.
+//!
+//! Sled doesn't allow a tree to be used on its own while a transaction is
+//! running, so requests saved as part of a model transaction go through the
+//! rune tree within that same transaction, via [`Runes`].
+
+use crate::rune::v1::rune::Rune;
+use crate::server::crypto::Cipher;
+use crate::server::error::Error;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{from_value, json, to_value, Value};
+use sled::transaction::{
+    ConflictableTransactionError, TransactionalTree, UnabortableTransactionError,
+};
+use sled::IVec;
+
+type Result<T> = std::result::Result<T, ConflictableTransactionError<Error>>;
+
+/// The rune tree, either on its own or within a transaction
+pub trait Runes {
+    fn get(&self, digest: &str) -> std::result::Result<Option<IVec>, UnabortableTransactionError>;
+    fn insert(
+        &self,
+        digest: &str,
+        rune: Vec<u8>,
+    ) -> std::result::Result<(), UnabortableTransactionError>;
+}
+
+impl Runes for sled::Tree {
+    fn get(&self, digest: &str) -> std::result::Result<Option<IVec>, UnabortableTransactionError> {
+        Ok(sled::Tree::get(self, digest)?)
+    }
+
+    fn insert(
+        &self,
+        digest: &str,
+        rune: Vec<u8>,
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        sled::Tree::insert(self, digest, rune)?;
+        Ok(())
+    }
+}
+
+impl Runes for TransactionalTree {
+    fn get(&self, digest: &str) -> std::result::Result<Option<IVec>, UnabortableTransactionError> {
+        TransactionalTree::get(self, digest)
+    }
+
+    fn insert(
+        &self,
+        digest: &str,
+        rune: Vec<u8>,
+    ) -> std::result::Result<(), UnabortableTransactionError> {
+        TransactionalTree::insert(self, digest, rune)?;
+        Ok(())
+    }
+}
+
+fn abort<E: Into<Error>>(err: E) -> ConflictableTransactionError<Error> {
+    ConflictableTransactionError::Abort(err.into())
+}
+
+#[derive(Clone)]
+pub struct RuneStore {
+    tree: sled::Tree,
+    cipher: Cipher,
+}
+
+impl RuneStore {
+    pub fn new(database: &sled::Db, cipher: Cipher) -> std::result::Result<Self, Error> {
+        Ok(Self {
+            tree: database.open_tree("runes")?,
+            cipher,
+        })
+    }
+
+    pub fn tree(&self) -> &sled::Tree {
+        &self.tree
+    }
+
+    /// The cipher that secrets in requests are encrypted with
+    pub fn cipher(&self) -> &Cipher {
+        &self.cipher
+    }
+
+    /// Number of distinct runes stored
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn get(&self, digest: &str) -> std::result::Result<Rune, Error> {
+        get(&self.tree, digest).map_err(outside_transaction)
+    }
+
+    /// Serializes requests, as with [`to_vec`], outside of any transaction
+    pub fn to_vec<T: Serialize>(&self, requests: &T) -> std::result::Result<Vec<u8>, Error> {
+        self::to_vec(&self.tree, &self.cipher, requests).map_err(outside_transaction)
+    }
+
+    /// Deserializes requests, as with [`from_slice`], outside of any
+    /// transaction
+    pub fn from_slice<T: DeserializeOwned>(&self, bytes: &[u8]) -> std::result::Result<T, Error> {
+        self::from_slice(&self.tree, &self.cipher, bytes).map_err(outside_transaction)
+    }
+}
+
+/// Only transactions can conflict, so anything else is a storage error or an
+/// error of our own
+fn outside_transaction(err: ConflictableTransactionError<Error>) -> Error {
+    match err {
+        ConflictableTransactionError::Abort(err) => err,
+        ConflictableTransactionError::Storage(err) => Error::SledError(err),
+        ConflictableTransactionError::Conflict => {
+            unreachable!("Conflict outside of a transaction")
+        }
+    }
+}
+
+fn get(runes: &dyn Runes, digest: &str) -> Result<Rune> {
+    match runes.get(digest)? {
+        Some(bytes) => serde_json::from_slice(&bytes).map_err(abort),
+        None => Err(abort(Error::RuneNotFound(digest.into()))),
+    }
+}
+
+/// Stores `rune` unless an identical one is already stored, returning its
+/// digest
+pub fn put(runes: &dyn Runes, rune: &Rune) -> Result<String> {
+    let digest = rune.digest().map_err(abort)?;
+    if runes.get(&digest)?.is_none() {
+        let mut rune = rune.clone();
+        rune.images.clear();
+        rune.secrets.clear();
+        runes.insert(&digest, serde_json::to_vec(&rune).map_err(abort)?)?;
+    }
+    Ok(digest)
+}
+
+/// Encrypts a map of secrets in place. Empty ones are left as they are, so that
+/// runes without secrets don't need a master key.
+fn seal(cipher: &Cipher, secrets: &mut Value) -> Result<()> {
+    match secrets {
+        Value::Object(map) if !map.is_empty() => {}
+        _ => return Ok(()),
+    }
+    let plaintext = serde_json::to_vec(secrets).map_err(abort)?;
+    *secrets = Value::String(base64::encode(cipher.encrypt(&plaintext).map_err(abort)?));
+    Ok(())
+}
+
+/// Decrypts a map of secrets sealed by [`seal`] in place. Maps stored before
+/// secrets were encrypted are left as they are, to be sealed when next saved.
+fn open(cipher: &Cipher, secrets: &mut Value) -> Result<()> {
+    if let Value::String(encrypted) = secrets {
+        let encrypted = base64::decode(encrypted).map_err(|_| abort(Error::DecryptionFailed))?;
+        *secrets =
+            serde_json::from_slice(&cipher.decrypt(&encrypted).map_err(abort)?).map_err(abort)?;
+    }
+    Ok(())
+}
+
+/// Serializes requests, storing each rune they add and leaving only its digest,
+/// images and encrypted secrets in its place
+pub fn to_vec<T: Serialize>(runes: &dyn Runes, cipher: &Cipher, requests: &T) -> Result<Vec<u8>> {
+    // Requests hold u128 timestamps, which `to_value` can't represent
+    let bytes = serde_json::to_vec(requests).map_err(abort)?;
+    let mut value: Value = serde_json::from_slice(&bytes).map_err(abort)?;
+    visit(&mut value, &mut |action| match action {
+        Stored::AddRune(rune) => {
+            let stored: Rune = from_value(rune.take()).map_err(abort)?;
+            *rune = json!({
+                "digest": put(runes, &stored)?,
+                "images": stored.images,
+                "secrets": stored.secrets,
+            });
+            seal(cipher, &mut rune["secrets"])
+        }
+        Stored::ConfigureRune(secrets) => seal(cipher, secrets),
+    })?;
+    serde_json::to_vec(&value).map_err(abort)
+}
+
+/// Deserializes requests written by [`to_vec`], filling in the runes they add
+/// and decrypting secrets
+pub fn from_slice<T: DeserializeOwned>(
+    runes: &dyn Runes,
+    cipher: &Cipher,
+    bytes: &[u8],
+) -> Result<T> {
+    let mut value: Value = serde_json::from_slice(bytes).map_err(abort)?;
+    visit(&mut value, &mut |action| match action {
+        Stored::AddRune(stored) => {
+            let mut rune = get(runes, stored["digest"].as_str().unwrap_or_default())?;
+            rune.images = from_value(stored["images"].take()).map_err(abort)?;
+            // Requests stored before secrets were kept don't have any
+            if let Some(secrets) = stored.get_mut("secrets") {
+                open(cipher, secrets)?;
+                rune.secrets = from_value(secrets.take()).map_err(abort)?;
+            }
+            *stored = to_value(rune).map_err(abort)?;
+            Ok(())
+        }
+        Stored::ConfigureRune(secrets) => open(cipher, secrets),
+    })?;
+    from_value(value).map_err(abort)
+}
+
+/// The parts of requests that aren't stored as they are
+enum Stored<'a> {
+    /// The rune an `AddRune` action adds
+    AddRune(&'a mut Value),
+    /// The secrets a `ConfigureRune` action sets
+    ConfigureRune(&'a mut Value),
+}
+
+/// Calls `replace` with the parts of every action in `value` that aren't
+/// stored as they are
+fn visit<F>(value: &mut Value, replace: &mut F) -> Result<()>
+where
+    F: FnMut(Stored) -> Result<()>,
+{
+    match value {
+        Value::Object(map) => {
+            if let Some(Value::Object(action)) = map.get_mut("AddRune") {
+                if let Some(rune) = action.get_mut("rune") {
+                    return replace(Stored::AddRune(rune));
+                }
+            }
+            if let Some(Value::Object(action)) = map.get_mut("ConfigureRune") {
+                if let Some(secrets) = action.get_mut("secrets") {
+                    return replace(Stored::ConfigureRune(secrets));
+                }
+            }
+            for item in map.values_mut() {
+                visit(item, replace)?;
+            }
+        }
+        Value::Array(items) => {
+            for item in items {
+                visit(item, replace)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
@@ -0,0 +1,98 @@
+//! Fake CloudFormation Query API
+//!
+//! Accepts `CreateStack`, `UpdateStack` and `DeleteStack` without checking
+//! signatures, and keeps the submitted templates in memory.
+
+use serde_json::{from_str, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use warp::http::StatusCode;
+use warp::Filter;
+
+type Reply = warp::reply::WithStatus<warp::reply::WithHeader<String>>;
+
+#[derive(Clone, Default)]
+pub struct FakeCloudFormation {
+    stacks: Arc<Mutex<BTreeMap<String, Value>>>,
+}
+
+fn reply(code: StatusCode, body: String) -> Reply {
+    warp::reply::with_status(
+        warp::reply::with_header(body, "Content-Type", "text/xml"),
+        code,
+    )
+}
+
+fn error(code: &str, message: &str) -> Reply {
+    reply(
+        StatusCode::BAD_REQUEST,
+        format!(
+            "<ErrorResponse><Error><Type>Sender</Type><Code>{}</Code><Message>{}</Message></Error></ErrorResponse>",
+            code, message
+        ),
+    )
+}
+
+impl FakeCloudFormation {
+    /// Binds to an ephemeral port. Must be called from within a Tokio runtime.
+    pub fn serve(&self) -> (SocketAddr, impl Future<Output = ()> + Send + 'static) {
+        let fake = self.clone();
+        let routes = warp::post()
+            .and(warp::body::form())
+            .map(move |params: HashMap<String, String>| fake.handle(params));
+
+        warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0))
+    }
+
+    pub fn stack(&self, name: &str) -> Option<Value> {
+        self.stacks.lock().unwrap().get(name).cloned()
+    }
+
+    fn handle(&self, params: HashMap<String, String>) -> Reply {
+        let mut stacks = self.stacks.lock().unwrap();
+        let action = params.get("Action").map(String::as_str).unwrap_or("");
+        let name = match params.get("StackName") {
+            Some(name) => name.clone(),
+            None => return error("ValidationError", "StackName is required"),
+        };
+        let template = params.get("TemplateBody").map(|t| from_str::<Value>(t));
+
+        match (action, template) {
+            ("CreateStack", Some(Ok(template))) => {
+                if stacks.contains_key(&name) {
+                    return error(
+                        "AlreadyExistsException",
+                        &format!("Stack [{}] already exists", name),
+                    );
+                }
+                stacks.insert(name, template);
+            }
+            ("UpdateStack", Some(Ok(template))) => match stacks.get(&name) {
+                Some(existing) if existing == &template => {
+                    return error("ValidationError", "No updates are to be performed.");
+                }
+                Some(_) => {
+                    stacks.insert(name, template);
+                }
+                None => {
+                    return error(
+                        "ValidationError",
+                        &format!("Stack [{}] does not exist", name),
+                    )
+                }
+            },
+            ("DeleteStack", _) => {
+                stacks.remove(&name);
+            }
+            (_, Some(Err(err))) => return error("ValidationError", &err.to_string()),
+            _ => return error("InvalidAction", action),
+        }
+
+        reply(
+            StatusCode::OK,
+            format!("<{0}Response><{0}Result/></{0}Response>", action),
+        )
+    }
+}
@@ -0,0 +1,37 @@
+//! Fake docker command
+//!
+//! Records each command it's run with, fails builds of contexts without a
+//! Dockerfile or with scripts that aren't executable, and reports pushed images
+//! by a made up digest.
+
+use std::fs::{set_permissions, write, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+/// Writes the fake command into `dir`, returning its path and that of the file
+/// it records commands in
+pub fn write_docker(dir: &Path) -> (PathBuf, PathBuf) {
+    let program = dir.join("docker");
+    let log = dir.join("docker.log");
+    let script = format!(
+        r#"#!/bin/sh
+echo "$@" >> {log}
+case "$1" in
+    build)
+        test -f "$4/Dockerfile" || exit 1
+        for script in "$4"/*.sh; do
+            test -x "$script" || {{ echo "$script isn't executable" >&2; exit 1; }}
+        done
+        ;;
+    inspect)
+        echo "${{4%:latest}}@sha256:{digest}"
+        ;;
+esac
+"#,
+        log = log.display(),
+        digest = "0".repeat(64),
+    );
+    write(&program, script).unwrap();
+    set_permissions(&program, Permissions::from_mode(0o755)).unwrap();
+    (program, log)
+}
@@ -0,0 +1,113 @@
+//! Fake Cloud Deployment Manager API
+//!
+//! Supports inserting, updating, getting and deleting deployments in any
+//! project, without checking credentials. Submitted configs are parsed and
+//! kept in memory.
+
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use warp::http::StatusCode;
+use warp::Filter;
+
+type Reply = warp::reply::WithStatus<warp::reply::Json>;
+
+#[derive(Clone, Default)]
+pub struct FakeDeploymentManager {
+    deployments: Arc<Mutex<BTreeMap<String, Value>>>,
+}
+
+fn not_found(name: &str) -> Reply {
+    warp::reply::with_status(
+        warp::reply::json(&json!({"error": {"code": 404, "message": name}})),
+        StatusCode::NOT_FOUND,
+    )
+}
+
+fn operation(name: &str) -> Reply {
+    warp::reply::with_status(
+        warp::reply::json(&json!({"kind": "deploymentmanager#operation", "targetLink": name})),
+        StatusCode::OK,
+    )
+}
+
+impl FakeDeploymentManager {
+    /// Binds to an ephemeral port. Must be called from within a Tokio runtime.
+    pub fn serve(&self) -> (SocketAddr, impl Future<Output = ()> + Send + 'static) {
+        let (insert, get, update, delete) =
+            (self.clone(), self.clone(), self.clone(), self.clone());
+        let base = warp::path!(
+            "deploymentmanager" / "v2" / "projects" / String / "global" / "deployments" / ..
+        );
+
+        let routes = base
+            .and(warp::path::end())
+            .and(warp::post())
+            .and(warp::body::json())
+            .map(move |_project: String, body: Value| insert.insert(body))
+            .or(base
+                .and(warp::path!(String))
+                .and(warp::get())
+                .map(move |_project: String, name: String| get.get_reply(&name)))
+            .or(base
+                .and(warp::path!(String))
+                .and(warp::put())
+                .and(warp::body::json())
+                .map(move |_project: String, name: String, body: Value| update.update(&name, body)))
+            .or(base
+                .and(warp::path!(String))
+                .and(warp::delete())
+                .map(move |_project: String, name: String| delete.delete(&name)));
+
+        warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0))
+    }
+
+    /// Returns the parsed config of a deployment
+    pub fn deployment(&self, name: &str) -> Option<Value> {
+        let deployments = self.deployments.lock().unwrap();
+        let content = deployments.get(name)?["target"]["config"]["content"].as_str()?;
+        serde_yaml::from_str(content).ok()
+    }
+
+    fn insert(&self, mut body: Value) -> Reply {
+        let name = body["name"].as_str().unwrap_or_default().to_string();
+        body["fingerprint"] = json!("1");
+        self.deployments.lock().unwrap().insert(name.clone(), body);
+        operation(&name)
+    }
+
+    fn get_reply(&self, name: &str) -> Reply {
+        match self.deployments.lock().unwrap().get(name) {
+            Some(deployment) => {
+                warp::reply::with_status(warp::reply::json(deployment), StatusCode::OK)
+            }
+            None => not_found(name),
+        }
+    }
+
+    fn update(&self, name: &str, mut body: Value) -> Reply {
+        let mut deployments = self.deployments.lock().unwrap();
+        match deployments.get(name) {
+            Some(existing) if existing["fingerprint"] == body["fingerprint"] => {
+                let fingerprint: u64 = body["fingerprint"].as_str().unwrap().parse().unwrap();
+                body["fingerprint"] = json!((fingerprint + 1).to_string());
+                deployments.insert(name.into(), body);
+                operation(name)
+            }
+            Some(_) => warp::reply::with_status(
+                warp::reply::json(&json!({"error": {"code": 412, "message": "fingerprint"}})),
+                StatusCode::PRECONDITION_FAILED,
+            ),
+            None => not_found(name),
+        }
+    }
+
+    fn delete(&self, name: &str) -> Reply {
+        match self.deployments.lock().unwrap().remove(name) {
+            Some(_) => operation(name),
+            None => not_found(name),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+//! Fake Kubernetes API server
+//!
+//! Understands just enough of the API for the Kubernetes cloud: server-side
+//! apply, get and delete of any object by path. Applied objects are kept in
+//! memory so that tests can inspect what would have been deployed.
+
+use serde_json::{from_slice, json, Value};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use warp::http::{Method, StatusCode};
+use warp::path::FullPath;
+use warp::Filter;
+
+#[derive(Clone, Default)]
+pub struct FakeKubernetes {
+    objects: Arc<Mutex<BTreeMap<String, Value>>>,
+}
+
+fn status(
+    code: StatusCode,
+    reason: &str,
+    message: &str,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    warp::reply::with_status(
+        warp::reply::json(&json!({
+            "kind": "Status",
+            "apiVersion": "v1",
+            "metadata": {},
+            "status": if code.is_success() { "Success" } else { "Failure" },
+            "message": message,
+            "reason": reason,
+            "code": code.as_u16(),
+        })),
+        code,
+    )
+}
+
+impl FakeKubernetes {
+    /// Binds to an ephemeral port. Must be called from within a Tokio runtime.
+    pub fn serve(&self) -> (SocketAddr, impl Future<Output = ()> + Send + 'static) {
+        let fake = self.clone();
+        let routes = warp::method()
+            .and(warp::path::full())
+            .and(warp::body::bytes())
+            .map(
+                move |method: Method, path: FullPath, body: warp::hyper::body::Bytes| {
+                    fake.handle(method, path.as_str(), &body)
+                },
+            );
+
+        warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0))
+    }
+
+    /// Writes a kubeconfig pointing at this server, with a single context
+    /// named `fake`.
+    pub fn write_kubeconfig(&self, addr: SocketAddr, path: &str) {
+        let kubeconfig = json!({
+            "apiVersion": "v1",
+            "kind": "Config",
+            "clusters": [{"name": "fake", "cluster": {"server": format!("http://{}", addr)}}],
+            "users": [{"name": "fake", "user": {}}],
+            "contexts": [{"name": "fake", "context": {"cluster": "fake", "user": "fake"}}],
+            "current-context": "fake",
+        });
+        std::fs::write(path, serde_yaml::to_vec(&kubeconfig).unwrap()).unwrap();
+    }
+
+    pub fn get(&self, path: &str) -> Option<Value> {
+        self.objects.lock().unwrap().get(path).cloned()
+    }
+
+    pub fn namespace(&self, name: &str) -> Option<Value> {
+        self.get(&format!("/api/v1/namespaces/{}", name))
+    }
+
+    pub fn deployment(&self, namespace: &str, name: &str) -> Option<Value> {
+        self.get(&format!(
+            "/apis/apps/v1/namespaces/{}/deployments/{}",
+            namespace, name
+        ))
+    }
+
+    fn handle(
+        &self,
+        method: Method,
+        path: &str,
+        body: &[u8],
+    ) -> warp::reply::WithStatus<warp::reply::Json> {
+        let mut objects = self.objects.lock().unwrap();
+
+        match method {
+            Method::GET => match objects.get(path) {
+                Some(obj) => warp::reply::with_status(warp::reply::json(obj), StatusCode::OK),
+                None => status(StatusCode::NOT_FOUND, "NotFound", path),
+            },
+            Method::PATCH => match from_slice::<Value>(body) {
+                Ok(obj) => {
+                    objects.insert(path.into(), obj.clone());
+                    warp::reply::with_status(warp::reply::json(&obj), StatusCode::OK)
+                }
+                Err(err) => status(StatusCode::BAD_REQUEST, "BadRequest", &err.to_string()),
+            },
+            Method::DELETE => match objects.remove(path) {
+                Some(_) => {
+                    // Deleting a namespace takes everything in it along too
+                    if let Some(ns) = path.strip_prefix("/api/v1/namespaces/") {
+                        let prefix = format!("/namespaces/{}/", ns);
+                        objects.retain(|p, _| !p.contains(&prefix));
+                    }
+                    status(StatusCode::OK, "", path)
+                }
+                None => status(StatusCode::NOT_FOUND, "NotFound", path),
+            },
+            _ => status(StatusCode::METHOD_NOT_ALLOWED, "MethodNotAllowed", path),
+        }
+    }
+}
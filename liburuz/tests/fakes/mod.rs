@@ -0,0 +1,7 @@
+//! In-process stand-ins for the cloud APIs that uruzd talks to
+
+pub mod aws;
+pub mod docker;
+pub mod gce;
+pub mod kubernetes;
+pub mod registry;
@@ -0,0 +1,105 @@
+//! Fake container registry
+//!
+//! Supports the parts of the distribution API needed to push an image:
+//! checking for blobs, monolithic blob uploads and putting manifests. Uploaded
+//! blobs are checked against their digest.
+
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use warp::http::{Method, Response, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::Filter;
+
+#[derive(Clone, Default)]
+pub struct FakeRegistry {
+    blobs: Arc<Mutex<BTreeMap<String, Vec<u8>>>>,
+    manifests: Arc<Mutex<BTreeMap<(String, String), Vec<u8>>>>,
+}
+
+fn reply(status: StatusCode) -> Response<Vec<u8>> {
+    Response::builder().status(status).body(vec![]).unwrap()
+}
+
+impl FakeRegistry {
+    /// Binds to an ephemeral port. Must be called from within a Tokio runtime.
+    pub fn serve(&self) -> (SocketAddr, impl Future<Output = ()> + Send + 'static) {
+        let registry = self.clone();
+        let routes = warp::path("v2")
+            .and(warp::path::tail())
+            .and(warp::method())
+            .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+            .and(warp::body::bytes())
+            .map(
+                move |tail: warp::path::Tail, method, query: String, body: Bytes| {
+                    registry.handle(tail.as_str(), method, &query, &body)
+                },
+            );
+
+        warp::serve(routes).bind_ephemeral(([127, 0, 0, 1], 0))
+    }
+
+    /// Returns the manifest pushed for `name` with tag `reference`
+    pub fn manifest(&self, name: &str, reference: &str) -> Option<serde_json::Value> {
+        let manifests = self.manifests.lock().unwrap();
+        let manifest = manifests.get(&(name.to_string(), reference.to_string()))?;
+        serde_json::from_slice(manifest).ok()
+    }
+
+    pub fn blob(&self, digest: &str) -> Option<Vec<u8>> {
+        self.blobs.lock().unwrap().get(digest).cloned()
+    }
+
+    fn handle(&self, path: &str, method: Method, query: &str, body: &[u8]) -> Response<Vec<u8>> {
+        if let Some(name) = path.strip_suffix("/blobs/uploads/") {
+            if method == Method::POST {
+                return Response::builder()
+                    .status(StatusCode::ACCEPTED)
+                    .header("Location", format!("/v2/{}/blobs/uploads/1", name))
+                    .body(vec![])
+                    .unwrap();
+            }
+        }
+
+        if path.contains("/blobs/uploads/") && method == Method::PUT {
+            let digest = query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("digest="))
+                .unwrap_or_default()
+                .replace("%3A", ":");
+            if digest != format!("sha256:{}", hex::encode(Sha256::digest(body))) {
+                return reply(StatusCode::BAD_REQUEST);
+            }
+            self.blobs.lock().unwrap().insert(digest, body.to_vec());
+            return reply(StatusCode::CREATED);
+        }
+
+        if let Some((_, digest)) = split_last(path, "/blobs/") {
+            if method == Method::HEAD {
+                return match self.blobs.lock().unwrap().contains_key(digest) {
+                    true => reply(StatusCode::OK),
+                    false => reply(StatusCode::NOT_FOUND),
+                };
+            }
+        }
+
+        if let Some((name, reference)) = split_last(path, "/manifests/") {
+            if method == Method::PUT {
+                self.manifests
+                    .lock()
+                    .unwrap()
+                    .insert((name.into(), reference.into()), body.to_vec());
+                return reply(StatusCode::CREATED);
+            }
+        }
+
+        reply(StatusCode::NOT_FOUND)
+    }
+}
+
+fn split_last<'a>(path: &'a str, separator: &str) -> Option<(&'a str, &'a str)> {
+    let index = path.rfind(separator)?;
+    Some((&path[..index], &path[index + separator.len()..]))
+}
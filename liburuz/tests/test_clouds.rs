@@ -0,0 +1,105 @@
+use liburuz::clouds::{aws, gce};
+use liburuz::rune::v1::template::TemplateInteger;
+use liburuz::rune::v1::Rune;
+use liburuz::server::config::FargateConfig;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+#[test]
+fn render_aws() {
+    let mut rune = Rune::load("../example-runes/pipelines-api/").unwrap();
+    rune.template[0].include =
+        Some(serde_yaml::from_str("aws: {Memory: 512, Environment: []}").unwrap());
+    // As rendered from the default config
+    rune.template[0].ports[0].container_port = TemplateInteger::Integer(8888);
+    let mut runes = BTreeMap::new();
+    runes.insert("pipelines-api".to_string(), rune);
+
+    let fargate = FargateConfig {
+        subnets: vec!["subnet-1234".into()],
+        security_groups: vec!["sg-1234".into()],
+        assign_public_ip: false,
+        ..Default::default()
+    };
+    let rendered = aws::render("test", &fargate, &runes).unwrap();
+
+    // Each rune runs as a Fargate task, sized and networked as configured
+    let resources = &rendered["Resources"];
+    let task = &resources["PipelinesApiTaskDefinition"]["Properties"];
+    assert_eq!(task["Cpu"], "256");
+    assert_eq!(task["Memory"], "512");
+    assert_eq!(task["NetworkMode"], "awsvpc");
+    assert_eq!(task["RequiresCompatibilities"], json!(["FARGATE"]));
+    let service = &resources["PipelinesApiService"]["Properties"];
+    assert_eq!(service["LaunchType"], "FARGATE");
+    assert_eq!(
+        service["NetworkConfiguration"],
+        json!({
+            "AwsvpcConfiguration": {
+                "AssignPublicIp": "DISABLED",
+                "SecurityGroups": ["sg-1234"],
+                "Subnets": ["subnet-1234"],
+            },
+        })
+    );
+
+    let container = &task["ContainerDefinitions"][0];
+    assert_eq!(container["Name"], "pipelines-api");
+    assert_eq!(container["Image"], "gcr.io/ml-pipeline/api-server:0.1.14");
+    assert_eq!(container["EntryPoint"][0], "/pipelines-api");
+    assert_eq!(container["Command"][0], "--foo");
+    assert_eq!(container["Memory"], 512);
+    assert_eq!(container["Environment"], json!([]));
+    assert_eq!(
+        container["PortMappings"],
+        json!([{"ContainerPort": 8888, "Protocol": "tcp"}]),
+        "Ports still to be rendered are left out"
+    );
+
+    // Names that would share a logical ID can't be in the same stack
+    let rune = runes["pipelines-api"].clone();
+    runes.insert("pipelines_api".to_string(), rune);
+    assert!(aws::render("test", &fargate, &runes).is_err());
+}
+
+#[test]
+fn render_gce() {
+    let mut rune = Rune::load("../example-runes/mariadb/").unwrap();
+    rune.template[0].include =
+        Some(serde_yaml::from_str("gce: {securityContext: {privileged: true}}").unwrap());
+    rune.template[0].ports[0].container_port = TemplateInteger::Integer(3306);
+    let mut runes = BTreeMap::new();
+    runes.insert("mariadb".to_string(), rune);
+
+    let rendered = gce::render("test", "europe-west1-b", &runes).unwrap();
+    let firewall = &rendered["resources"][0];
+    assert_eq!(firewall["type"], "compute.v1.firewall");
+    assert_eq!(
+        firewall["properties"]["targetTags"],
+        json!(["test-mariadb"])
+    );
+    assert_eq!(
+        firewall["properties"]["allowed"],
+        json!([{"IPProtocol": "tcp", "ports": ["3306"]}])
+    );
+    let instance = &rendered["resources"][1];
+    assert_eq!(instance["name"], "test-mariadb");
+    assert_eq!(instance["properties"]["zone"], "europe-west1-b");
+
+    let declaration: serde_json::Value = serde_yaml::from_str(
+        instance["properties"]["metadata"]["items"][0]["value"]
+            .as_str()
+            .unwrap(),
+    )
+    .unwrap();
+    let container = &declaration["spec"]["containers"][0];
+    assert_eq!(container["name"], "mariadb");
+    assert_eq!(container["command"][0], "/mariadb");
+    assert_eq!(container["securityContext"]["privileged"], true);
+
+    // Only the first container would ever run
+    let mut rune = runes["mariadb"].clone();
+    rune.template.push(rune.template[0].clone());
+    runes.insert("mariadb".to_string(), rune);
+    assert!(gce::render("test", "europe-west1-b", &runes).is_err());
+}
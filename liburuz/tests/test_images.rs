@@ -0,0 +1,62 @@
+use liburuz::images::oci::{digest, OciLayout};
+use liburuz::images::{build_rune, context};
+use liburuz::rune::v1::Rune;
+use std::fs::{read, read_to_string};
+
+#[test]
+fn build_oci_layout() {
+    let dir = tempfile::tempdir().unwrap();
+    let layout = OciLayout::new(dir.path());
+    let mut rune = Rune::load("../example-runes/mariadb/").unwrap();
+    assert_eq!(rune.image(&rune.template[0]), None);
+
+    let build_context = context(&rune, "build/");
+    assert_eq!(
+        build_context.keys().collect::<Vec<_>>(),
+        vec!["Dockerfile", "docker-entrypoint.sh"]
+    );
+    assert!(build_context["docker-entrypoint.sh"].executable);
+    assert!(!build_context["Dockerfile"].executable);
+
+    // A Dockerfile would never be run, so the context can't just be packaged
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    assert!(rt.block_on(build_rune(&layout, "mariadb", &rune)).is_err());
+
+    rune.files.remove("build/Dockerfile");
+    rune.images = rt.block_on(build_rune(&layout, "mariadb", &rune)).unwrap();
+    let reference = format!("oci:{}:mariadb/mariadb", dir.path().display());
+    assert_eq!(rune.image(&rune.template[0]), Some(reference.as_str()));
+
+    let index: serde_json::Value =
+        serde_json::from_str(&read_to_string(dir.path().join("index.json")).unwrap()).unwrap();
+    assert_eq!(index["manifests"].as_array().unwrap().len(), 1);
+    let manifest_digest = index["manifests"][0]["digest"].as_str().unwrap();
+    let manifest = read(
+        dir.path()
+            .join("blobs/sha256")
+            .join(&manifest_digest["sha256:".len()..]),
+    )
+    .unwrap();
+    assert_eq!(digest(&manifest), manifest_digest);
+
+    // Files keep the modes they have in the rune
+    let manifest: serde_json::Value = serde_json::from_slice(&manifest).unwrap();
+    let layer_digest = manifest["layers"][0]["digest"].as_str().unwrap();
+    let layer = read(
+        dir.path()
+            .join("blobs/sha256")
+            .join(&layer_digest["sha256:".len()..]),
+    )
+    .unwrap();
+    let mut layer = tar::Archive::new(layer.as_slice());
+    let entry = layer.entries().unwrap().next().unwrap().unwrap();
+    assert_eq!(entry.path().unwrap().to_str(), Some("docker-entrypoint.sh"));
+    assert_eq!(entry.header().mode().unwrap(), 0o755);
+
+    // Building again gives the same image, replacing the tag
+    rt.block_on(build_rune(&layout, "mariadb", &rune)).unwrap();
+    let index: serde_json::Value =
+        serde_json::from_str(&read_to_string(dir.path().join("index.json")).unwrap()).unwrap();
+    assert_eq!(index["manifests"].as_array().unwrap().len(), 1);
+    assert_eq!(index["manifests"][0]["digest"], manifest_digest);
+}
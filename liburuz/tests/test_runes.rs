@@ -1,4 +1,102 @@
+use liburuz::api::v1::{RuneArchive, RuneUpload};
+use liburuz::client::secrets::resolve_env;
+use liburuz::rune::v1::lint::Severity;
+use liburuz::rune::v1::metadata::ConfigItem;
+use liburuz::rune::v1::render::{Relation, State, Unresolved};
+use liburuz::rune::v1::template::TemplateInteger;
 use liburuz::rune::v1::Rune;
+use liburuz::rune::v1::{archive, schema, signature};
+use liburuz::rune::version::{migrate, LATEST};
+use liburuz::rune::Error;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::{copy, create_dir_all, read_to_string, write};
+use std::io::Write;
+
+#[test]
+fn rune_files() {
+    let mariadb = Rune::load("../example-runes/mariadb/").unwrap();
+    assert_eq!(
+        mariadb.files.keys().collect::<Vec<_>>(),
+        vec!["build/Dockerfile", "build/docker-entrypoint.sh"]
+    );
+    assert_eq!(
+        Rune::unzip(&mariadb.zip().unwrap()).unwrap().files,
+        mariadb.files
+    );
+
+    // Which files are executable survives the archive
+    assert_eq!(
+        mariadb.executables.iter().collect::<Vec<_>>(),
+        vec!["build/docker-entrypoint.sh"]
+    );
+    assert_eq!(
+        Rune::unzip(&mariadb.zip().unwrap()).unwrap().executables,
+        mariadb.executables
+    );
+
+    let dir = tempfile::tempdir().unwrap();
+    for name in &["metadata.yaml", "rune.yaml"] {
+        copy(
+            format!("../example-runes/pipelines-api/{}", name),
+            dir.path().join(name),
+        )
+        .unwrap();
+    }
+    create_dir_all(dir.path().join("resources/kubernetes")).unwrap();
+    create_dir_all(dir.path().join("scratch")).unwrap();
+    write(
+        dir.path().join("resources/kubernetes/role.yaml"),
+        "kind: Role",
+    )
+    .unwrap();
+    write(dir.path().join("resources/debug.log"), "").unwrap();
+    write(dir.path().join("scratch/notes.txt"), "").unwrap();
+    write(
+        dir.path().join(".runeignore"),
+        "# Local junk\n*.log\nscratch/\n",
+    )
+    .unwrap();
+
+    let rune = Rune::load(dir.path()).unwrap();
+    assert_eq!(
+        rune.files.keys().collect::<Vec<_>>(),
+        vec!["resources/kubernetes/role.yaml"]
+    );
+    assert_eq!(rune.files["resources/kubernetes/role.yaml"], b"kind: Role");
+    assert_eq!(Rune::unzip(&rune.zip().unwrap()).unwrap(), rune);
+
+    // Hidden files, such as VCS metadata, and env files that secrets are read
+    // from are never packed, whatever they're called
+    let dir = tempfile::tempdir().unwrap();
+    copy(
+        "../example-runes/mariadb/rune.yaml",
+        dir.path().join("rune.yaml"),
+    )
+    .unwrap();
+    let metadata = read_to_string("../example-runes/mariadb/metadata.yaml").unwrap();
+    write(
+        dir.path().join("metadata.yaml"),
+        metadata.replacen("file: .env", "file: ./secrets.env", 1),
+    )
+    .unwrap();
+    create_dir_all(dir.path().join(".git")).unwrap();
+    write(dir.path().join(".git/HEAD"), "ref: refs/heads/master").unwrap();
+    write(dir.path().join(".env"), "PASSWORD=hunter2").unwrap();
+    write(dir.path().join("secrets.env"), "PASSWORD=hunter2").unwrap();
+    write(dir.path().join("Dockerfile"), "FROM mariadb").unwrap();
+
+    let rune = Rune::load(dir.path()).unwrap();
+    assert_eq!(rune.files.keys().collect::<Vec<_>>(), vec!["Dockerfile"]);
+    let zipped = rune.zip().unwrap();
+    let mut archive = zip::ZipArchive::new(std::io::Cursor::new(&zipped)).unwrap();
+    let names: Vec<_> = (0..archive.len())
+        .map(|i| archive.by_index(i).unwrap().name().to_string())
+        .collect();
+    assert!(!names.iter().any(|name| name.ends_with(".env")));
+    assert!(!names.iter().any(|name| name.contains(".git")));
+}
 
 #[test]
 fn parse_rune() {
@@ -11,3 +109,841 @@ fn parse_rune() {
         assert_eq!(loaded, unzipped);
     }
 }
+
+fn pipelines_api_state() -> State {
+    let mut state = State::default();
+    state.juju.model_name = "kubeflow".into();
+    state.config.insert("timeout".into(), json!("6m"));
+    state.config.insert("grpc-port".into(), json!(8887));
+    state.config.insert("http-port".into(), json!(8888));
+
+    let mut mysql = Relation::default();
+    mysql.juju.host = Some("mariadb".into());
+    mysql.config.insert("port".into(), json!(3306));
+    mysql.config.insert("user".into(), json!("root"));
+    mysql.secrets.insert("password".into(), "hunter2".into());
+    state.relations.insert("mysql".into(), mysql);
+
+    let mut minio = Relation::default();
+    minio.juju.host = Some("minio".into());
+    minio.config.insert("port".into(), json!(9000));
+    state.relations.insert("minio".into(), minio);
+
+    state
+}
+
+#[test]
+fn render_template() {
+    let rune = Rune::load("../example-runes/pipelines-api/").unwrap();
+    let rendered = rune.render(&pipelines_api_state()).unwrap();
+
+    let environment = &rendered[0].environment;
+    assert_eq!(environment["MYSQL_SERVICE_HOST"], "mariadb");
+    assert_eq!(environment["MYSQL_SERVICE_PORT"], "3306");
+    assert_eq!(environment["MYSQL_SERVICE_PASS"], "hunter2");
+    assert_eq!(environment["MINIO_SERVICE_SERVICE_PORT"], "9000");
+    assert_eq!(environment["POD_NAMESPACE"], "kubeflow");
+    assert_eq!(environment["InitConnectionTimeout"], "6m");
+    assert_eq!(
+        rendered[0].ports[0].container_port,
+        TemplateInteger::Integer(8887)
+    );
+
+    // Secrets are only available as secrets, not as config
+    let rune = Rune::load("../example-runes/mariadb/").unwrap();
+    let mut state = State::default();
+    for key in &["database", "user", "port"] {
+        let value = rune.metadata.config[*key].default_value().unwrap();
+        state.config.insert(key.to_string(), value);
+    }
+    state
+        .secrets
+        .insert("root-password".into(), "generated".into());
+    state.secrets.insert("password".into(), "from-env".into());
+    let environment = &rune.render(&state).unwrap()[0].environment;
+    assert_eq!(environment["MYSQL_ROOT_PASSWORD"], "generated");
+    assert_eq!(environment["MYSQL_PASSWORD"], "from-env");
+    assert_eq!(environment["MYSQL_USER"], "mysql-user");
+
+    state.config.insert("password".into(), json!("from-config"));
+    state.secrets.remove("password");
+    match rune.render(&state) {
+        Err(Error::UnresolvedReferences(unresolved)) => assert_eq!(
+            unresolved,
+            vec![Unresolved {
+                field: "mariadb.environment.MYSQL_PASSWORD".into(),
+                reference: "state.secrets.password".into(),
+            }]
+        ),
+        other => panic!("Expected an unresolved secret, got {:?}", other),
+    }
+}
+
+#[test]
+fn render_unresolved() {
+    let rune = Rune::load("../example-runes/pipelines-api/").unwrap();
+    let mut state = pipelines_api_state();
+    state.relations.remove("minio");
+    state.config.remove("http-port");
+
+    let mut unresolved = match rune.render(&state) {
+        Err(Error::UnresolvedReferences(unresolved)) => unresolved,
+        other => panic!("Expected unresolved references, got {:?}", other),
+    };
+    unresolved.sort_by(|a, b| a.field.cmp(&b.field));
+    assert_eq!(
+        unresolved,
+        vec![
+            Unresolved {
+                field: "pipelines-api.environment.MINIO_SERVICE_SERVICE_HOST".into(),
+                reference: "state.relations.minio.juju.host".into(),
+            },
+            Unresolved {
+                field: "pipelines-api.environment.MINIO_SERVICE_SERVICE_PORT".into(),
+                reference: "state.relations.minio.config.port".into(),
+            },
+            Unresolved {
+                field: "pipelines-api.ports.http.containerPort".into(),
+                reference: "state.config.http_port".into(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn validate_config_references() {
+    let mut rune = Rune::load("../example-runes/pipelines-api/").unwrap();
+    rune.template[0]
+        .environment
+        .insert("GRPC".into(), "{{ state.config.grpc-port }}".into());
+    rune.validate().unwrap();
+
+    rune.template[0]
+        .environment
+        .insert("GRPC".into(), "{{ state.config.grpcport }}".into());
+    match rune.validate() {
+        Err(Error::UnknownConfigReferences(unknown)) => assert_eq!(
+            unknown,
+            vec![Unresolved {
+                field: "pipelines-api.environment.GRPC".into(),
+                reference: "state.config.grpcport".into(),
+            }]
+        ),
+        other => panic!("Expected unknown config references, got {:?}", other),
+    }
+
+    // Secrets and other config items can't stand in for each other
+    let mut mariadb = Rune::load("../example-runes/mariadb/").unwrap();
+    let environment = &mut mariadb.template[0].environment;
+    environment.insert("USER".into(), "{{ state.secrets.user }}".into());
+    environment.insert("PASSWORD".into(), "{{ state.config.password }}".into());
+    match mariadb.validate() {
+        Err(Error::UnknownConfigReferences(unknown)) => assert_eq!(
+            unknown
+                .iter()
+                .map(|r| r.reference.as_str())
+                .collect::<Vec<_>>(),
+            vec!["state.config.password", "state.secrets.user"]
+        ),
+        other => panic!("Expected unknown config references, got {:?}", other),
+    }
+
+    let item = rune.metadata.config["grpc-port"].clone();
+    rune.metadata.config.insert("grpc_port".into(), item);
+    match rune.validate() {
+        Err(Error::ConflictingConfigKeys(a, b)) => {
+            assert_eq!((a.as_str(), b.as_str()), ("grpc-port", "grpc_port"))
+        }
+        other => panic!("Expected conflicting config keys, got {:?}", other),
+    }
+}
+
+#[test]
+fn config_items() {
+    let items: BTreeMap<String, ConfigItem> = serde_yaml::from_str(
+        r#"
+offset:
+  type: integer
+  description: Can be negative, and has no default
+  min: -10
+  max: 10
+ratio:
+  type: float
+  description: A ratio
+  default: 1
+  max: 1.5
+name:
+  type: string
+  description: Lowercase only
+  default: mariadb
+  regex: "[a-z]+"
+  required: true
+hosts:
+  type: list
+  description: Hosts to allow
+  default: [localhost]
+labels:
+  type: map
+  description: Labels to add
+mode:
+  type: enum
+  description: Which mode to run in
+  values: [primary, replica]
+  default: primary
+"#,
+    )
+    .unwrap();
+
+    for (key, item) in &items {
+        item.check_declaration(key).unwrap();
+    }
+    assert_eq!(items["offset"].default_value(), None);
+    assert_eq!(items["ratio"].default_value(), Some(json!(1.0)));
+    assert_eq!(items["hosts"].default_value(), Some(json!(["localhost"])));
+    assert!(items["name"].required());
+    assert!(!items["offset"].required());
+
+    let parse =
+        |key: &str, value: &str| items[key].parse(key, value).map_err(|err| err.to_string());
+    assert_eq!(parse("offset", "-5"), Ok(json!(-5)));
+    assert_eq!(parse("ratio", "0.5"), Ok(json!(0.5)));
+    assert_eq!(parse("hosts", "[a, b]"), Ok(json!(["a", "b"])));
+    assert_eq!(
+        parse("labels", r#"{"app": "db"}"#),
+        Ok(json!({"app": "db"}))
+    );
+    assert_eq!(parse("mode", "replica"), Ok(json!("replica")));
+    assert_eq!(
+        parse("offset", "-11"),
+        Err("Config item offset must be at least -10, got -11".into())
+    );
+    assert_eq!(
+        parse("offset", "1.5"),
+        Err("Config item offset must be of type integer".into())
+    );
+    assert_eq!(
+        parse("ratio", "2"),
+        Err("Config item ratio must be at most 1.5, got 2".into())
+    );
+    assert_eq!(
+        parse("name", "MariaDB"),
+        Err(r#"Config item name must match [a-z]+, got "MariaDB""#.into())
+    );
+    assert_eq!(
+        parse("mode", "standby"),
+        Err(r#"Config item mode must be one of primary, replica, got "standby""#.into())
+    );
+    assert_eq!(
+        parse("hosts", "{}"),
+        Err("Config item hosts must be of type list".into())
+    );
+
+    // Defaults have to meet their own constraints
+    let mut rune = Rune::load("../example-runes/mariadb/").unwrap();
+    rune.metadata.config.insert(
+        "mode".into(),
+        serde_yaml::from_str("{type: enum, description: Mode, values: [a], default: b}").unwrap(),
+    );
+    assert_eq!(
+        rune.validate().unwrap_err().to_string(),
+        r#"Config item mode must be one of a, got "b""#
+    );
+    rune.metadata.config.insert(
+        "mode".into(),
+        serde_yaml::from_str("{type: string, description: Mode, regex: '['}").unwrap(),
+    );
+    assert!(matches!(
+        rune.validate(),
+        Err(Error::InvalidConfigValue { key, .. }) if key == "mode"
+    ));
+
+    // Required items need a value, whether or not they have a default
+    rune.metadata.config = items;
+    let mut config: BTreeMap<String, Value> = rune
+        .metadata
+        .config
+        .iter()
+        .filter_map(|(key, item)| Some((key.clone(), item.default_value()?)))
+        .collect();
+    rune.check_config(&config).unwrap();
+    config.remove("name");
+    assert_eq!(
+        rune.check_config(&config).unwrap_err().to_string(),
+        "Config item name is required"
+    );
+    config.insert("name".into(), json!("mariadb"));
+    config.insert("colour".into(), json!("blue"));
+    assert_eq!(
+        rune.check_config(&config).unwrap_err().to_string(),
+        "Config item colour isn't declared"
+    );
+    // Batches of changes are checked as a whole, and resets go back to defaults
+    let reset = |keys: &[&str]| keys.iter().map(|k| k.to_string()).collect::<Vec<_>>();
+    let mut changes = BTreeMap::new();
+    changes.insert("offset".to_string(), json!("-3"));
+    assert_eq!(
+        rune.config_changes(changes.clone(), &reset(&["ratio", "labels"]))
+            .unwrap(),
+        vec![
+            ("labels".to_string(), None),
+            ("offset".to_string(), Some(json!(-3))),
+            ("ratio".to_string(), Some(json!(1.0))),
+        ]
+        .into_iter()
+        .collect()
+    );
+    assert_eq!(
+        rune.config_changes(changes.clone(), &reset(&["offset"]))
+            .unwrap_err()
+            .to_string(),
+        "Config item offset can't be both set and reset"
+    );
+    rune.metadata.config.insert(
+        "labels".into(),
+        serde_yaml::from_str("{type: map, description: Labels, required: true}").unwrap(),
+    );
+    assert_eq!(
+        rune.config_changes(changes, &reset(&["labels"]))
+            .unwrap_err()
+            .to_string(),
+        "Config item labels is required, and has no default to reset to"
+    );
+}
+
+#[test]
+fn secret_sources() {
+    let item = |yaml: &str| serde_yaml::from_str::<ConfigItem>(yaml).unwrap();
+    let mut rune = Rune::load("../example-runes/mariadb/").unwrap();
+
+    // Generated secrets can be given a length and alphabet
+    rune.metadata.config.insert(
+        "token".into(),
+        item(
+            "{type: secret, description: Token, source: {type: generate, length: 8, alphabet: ab}}",
+        ),
+    );
+    rune.secrets.insert("root-password".into(), "given".into());
+    rune.generate_secrets();
+    assert_eq!(rune.secrets["root-password"], "given");
+    assert_eq!(rune.secrets["token"].len(), 8);
+    assert!(rune.secrets["token"].chars().all(|c| c == 'a' || c == 'b'));
+    assert!(!rune.secrets.contains_key("password"));
+    assert_eq!(
+        item("{type: secret, description: Token, source: {type: generate, alphabet: ''}}")
+            .check_declaration("token")
+            .unwrap_err()
+            .to_string(),
+        "Config item token can't generate secrets from an empty alphabet"
+    );
+
+    // Env file secrets are read on the client, by default from the variable
+    // named after the key
+    let dir = tempfile::tempdir().unwrap();
+    assert!(resolve_env(&rune, dir.path())
+        .unwrap_err()
+        .to_string()
+        .starts_with("Can't resolve secret password: can't read .env: "));
+    write(dir.path().join(".env"), "# Database\nUSER=admin\n").unwrap();
+    assert_eq!(
+        resolve_env(&rune, dir.path()).unwrap_err().to_string(),
+        "Can't resolve secret password: PASSWORD isn't set in .env"
+    );
+    write(
+        dir.path().join(".env"),
+        "# Database\n\nexport PASSWORD=\"hunter 2\"\nUSER=admin\n",
+    )
+    .unwrap();
+    let secrets = resolve_env(&rune, dir.path()).unwrap();
+    assert_eq!(secrets.len(), 1);
+    assert_eq!(secrets["password"], "hunter 2");
+
+    rune.metadata.config.insert(
+        "password".into(),
+        item("{type: secret, description: Password, source: {type: env, file: .env, variable: USER}}"),
+    );
+    assert_eq!(resolve_env(&rune, dir.path()).unwrap()["password"], "admin");
+    rune.check_secrets(&secrets).unwrap();
+    let mut secrets = secrets;
+    secrets.insert("user".into(), "admin".into());
+    assert_eq!(
+        rune.check_secrets(&secrets).unwrap_err().to_string(),
+        "Config item user isn't a secret"
+    );
+}
+
+#[test]
+fn lint_rune() {
+    let rune = Rune::read("../example-runes/pipelines-ui/").unwrap();
+    let errors: Vec<_> = rune
+        .lint()
+        .into_iter()
+        .filter(|p| p.severity == Severity::Error)
+        .collect();
+    assert_eq!(errors, vec![]);
+
+    let mut rune = Rune::read("../example-runes/mariadb/").unwrap();
+    rune.metadata.react = Some("react_missing".into());
+    rune.metadata.series.push("openstack".into());
+    let port = rune.template[0].ports[0].clone();
+    rune.template[0].ports.push(port);
+    rune.template[0].environment.insert(
+        "PEER".into(),
+        "{{ state.relations.galera.juju.host }}".into(),
+    );
+    rune.template[0]
+        .environment
+        .insert("MISSING".into(), "{{ state.config.nope }}".into());
+    rune.template[0]
+        .environment
+        .insert("SECRET".into(), "{{ state.config.root-password }}".into());
+
+    let messages: Vec<_> = rune.lint().iter().map(ToString::to_string).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "rune.yaml: error: mariadb.environment.MISSING refers to undeclared config item nope",
+            "rune.yaml: error: mariadb.environment.PEER refers to relation galera, which isn't in requires",
+            "rune.yaml: error: mariadb.environment.SECRET refers to secret root-password as config, rather than as state.secrets.root_password",
+            "rune.yaml: error: mariadb has more than one port named mariadb",
+            "rune.py: error: React handler react_missing isn't defined",
+            "metadata.yaml: warning: Series openstack has no include support in any cloud",
+        ]
+    );
+}
+
+#[test]
+fn render_ports() {
+    let mut rune = Rune::load("../example-runes/pipelines-api/").unwrap();
+    let mut state = pipelines_api_state();
+    state.config.insert("http-port".into(), json!(70000));
+    match rune.render(&state) {
+        Err(Error::InvalidPort {
+            rune,
+            container,
+            port,
+            value,
+        }) => assert_eq!(
+            (rune, container, port, value),
+            (
+                "pipelines-api".into(),
+                "pipelines-api".into(),
+                "http".into(),
+                "70000".into()
+            )
+        ),
+        other => panic!("Expected an invalid port, got {:?}", other),
+    }
+
+    rune.metadata.config.insert(
+        "http-port".into(),
+        serde_yaml::from_str("{type: string, default: '8888', description: HTTP port}").unwrap(),
+    );
+    match rune.validate() {
+        Err(Error::ConfigTypeMismatch {
+            field,
+            key,
+            expected,
+        }) => {
+            assert_eq!(field, "pipelines-api.ports.http.containerPort");
+            assert_eq!(key, "http-port");
+            assert_eq!(expected, "integer");
+        }
+        other => panic!("Expected a config type mismatch, got {:?}", other),
+    }
+}
+
+#[test]
+fn rune_versions() {
+    let dir = tempfile::tempdir().unwrap();
+    copy(
+        "../example-runes/pipelines-ui/rune.yaml",
+        dir.path().join("rune.yaml"),
+    )
+    .unwrap();
+    let metadata = read_to_string("../example-runes/pipelines-ui/metadata.yaml").unwrap();
+
+    // Quoted and unquoted versions are the same, and runes already in the
+    // latest format are left as they are
+    write(
+        dir.path().join("metadata.yaml"),
+        metadata.replace("version: 2", "version: '2'"),
+    )
+    .unwrap();
+    assert_eq!(Rune::read(dir.path()).unwrap().metadata.version, "2");
+    assert_eq!(migrate(dir.path()).unwrap().version, LATEST);
+    assert_eq!(
+        read_to_string(dir.path().join("metadata.yaml")).unwrap(),
+        metadata.replace("version: 2", "version: '2'")
+    );
+
+    write(
+        dir.path().join("metadata.yaml"),
+        metadata.replace("version: 2", "version: 99"),
+    )
+    .unwrap();
+    match Rune::load(dir.path()) {
+        Err(Error::InvalidRune(diagnostics)) => assert_eq!(
+            diagnostics[0].to_string(),
+            "metadata.yaml: version: Unsupported rune format version 99"
+        ),
+        other => panic!("Expected an unsupported version, got {:?}", other),
+    }
+    match Rune::unzip(
+        &Rune::load("../example-runes/pipelines-ui/")
+            .unwrap()
+            .zip()
+            .unwrap(),
+    ) {
+        Ok(rune) => assert_eq!(rune.metadata.version, "2"),
+        other => panic!("Expected the rune to unzip, got {:?}", other),
+    }
+}
+
+#[test]
+fn migrate_secret_references() {
+    // Version 1 templates referred to secrets as config
+    let dir = tempfile::tempdir().unwrap();
+    let metadata = read_to_string("../example-runes/mariadb/metadata.yaml").unwrap();
+    let template = read_to_string("../example-runes/mariadb/rune.yaml").unwrap();
+    write(
+        dir.path().join("metadata.yaml"),
+        metadata.replace("version: 2", "version: 1"),
+    )
+    .unwrap();
+    write(
+        dir.path().join("rune.yaml"),
+        template.replace("state.secrets.", "state.config."),
+    )
+    .unwrap();
+
+    // Loading upgrades them in memory, so they parse as the latest format
+    let rune = Rune::read(dir.path()).unwrap();
+    let latest = Rune::load("../example-runes/mariadb/").unwrap();
+    assert_eq!(rune.metadata.version, "2");
+    assert_eq!(rune.template, latest.template);
+    rune.validate().unwrap();
+    let environment = &rune.template[0].environment;
+    assert_eq!(
+        environment["MYSQL_ROOT_PASSWORD"],
+        "{{ state.secrets.root_password }}"
+    );
+    assert_eq!(
+        environment["MYSQL_PASSWORD"],
+        "{{ state.secrets.password }}"
+    );
+    assert_eq!(environment["MYSQL_DATABASE"], "{{ state.config.database }}");
+
+    // Migrating rewrites the files in place, keeping their comments and
+    // layout, after which there's nothing left to do
+    let migrated = migrate(dir.path()).unwrap();
+    assert_eq!(migrated.version, 1);
+    assert!(migrated.reformatted.is_empty());
+    assert!(metadata.contains('#'));
+    assert_eq!(
+        read_to_string(dir.path().join("metadata.yaml")).unwrap(),
+        metadata
+    );
+    assert_eq!(
+        read_to_string(dir.path().join("rune.yaml")).unwrap(),
+        template
+    );
+    assert_eq!(Rune::read(dir.path()).unwrap(), rune);
+    assert_eq!(migrate(dir.path()).unwrap().version, LATEST);
+
+    // Metadata whose version can't be found in its text is re-serialized
+    write(
+        dir.path().join("metadata.yaml"),
+        "{version: 1, name: mariadb}",
+    )
+    .unwrap();
+    let migrated = migrate(dir.path()).unwrap();
+    assert_eq!(migrated.reformatted, vec!["metadata.yaml".to_string()]);
+    let metadata: serde_yaml::Value =
+        serde_yaml::from_str(&read_to_string(dir.path().join("metadata.yaml")).unwrap()).unwrap();
+    assert_eq!(metadata["version"], serde_yaml::Value::from(LATEST));
+}
+
+#[test]
+fn migrate_relation_references() {
+    // Version 1 templates got related runes' secrets as config, and which of
+    // their config items are secrets can't be told from this rune
+    let dir = tempfile::tempdir().unwrap();
+    let metadata = read_to_string("../example-runes/pipelines-api/metadata.yaml").unwrap();
+    let template = read_to_string("../example-runes/pipelines-api/rune.yaml").unwrap();
+    write(
+        dir.path().join("metadata.yaml"),
+        metadata.replace("version: 2", "version: 1"),
+    )
+    .unwrap();
+    write(
+        dir.path().join("rune.yaml"),
+        template.replace(".secrets.password", ".config.password"),
+    )
+    .unwrap();
+
+    for result in [
+        Rune::read(dir.path()).map(|_| ()),
+        migrate(dir.path()).map(|_| ()),
+    ] {
+        match result {
+            Err(Error::RelationConfigReferences(refs)) => assert_eq!(
+                refs,
+                vec![
+                    "state.relations.minio.config.port",
+                    "state.relations.mysql.config.password",
+                    "state.relations.mysql.config.port",
+                    "state.relations.mysql.config.user",
+                ]
+            ),
+            other => panic!("Expected relation config references, got {:?}", other),
+        }
+    }
+    assert_eq!(
+        read_to_string(dir.path().join("rune.yaml")).unwrap(),
+        template.replace(".secrets.password", ".config.password"),
+    );
+
+    // Relations that aren't in requires are caught before deploying
+    let mut rune = Rune::load("../example-runes/pipelines-api/").unwrap();
+    rune.metadata.requires.retain(|r| r.name != "minio");
+    match rune.validate() {
+        Err(Error::UndeclaredRelations(refs)) => {
+            assert_eq!(refs.len(), 2);
+            assert!(refs
+                .iter()
+                .all(|r| r.reference.starts_with("state.relations.minio.")));
+        }
+        other => panic!("Expected undeclared relations, got {:?}", other),
+    }
+}
+
+#[test]
+fn rune_schemas() {
+    let metadata = serde_json::to_value(schema::metadata()).unwrap();
+    assert_eq!(metadata["additionalProperties"], json!(false));
+    assert_eq!(
+        metadata["properties"]["version"]["type"],
+        json!(["integer", "string"])
+    );
+
+    // Every example rune only uses fields the schema knows about
+    let yaml = read_to_string("../example-runes/mariadb/metadata.yaml").unwrap();
+    let example: serde_json::Value = serde_yaml::from_str(&yaml).unwrap();
+    for key in example.as_object().unwrap().keys() {
+        assert!(metadata["properties"].get(key).is_some(), "{}", key);
+    }
+
+    let config_types: Vec<_> = metadata["definitions"]["ConfigItem"]["oneOf"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["properties"]["type"]["enum"][0].clone())
+        .collect();
+    assert_eq!(
+        config_types,
+        vec![
+            json!("boolean"),
+            json!("integer"),
+            json!("float"),
+            json!("string"),
+            json!("list"),
+            json!("map"),
+            json!("enum"),
+            json!("secret"),
+            json!("archive")
+        ]
+    );
+
+    let template = serde_json::to_value(schema::template()).unwrap();
+    assert_eq!(template["type"], json!("array"));
+    assert_eq!(template["items"]["$ref"], json!("#/definitions/Template"));
+    assert!(template["definitions"]["Template"]["properties"]
+        .get("include")
+        .is_some());
+}
+
+#[test]
+fn load_diagnostics() {
+    let dir = tempfile::tempdir().unwrap();
+    let metadata = read_to_string("../example-runes/mariadb/metadata.yaml").unwrap();
+    write(
+        dir.path().join("metadata.yaml"),
+        metadata.replace(
+            "    description: Database name",
+            "    description: Database name\n    colour: blue",
+        ),
+    )
+    .unwrap();
+
+    // Both files are reported, even though one of them is missing
+    match Rune::load(dir.path()) {
+        Err(Error::InvalidRune(diagnostics)) => {
+            assert_eq!(
+                diagnostics
+                    .iter()
+                    .map(|d| (d.file.as_str(), d.line, d.key.as_deref()))
+                    .collect::<Vec<_>>(),
+                vec![
+                    ("metadata.yaml", Some(34), Some("config.colour")),
+                    ("rune.yaml", None, None),
+                ]
+            );
+            assert!(diagnostics[0].message.starts_with("unknown field `colour`"));
+        }
+        other => panic!("Expected diagnostics, got {:?}", other),
+    }
+
+    copy(
+        "../example-runes/mariadb/metadata.yaml",
+        dir.path().join("metadata.yaml"),
+    )
+    .unwrap();
+    write(
+        dir.path().join("rune.yaml"),
+        "- name: mariadb\n  ports: []\n",
+    )
+    .unwrap();
+    let err = Rune::load(dir.path()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "rune.yaml:1:7: [0].image: missing field `image`"
+    );
+
+    write(dir.path().join("rune.yaml"), "- name: [mariadb\n").unwrap();
+    let err = Rune::load(dir.path()).unwrap_err();
+    assert_eq!(
+        err.to_string(),
+        "rune.yaml:2:1: while parsing a flow sequence, expected ',' or ']'"
+    );
+}
+
+#[test]
+fn signed_archives() {
+    let rune = Rune::load("../example-runes/mariadb/").unwrap();
+    let key = signature::generate_key();
+    let other = signature::generate_key();
+
+    let signed = rune.zip_signed(&key).unwrap();
+    assert_eq!(
+        signature::verify_archive(&signed, &[other.public, key.public]).unwrap(),
+        key.public
+    );
+    assert_eq!(Rune::unzip(&signed).unwrap(), rune);
+
+    match signature::verify_archive(&signed, &[other.public]) {
+        Err(Error::UntrustedKey(k)) => assert_eq!(k, hex::encode(key.public.as_bytes())),
+        other => panic!("Expected an untrusted key, got {:?}", other),
+    }
+    match signature::verify_archive(&rune.zip().unwrap(), &[key.public]) {
+        Err(Error::Unsigned) => {}
+        other => panic!("Expected an unsigned rune, got {:?}", other),
+    }
+
+    // Changing any file after signing invalidates the signature
+    let mut entries = archive::read(&signed).unwrap();
+    entries.insert("build/extra".into(), b"extra".to_vec());
+    match signature::verify(&entries, &[key.public]) {
+        Err(Error::InvalidSignature) => {}
+        other => panic!("Expected an invalid signature, got {:?}", other),
+    }
+
+    // Keys round trip through their hex encoding
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("key");
+    write(&path, hex::encode(key.secret.as_bytes())).unwrap();
+    assert_eq!(
+        signature::read_key(&path).unwrap().public,
+        signature::public_key(&hex::encode(key.public.as_bytes())).unwrap()
+    );
+}
+
+#[test]
+fn reproducible_archives() {
+    let rune = Rune::load("../example-runes/mariadb/").unwrap();
+    let zipped = rune.zip().unwrap();
+    assert_eq!(
+        Rune::load("../example-runes/mariadb/")
+            .unwrap()
+            .zip()
+            .unwrap(),
+        zipped
+    );
+
+    let unzipped = Rune::unzip(&zipped).unwrap();
+    assert_eq!(unzipped.zip().unwrap(), zipped);
+    assert_eq!(unzipped.digest().unwrap(), rune.digest().unwrap());
+
+    // The manifest lists every file, and the digest is the manifest's digest
+    let entries = archive::read(&zipped).unwrap();
+    let manifest = String::from_utf8(entries["MANIFEST"].clone()).unwrap();
+    assert_eq!(
+        manifest.lines().map(|l| &l[66..]).collect::<Vec<_>>(),
+        vec![
+            "build/Dockerfile",
+            "build/docker-entrypoint.sh",
+            "metadata.yaml",
+            "rune.py",
+            "rune.yaml"
+        ]
+    );
+    assert_eq!(
+        rune.digest().unwrap(),
+        format!(
+            "sha256:{}",
+            hex::encode(Sha256::digest(manifest.as_bytes()))
+        )
+    );
+
+    // Archives whose files don't match their manifest are rejected
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+    for (name, contents) in &entries {
+        writer
+            .start_file(name.as_str(), Default::default())
+            .unwrap();
+        match name.as_str() {
+            "rune.py" => writer.write_all(b"# changed").unwrap(),
+            _ => writer.write_all(contents).unwrap(),
+        }
+    }
+    let tampered = writer.finish().unwrap().into_inner();
+    match Rune::unzip(&tampered) {
+        Err(Error::ManifestMismatch) => {}
+        other => panic!("Expected a manifest mismatch, got {:?}", other),
+    }
+}
+
+#[test]
+fn encoded_archives() {
+    let zipped = Rune::load("../example-runes/mariadb/")
+        .unwrap()
+        .zip()
+        .unwrap();
+    let pulled = RuneArchive {
+        name: "mariadb".into(),
+        revision: 1,
+        digest: archive::digest(&archive::read(&zipped).unwrap()),
+        archive: zipped.clone(),
+    };
+
+    // Archives travel as base64 rather than as arrays of bytes
+    let mut value = serde_json::to_value(&pulled).unwrap();
+    assert_eq!(value["archive"], json!(base64::encode(&zipped)));
+    assert_eq!(
+        serde_json::from_value::<RuneArchive>(value.clone()).unwrap(),
+        pulled
+    );
+    let upload = serde_json::to_value(&RuneUpload {
+        name: "mariadb".into(),
+        rune: zipped.clone(),
+        tag: None,
+    })
+    .unwrap();
+    assert_eq!(upload["rune"], value["archive"]);
+
+    // Archives stored as arrays of bytes are still read
+    value["archive"] = json!(zipped);
+    assert_eq!(
+        serde_json::from_value::<RuneArchive>(value).unwrap(),
+        pulled
+    );
+}
@@ -0,0 +1,132 @@
+use liburuz::rune::v1::Rune;
+use liburuz::server::crypto::Cipher;
+use liburuz::server::error::Error;
+use liburuz::server::model::{Action, Queued};
+use liburuz::server::store::RuneStore;
+use std::collections::{BTreeMap, VecDeque};
+
+fn cipher(key: u8, previous: &[u8]) -> Cipher {
+    let hex = |key: &u8| hex::encode([*key; 32]);
+    let previous: Vec<_> = previous.iter().map(hex).collect();
+    let previous: Vec<_> = previous.iter().map(String::as_str).collect();
+    Cipher::from_hex(Some(&hex(&key)), &previous).unwrap()
+}
+
+#[test]
+fn store_runes_by_digest() {
+    let dir = tempfile::tempdir().unwrap();
+    let database = sled::open(dir.path().join("uruz.sled")).unwrap();
+    let store = RuneStore::new(&database, cipher(1, &[])).unwrap();
+
+    let rune = Rune::load("../example-runes/mariadb/").unwrap();
+    let mut deployed = rune.clone();
+    deployed
+        .images
+        .insert("mariadb".into(), "localhost/mariadb@sha256:1234".into());
+    deployed
+        .secrets
+        .insert("root-password".into(), "generated".into());
+
+    // The same rune added twice, even with different images and secrets, is
+    // stored once
+    let backlog: VecDeque<_> = vec![
+        Queued::from_action(
+            Action::AddRune {
+                name: "first".into(),
+                rune: rune.clone(),
+            },
+            0,
+        ),
+        Queued::from_action(
+            Action::AddRune {
+                name: "second".into(),
+                rune: deployed.clone(),
+            },
+            1,
+        ),
+    ]
+    .into_iter()
+    .collect();
+
+    let bytes = store.to_vec(&backlog).unwrap();
+    assert_eq!(store.len(), 1);
+    assert_eq!(store.get(&rune.digest().unwrap()).unwrap(), rune);
+    assert!(bytes.len() < rune.zip().unwrap().len());
+
+    let loaded: VecDeque<Queued> = store.from_slice(&bytes).unwrap();
+    assert_eq!(loaded, backlog);
+}
+
+#[test]
+fn encrypt_secrets_at_rest() {
+    let dir = tempfile::tempdir().unwrap();
+    let database = sled::open(dir.path().join("uruz.sled")).unwrap();
+    let store = RuneStore::new(&database, cipher(1, &[])).unwrap();
+
+    let mut rune = Rune::load("../example-runes/mariadb/").unwrap();
+    rune.secrets
+        .insert("root-password".into(), "generated-password".into());
+    let mut secrets = BTreeMap::new();
+    secrets.insert(
+        "password".to_string(),
+        Some("configured-password".to_string()),
+    );
+    let history = vec![
+        Queued::from_action(
+            Action::AddRune {
+                name: "mariadb".into(),
+                rune: rune.clone(),
+            },
+            0,
+        ),
+        Queued::from_action(
+            Action::ConfigureRune {
+                name: "mariadb".into(),
+                config: BTreeMap::new(),
+                secrets,
+            },
+            1,
+        ),
+    ];
+
+    // Secrets never appear in what's stored, but load as they were
+    let bytes = store.to_vec(&history).unwrap();
+    let stored = String::from_utf8_lossy(&bytes);
+    assert!(!stored.contains("generated-password"));
+    assert!(!stored.contains("configured-password"));
+    assert_eq!(store.from_slice::<Vec<Queued>>(&bytes).unwrap(), history);
+
+    // After rotating keys, secrets encrypted with the previous key still load,
+    // and once re-encrypted with the new key, the previous one is no longer
+    // needed, nor of any use
+    let rotated = RuneStore::new(&database, cipher(2, &[1])).unwrap();
+    assert_eq!(rotated.from_slice::<Vec<Queued>>(&bytes).unwrap(), history);
+    let bytes = rotated.to_vec(&history).unwrap();
+    let dropped = RuneStore::new(&database, cipher(2, &[])).unwrap();
+    assert_eq!(dropped.from_slice::<Vec<Queued>>(&bytes).unwrap(), history);
+    assert!(matches!(
+        store.from_slice::<Vec<Queued>>(&bytes),
+        Err(Error::DecryptionFailed)
+    ));
+
+    // Without a master key, only requests without secrets can be stored
+    let keyless = RuneStore::new(&database, Cipher::from_hex(None, &[]).unwrap()).unwrap();
+    assert!(matches!(
+        keyless.to_vec(&history),
+        Err(Error::MasterKeyMissing)
+    ));
+    assert!(matches!(
+        keyless.from_slice::<Vec<Queued>>(&bytes),
+        Err(Error::MasterKeyMissing)
+    ));
+    rune.secrets.clear();
+    let backlog = vec![Queued::from_action(
+        Action::AddRune {
+            name: "mariadb".into(),
+            rune,
+        },
+        2,
+    )];
+    let bytes = keyless.to_vec(&backlog).unwrap();
+    assert_eq!(keyless.from_slice::<Vec<Queued>>(&bytes).unwrap(), backlog);
+}
@@ -1,35 +1,149 @@
+mod fakes;
+
+use async_std::task;
+use fakes::aws::FakeCloudFormation;
+use fakes::docker::write_docker;
+use fakes::gce::FakeDeploymentManager;
+use fakes::kubernetes::FakeKubernetes;
+use fakes::registry::FakeRegistry;
 use futures::join;
-use liburuz::api::v1::{Action, ModelConfig, ModelConfigure, ModelCreate, RuneConfigure};
+use liburuz::api::v1::{
+    Action, CloudCredentials, DummyConfig, DummyEvent, ErrorMessage, ModelConfig, ModelConfigure,
+    ModelCreate, RegisteredCloud, RuneConfigure, RuneSource, RuneUpload, REDACTED,
+};
 use liburuz::client::api::v1::Client;
-use liburuz::clouds::Cloud;
+use liburuz::client::error::Error as ClientError;
+use liburuz::rune::v1::archive;
 use liburuz::rune::v1::Rune;
-use liburuz::server::{config::Config, start};
-use std::collections::HashMap;
+use liburuz::server::config::{
+    AwsConfig, Config, GceConfig, ImagesConfig, KubernetesConfig, LocalConfig, RetriesConfig,
+};
+use liburuz::server::repository::Selector;
+use liburuz::server::start;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{read_to_string, write};
+use std::io::{Cursor, Write};
+use std::path::{Path, PathBuf};
 use std::thread::sleep;
 use std::time::Duration;
 use tokio::runtime::Runtime;
+use zip::write::{FileOptions, ZipWriter};
 
 static URL: &'static str = "http://localhost:8000";
+static SECRETS_TOKEN: &'static str = "secrets-token";
 
 #[test]
 fn test_main() {
     let tempdir = tempfile::tempdir().unwrap();
-    let tempdir = tempdir.path().to_str().unwrap().to_string();
+    let database_path = tempdir.path().join("uruz.sled");
+    let database_path = database_path.to_str().unwrap().to_string();
+    let kubeconfig = tempdir.path().join("kubeconfig");
+    let kubeconfig = kubeconfig.to_str().unwrap().to_string();
+    let local_dir = tempdir.path().join("local");
+    let master_key = tempdir.path().join("master.key");
+    write(&master_key, "00".repeat(32)).unwrap();
+    let mut rt = Runtime::new().unwrap();
+
+    // Start fake Kubernetes API server
+    let kubernetes = FakeKubernetes::default();
+    let (addr, fake) = rt.enter(|| kubernetes.serve());
+    rt.spawn(fake);
+    kubernetes.write_kubeconfig(addr, &kubeconfig);
+
+    // Start fake CloudFormation API server
+    let cloudformation = FakeCloudFormation::default();
+    let (addr, fake) = rt.enter(|| cloudformation.serve());
+    rt.spawn(fake);
+
+    // Start fake Deployment Manager API server
+    let deploymentmanager = FakeDeploymentManager::default();
+    let (gce_addr, fake) = rt.enter(|| deploymentmanager.serve());
+    rt.spawn(fake);
+
+    // Start fake container registry, and write a fake docker to push to it
+    let registry = FakeRegistry::default();
+    let (registry_addr, fake) = rt.enter(|| registry.serve());
+    rt.spawn(fake);
+    let (docker, docker_log) = write_docker(tempdir.path());
+
     let config = Config {
-        database_path: tempdir,
+        database_path,
         api_host: [0, 0, 0, 0],
         api_port: 8000,
+        master_key_path: Some(master_key.to_str().unwrap().into()),
+        secrets_token: Some(SECRETS_TOKEN.into()),
+        previous_master_key_paths: vec![],
+        aws: AwsConfig {
+            endpoint: Some(format!("http://{}", addr)),
+            ..Default::default()
+        },
+        gce: GceConfig {
+            endpoint: Some(format!("http://{}", gce_addr)),
+            ..Default::default()
+        },
+        images: ImagesConfig {
+            registry: Some(format!("http://{}", registry_addr)),
+            docker: Some(docker.to_str().unwrap().into()),
+            ..Default::default()
+        },
+        kubernetes: KubernetesConfig {
+            kubeconfig: Some(kubeconfig.clone()),
+            context: Some("fake".into()),
+        },
+        local: LocalConfig {
+            working_dir: local_dir.to_str().unwrap().into(),
+            binaries: vec![("local-rune".into(), "/bin/sh".into())]
+                .into_iter()
+                .collect(),
+        },
+        retries: RetriesConfig {
+            attempts: 4,
+            backoff_ms: 100,
+            max_backoff_ms: 1000,
+        },
+        signatures: Default::default(),
     };
-    let mut rt = Runtime::new().unwrap();
 
-    // Start server
-    rt.spawn(start(config));
+    // Start server, on its own runtime so that it can be restarted
+    let server = Runtime::new().unwrap();
+    server.spawn(start(config.clone()));
 
     // Wait a bit for server to boot up before running tests
     sleep(Duration::from_secs(1));
 
     // Run tests
-    rt.block_on(async { join!(test_model_config(), test_runes()) });
+    rt.block_on(async {
+        join!(
+            test_model_config(),
+            test_runes(registry.clone(), registry_addr.to_string(), docker_log),
+            test_repository(),
+            test_kubernetes(kubernetes.clone()),
+            test_aws(cloudformation.clone()),
+            test_gce(deploymentmanager.clone()),
+            test_local(local_dir.clone()),
+            test_registered_clouds(kubernetes.clone(), read_to_string(&kubeconfig).unwrap()),
+            test_dummy()
+        )
+    });
+
+    // Local processes don't outlive the server, but are started again along
+    // with it
+    let (model_id, pid_path) = rt.block_on(deploy_restored(local_dir.clone()));
+    let pid = rt.block_on(read_pid(&pid_path, None));
+    drop(server);
+    let server = Runtime::new().unwrap();
+    server.spawn(start(config));
+    sleep(Duration::from_secs(1));
+    rt.block_on(async {
+        let restarted = read_pid(&pid_path, Some(&pid)).await;
+        assert!(PathBuf::from("/proc").join(&restarted).exists());
+        Client::new(URL)
+            .destroy_model_wait(&model_id)
+            .await
+            .unwrap();
+        assert!(!PathBuf::from("/proc").join(&restarted).exists());
+    });
 }
 
 async fn test_model_config() {
@@ -37,11 +151,42 @@ async fn test_model_config() {
     let model = client
         .create_model(&ModelCreate {
             name: "test-model-config".into(),
-            cloud: Cloud::Dummy,
+            cloud: "dummy".into(),
         })
         .await
         .unwrap();
 
+    // Model names are unique
+    match client
+        .create_model(&ModelCreate {
+            name: "test-model-config".into(),
+            cloud: "dummy".into(),
+        })
+        .await
+    {
+        Err(ClientError::BadRequest(message)) => {
+            assert_eq!(message, "A model named test-model-config already exists")
+        }
+        other => panic!("Expected a duplicate name to be rejected, got {:?}", other),
+    }
+
+    // Names can end up in paths, so can't have separators in them
+    assert!(matches!(
+        client
+            .create_model(&ModelCreate {
+                name: "../escape".into(),
+                cloud: "dummy".into(),
+            })
+            .await,
+        Err(ClientError::BadRequest(_))
+    ));
+    assert!(matches!(
+        client
+            .add_rune(&model.id, "../../etc", &pipelines_api())
+            .await,
+        Err(ClientError::BadRequest(_))
+    ));
+
     // Configure model
     // Starts off with the default, then ensure we change it
     assert_eq!(model.state.config, ModelConfig { foo: None });
@@ -116,14 +261,24 @@ async fn test_model_config() {
         )
         .await
         .is_err());
+
+    // But its name can be given to a new model
+    let model = client
+        .create_model(&ModelCreate {
+            name: "test-model-config".into(),
+            cloud: "dummy".into(),
+        })
+        .await
+        .unwrap();
+    client.destroy_model_wait(&model.id).await.unwrap();
 }
 
-async fn test_runes() {
+async fn test_runes(registry: FakeRegistry, registry_host: String, docker_log: PathBuf) {
     let client = Client::new(URL);
     let model = client
         .create_model(&ModelCreate {
             name: "test-runes".into(),
-            cloud: Cloud::Dummy,
+            cloud: "dummy".into(),
         })
         .await
         .unwrap();
@@ -135,29 +290,892 @@ async fn test_runes() {
 
     let model = client.get_model(&model.id).await.unwrap();
     let mariadb = model.state.runes.get("mariadb").unwrap();
-    let mut expected: HashMap<String, Option<String>> = HashMap::new();
+    let mut expected: HashMap<String, Option<Value>> = HashMap::new();
     expected.insert("database".into(), Some("mysql-db".into()));
     expected.insert("user".into(), Some("mysql-user".into()));
-    expected.insert("password".into(), None);
-    expected.insert("root-password".into(), None);
+    expected.insert("port".into(), Some(json!(3306)));
     assert_eq!(mariadb.state, expected);
 
+    // Secrets are kept apart from the rest of the config and redacted, and
+    // the root password is generated when the rune is deployed
+    assert_eq!(mariadb.secrets["root-password"], Some(REDACTED.into()));
+    assert_eq!(mariadb.secrets["password"], None);
+    let read_secrets = |name: &'static str, token: &'static str| {
+        let model_id = model.id.clone();
+        let client = &client;
+        async move { client.get_rune_secrets(&model_id, name, token).await }
+    };
+    let root_password = read_secrets("mariadb", SECRETS_TOKEN).await.unwrap()["root-password"]
+        .clone()
+        .unwrap();
+    assert_eq!(root_password.len(), 32);
+    assert!(root_password.chars().all(|c| c.is_ascii_alphanumeric()));
+    match read_secrets("mariadb", "wrong").await {
+        Err(ClientError::Unauthorized(message)) => assert_eq!(
+            message,
+            "Reading secrets requires the controller's secrets token"
+        ),
+        other => panic!("Expected to be unauthorized, got {:?}", other),
+    }
+
+    // The mariadb image is built from its Dockerfile and pushed
+    let image = &mariadb.images["mariadb"];
+    let tag = format!("{}/mariadb/mariadb", registry_host);
+    assert_eq!(image, &format!("{}@sha256:{}", tag, "0".repeat(64)));
+    let commands = read_to_string(&docker_log).unwrap();
+    let pushed = format!("push {}:latest", tag);
+    assert!(commands.lines().any(|command| command == pushed));
+
+    // Contexts without a Dockerfile are packaged as they are instead, with
+    // scripts kept executable
+    let packaged = client
+        .create_model(&ModelCreate {
+            name: "test-runes-packaged".into(),
+            cloud: "dummy".into(),
+        })
+        .await
+        .unwrap();
+    let mut unbuilt = rune.clone();
+    unbuilt.files.remove("build/Dockerfile");
+    client
+        .add_rune_wait(&packaged.id, "packaged", &unbuilt)
+        .await
+        .unwrap();
+    let manifest = registry.manifest("packaged/mariadb", "latest").unwrap();
+    let layer = manifest["layers"][0]["digest"].as_str().unwrap();
+    let layer = registry.blob(layer).unwrap();
+    let mut layer = tar::Archive::new(layer.as_slice());
+    let modes: BTreeMap<_, _> = layer
+        .entries()
+        .unwrap()
+        .map(|entry| {
+            let entry = entry.unwrap();
+            let path = entry.path().unwrap().to_string_lossy().to_string();
+            (path, entry.header().mode().unwrap())
+        })
+        .collect();
+    assert_eq!(modes["docker-entrypoint.sh"], 0o755);
+    client.destroy_model_wait(&packaged.id).await.unwrap();
+
+    // Related keys change together, in one request
+    let requests = client.get_model(&model.id).await.unwrap().requests.len();
     client
         .configure_rune_wait(
             &model.id,
             "mariadb",
-            &RuneConfigure {
-                attribute: "password".into(),
-                value: "password".into(),
-            },
+            &set(&[("user", "admin".into()), ("password", "password".into())]),
         )
         .await
         .unwrap();
+    let model = client.get_model(&model.id).await.unwrap();
+    assert_eq!(model.requests.len(), requests + 1);
+    let mariadb = model.state.runes.get("mariadb").unwrap();
+    assert_eq!(mariadb.state["user"], Some("admin".into()));
+    assert_eq!(mariadb.secrets["password"], Some(REDACTED.into()));
+    assert_eq!(
+        read_secrets("mariadb", SECRETS_TOKEN).await.unwrap()["password"],
+        Some("password".into())
+    );
+    // Nor are they in the request that set them
+    match &model.requests.last().unwrap().action {
+        Action::ConfigureRune { config, .. } => {
+            assert_eq!(config["user"], Some("admin".into()));
+            assert_eq!(config["password"], Some(REDACTED.into()));
+        }
+        other => panic!("Expected to configure the rune, got {:?}", other),
+    }
+
+    // Values are checked against their config items before anything is
+    // queued, and strings are parsed as the item's type
+    let configure = |config: RuneConfigure| {
+        let model_id = model.id.clone();
+        let client = &client;
+        async move {
+            client
+                .configure_rune_wait(&model_id, "mariadb", &config)
+                .await
+        }
+    };
+    let bad_request = |result: Result<(), ClientError>| match result {
+        Err(ClientError::BadRequest(message)) => message,
+        other => panic!("Expected a bad request, got {:?}", other),
+    };
+    configure(set(&[("port", "3307".into())])).await.unwrap();
+    assert_eq!(
+        bad_request(configure(set(&[("user", "root".into()), ("port", "abc".into())])).await),
+        "Config item port must be of type integer"
+    );
+    assert_eq!(
+        bad_request(configure(set(&[("colour", "blue".into())])).await),
+        "Config item colour isn't declared"
+    );
+    assert!(client
+        .configure_rune(&model.id, "missing", &set(&[("port", json!(3306))]))
+        .await
+        .is_err());
+
+    // Nor can a rune be added whose defaults leave a required item unset
+    let mut unset = rune.clone();
+    unset.metadata.config.insert(
+        "schema".into(),
+        serde_yaml::from_str("{type: string, description: Schema, required: true}").unwrap(),
+    );
+    match client.add_rune(&model.id, "unset", &unset).await {
+        Err(ClientError::BadRequest(message)) => {
+            assert_eq!(message, "Config item schema is required")
+        }
+        other => panic!("Expected a bad request, got {:?}", other),
+    }
 
     let model = client.get_model(&model.id).await.unwrap();
     let mariadb = model.state.runes.get("mariadb").unwrap();
+    assert_eq!(mariadb.state["user"], Some("admin".into()));
+    assert_eq!(mariadb.state["port"], Some(json!(3307)));
+
+    // Keys can be reset to their defaults, or to no value if they have none
+    configure(RuneConfigure {
+        config: Default::default(),
+        reset: vec!["port".into(), "user".into(), "password".into()],
+    })
+    .await
+    .unwrap();
+    let model = client.get_model(&model.id).await.unwrap();
+    let mariadb = model.state.runes.get("mariadb").unwrap();
+    assert_eq!(mariadb.state, expected);
+    let values = read_secrets("mariadb", SECRETS_TOKEN).await.unwrap();
+    assert_eq!(values["password"], None);
+    assert_eq!(values["root-password"], Some(root_password));
+
+    // Secrets resolved by the client are added along with the rune, and are
+    // never replaced by generated ones
+    let mut secrets = BTreeMap::new();
+    secrets.insert("password".to_string(), "from-env".to_string());
+    secrets.insert("root-password".to_string(), "given".to_string());
+    let add = |name: &'static str, secrets: BTreeMap<String, String>| {
+        let model_id = model.id.clone();
+        let client = &client;
+        let source = RuneSource::Rune(rune.zip().unwrap());
+        async move {
+            let id = client
+                .add_rune_with_secrets(&model_id, name, source, &secrets)
+                .await?;
+            client.wait_for_action(&model_id, id).await
+        }
+    };
+    add("with-secrets", secrets).await.unwrap();
+    let values = read_secrets("with-secrets", SECRETS_TOKEN).await.unwrap();
+    assert_eq!(values["password"], Some("from-env".into()));
+    assert_eq!(values["root-password"], Some("given".into()));
+
+    let mut secrets = BTreeMap::new();
+    secrets.insert("user".to_string(), "admin".to_string());
+    assert_eq!(
+        bad_request(add("not-secret", secrets).await),
+        "Config item user isn't a secret"
+    );
+}
+
+/// Adds mariadb as `name`, along with the one secret it can't generate
+async fn add_mariadb(client: &Client, model_id: &str, name: &str, rune: Rune) {
+    let mut secrets = BTreeMap::new();
+    secrets.insert("password".to_string(), "from-env".to_string());
+    let source = RuneSource::Rune(rune.zip().unwrap());
+    let id = client
+        .add_rune_with_secrets(model_id, name, source, &secrets)
+        .await
+        .unwrap();
+    client.wait_for_action(model_id, id).await.unwrap();
+}
+
+/// pipelines-api without its minio relation, which no example rune provides
+fn pipelines_api() -> Rune {
+    let mut rune = Rune::load("../example-runes/pipelines-api/").unwrap();
+    rune.metadata.requires.retain(|r| r.name != "minio");
+    rune.template[0]
+        .environment
+        .retain(|key, _| !key.starts_with("MINIO_"));
+    rune
+}
+
+/// Sets each key to its value, without resetting any
+fn set(values: &[(&str, Value)]) -> RuneConfigure {
+    RuneConfigure {
+        config: values
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.clone()))
+            .collect(),
+        reset: vec![],
+    }
+}
+
+async fn test_repository() {
+    let client = Client::new(URL);
+    let mut rune = Rune::load("../example-runes/mariadb/").unwrap();
+    let archive = rune.zip().unwrap();
+
+    // Uploading the same rune twice gives the same revision
+    let first = client
+        .upload_rune("repository-mariadb", archive.clone(), None)
+        .await
+        .unwrap();
+    assert_eq!(first.revision, 1);
+    assert_eq!(first.digest, rune.digest().unwrap());
+    assert_eq!(
+        client
+            .upload_rune("repository-mariadb", archive.clone(), None)
+            .await
+            .unwrap(),
+        first
+    );
+
+    rune.metadata.description = "Changed".into();
+    let second = client
+        .upload_rune("repository-mariadb", rune.zip().unwrap(), None)
+        .await
+        .unwrap();
+    assert_eq!(second.revision, 2);
     assert_eq!(
-        mariadb.state.get("password").unwrap(),
-        &Some("password".into())
+        client
+            .list_rune_revisions("repository-mariadb")
+            .await
+            .unwrap(),
+        vec![first.clone(), second.clone()]
     );
+    assert!(client.list_runes().await.unwrap().contains(&second));
+
+    // Archives come back as uploaded
+    let fetched = client.get_rune("repository-mariadb", 1).await.unwrap();
+    assert_eq!(fetched.archive, archive);
+    assert_eq!(fetched.digest, first.digest);
+
+    // Pushing and pulling check digests on both ends
+    let pushed = client
+        .push_rune("repository-mariadb", rune.zip().unwrap(), None)
+        .await
+        .unwrap();
+    assert_eq!(pushed, second);
+    let pulled = client
+        .pull_rune("repository-mariadb", &Selector::Latest)
+        .await
+        .unwrap();
+    assert_eq!(pulled.summary(), second);
+    assert_eq!(
+        Rune::unzip(&pulled.archive).unwrap().metadata.description,
+        "Changed"
+    );
+    assert!(client
+        .pull_rune("repository-missing", &Selector::Latest)
+        .await
+        .is_err());
+
+    // Uploads can tag the revision they result in, new or not
+    let tagged = client
+        .push_rune("repository-mariadb", rune.zip().unwrap(), Some("stable"))
+        .await
+        .unwrap();
+    assert_eq!(tagged.revision, 2);
+    assert_eq!(tagged.tags, vec!["stable".to_string()]);
+    let pulled = client
+        .pull_rune("repository-mariadb", &Selector::Tag("stable".into()))
+        .await
+        .unwrap();
+    assert_eq!(pulled.revision, 2);
+    match client
+        .pull_rune("repository-mariadb", &Selector::Tag("missing".into()))
+        .await
+    {
+        Err(ClientError::TagNotFound(name, tag)) => {
+            assert_eq!(
+                (name.as_str(), tag.as_str()),
+                ("repository-mariadb", "missing")
+            )
+        }
+        other => panic!("Expected a missing tag, got {:?}", other),
+    }
+    // Numbers would read as revisions
+    assert!(client
+        .upload_rune("repository-mariadb", rune.zip().unwrap(), Some("3"))
+        .await
+        .is_err());
+
+    // Models can add a rune by reference instead of uploading it
+    let model = client
+        .create_model(&ModelCreate {
+            name: "test-repository".into(),
+            cloud: "dummy".into(),
+        })
+        .await
+        .unwrap();
+    client
+        .add_rune_reference_wait(&model.id, "mariadb", "repository-mariadb@1")
+        .await
+        .unwrap();
+    client
+        .add_rune_reference_wait(&model.id, "latest", "repository-mariadb")
+        .await
+        .unwrap();
+    let model = client.get_model(&model.id).await.unwrap();
+    client
+        .add_rune_reference_wait(&model.id, "stable", "repository-mariadb@stable")
+        .await
+        .unwrap();
+    let model = client.get_model(&model.id).await.unwrap();
+    assert!(model.state.runes.contains_key("mariadb"));
+    assert!(model.state.runes.contains_key("latest"));
+    assert!(model.state.runes.contains_key("stable"));
+    assert!(client
+        .add_rune_reference(&model.id, "missing", "repository-mariadb@3")
+        .await
+        .is_err());
+    match client
+        .add_rune_reference(&model.id, "invalid", "repository-mariadb@")
+        .await
+    {
+        Err(ClientError::BadRequest(message)) => {
+            assert_eq!(message, "Invalid rune reference repository-mariadb@")
+        }
+        other => panic!("Expected a bad request, got {:?}", other),
+    }
+
+    // Archives that don't match their manifest are rejected as bad requests,
+    // saying why
+    let mut writer = ZipWriter::new(Cursor::new(vec![]));
+    for (name, contents) in archive::read(&archive).unwrap() {
+        let contents = match name.as_str() {
+            "metadata.yaml" => contents.iter().chain(b"\n# Tampered").copied().collect(),
+            _ => contents,
+        };
+        writer.start_file(name, FileOptions::default()).unwrap();
+        writer.write_all(&contents).unwrap();
+    }
+    let tampered = writer.finish().unwrap().into_inner();
+    let response = reqwest::Client::new()
+        .post(&format!("{}/api/v1/runes", URL))
+        .json(&RuneUpload {
+            name: "repository-tampered".into(),
+            rune: tampered.clone(),
+            tag: None,
+        })
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    assert_eq!(
+        response.json::<ErrorMessage>().await.unwrap().message,
+        "Rune archive doesn't match its manifest"
+    );
+    match client
+        .add_rune_archive(&model.id, "tampered", tampered)
+        .await
+    {
+        Err(ClientError::BadRequest(message)) => {
+            assert_eq!(message, "Rune archive doesn't match its manifest")
+        }
+        other => panic!("Expected a bad request, got {:?}", other),
+    }
+
+    // Deleted revisions are gone from the repository, but not from models
+    client.delete_rune("repository-mariadb", 2).await.unwrap();
+    assert!(client.get_rune("repository-mariadb", 2).await.is_err());
+    assert!(client.delete_rune("repository-mariadb", 2).await.is_err());
+    assert_eq!(
+        client
+            .list_rune_revisions("repository-mariadb")
+            .await
+            .unwrap(),
+        vec![first]
+    );
+    let model = client.get_model(&model.id).await.unwrap();
+    assert!(model.state.runes.contains_key("latest"));
+
+    // Along with their tags, which can then be moved to another revision
+    assert!(client
+        .add_rune_reference(&model.id, "gone", "repository-mariadb@stable")
+        .await
+        .is_err());
+    let tagged = client
+        .upload_rune("repository-mariadb", archive.clone(), Some("stable"))
+        .await
+        .unwrap();
+    assert_eq!(tagged.revision, 1);
+    assert_eq!(tagged.tags, vec!["stable".to_string()]);
+
+    // Deleted revisions aren't given out again, even to the same rune
+    let third = client
+        .upload_rune("repository-mariadb", rune.zip().unwrap(), None)
+        .await
+        .unwrap();
+    assert_eq!(third.revision, 3);
+    assert!(client
+        .add_rune_reference(&model.id, "reused", "repository-mariadb@2")
+        .await
+        .is_err());
+}
+
+async fn test_kubernetes(kubernetes: FakeKubernetes) {
+    let client = Client::new(URL);
+    let model = client
+        .create_model(&ModelCreate {
+            name: "test-kubernetes".into(),
+            cloud: "kubernetes".into(),
+        })
+        .await
+        .unwrap();
+    let mariadb = Rune::load("../example-runes/mariadb/").unwrap();
+    add_mariadb(&client, &model.id, "mariadb", mariadb).await;
+    client
+        .add_rune_wait(&model.id, "pipelines-api", &pipelines_api())
+        .await
+        .unwrap();
+
+    // Templates are rendered against the model, with relations to the runes
+    // that provide them
+    assert!(kubernetes.namespace("test-kubernetes").is_some());
+    let env = |name: &str| {
+        let deployment = kubernetes.deployment("test-kubernetes", name).unwrap();
+        let container = &deployment["spec"]["template"]["spec"]["containers"][0];
+        container["env"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|var| {
+                let value = var["value"].as_str().unwrap().to_string();
+                (var["name"].as_str().unwrap().to_string(), value)
+            })
+            .collect::<BTreeMap<_, _>>()
+    };
+    assert_eq!(env("mariadb")["MYSQL_DATABASE"], "mysql-db");
+    assert_eq!(env("mariadb")["MYSQL_USER"], "mysql-user");
+    let api = env("pipelines-api");
+    assert_eq!(api["MYSQL_SERVICE_HOST"], "mariadb");
+    assert_eq!(api["MYSQL_SERVICE_PORT"], "3306");
+    assert_eq!(api["POD_NAMESPACE"], "test-kubernetes");
+    assert_eq!(api["InitConnectionTimeout"], "6m");
+
+    // Secrets reach the workload, whether given or generated on deploy, as do
+    // those of related runes
+    let secrets = client
+        .get_rune_secrets(&model.id, "mariadb", SECRETS_TOKEN)
+        .await
+        .unwrap();
+    let root_password = secrets["root-password"].clone().unwrap();
+    assert_eq!(env("mariadb")["MYSQL_ROOT_PASSWORD"], root_password);
+    assert_eq!(env("mariadb")["MYSQL_PASSWORD"], "from-env");
+    assert_eq!(api["MYSQL_SERVICE_PASS"], "from-env");
+    let deployment = kubernetes
+        .deployment("test-kubernetes", "pipelines-api")
+        .unwrap();
+    assert_eq!(
+        deployment["spec"]["template"]["spec"]["containers"][0]["image"],
+        "gcr.io/ml-pipeline/api-server:0.1.14"
+    );
+
+    // Containers expose their ports, along with anything included for them
+    let deployment = kubernetes.deployment("test-kubernetes", "mariadb").unwrap();
+    let container = &deployment["spec"]["template"]["spec"]["containers"][0];
+    assert_eq!(
+        container["ports"],
+        json!([{"name": "mariadb", "containerPort": 3306}])
+    );
+    assert_eq!(
+        container["readinessProbe"]["httpGet"]["path"],
+        "/example/check"
+    );
+
+    // Configuring a rune reapplies it, along with the runes related to it
+    client
+        .configure_rune_wait(
+            &model.id,
+            "mariadb",
+            &set(&[("database", "other-db".into()), ("port", json!(3307))]),
+        )
+        .await
+        .unwrap();
+    assert_eq!(env("mariadb")["MYSQL_DATABASE"], "other-db");
+    assert_eq!(env("pipelines-api")["MYSQL_SERVICE_PORT"], "3307");
+
+    // Models have nothing to reapply, so configuring them fails outright
+    match client
+        .configure_model_wait(
+            &model.id,
+            &ModelConfigure {
+                foo: Some("bar".into()),
+            },
+        )
+        .await
+    {
+        Err(ClientError::RequestFailed(_, error)) => assert_eq!(
+            error,
+            "Configuring models on the kubernetes cloud isn't supported"
+        ),
+        other => panic!("Expected configuring to fail, got {:?}", other),
+    }
+
+    // A rune whose templates can't be rendered fails to deploy
+    let ui = Rune::load("../example-runes/pipelines-ui/").unwrap();
+    match client.add_rune_wait(&model.id, "pipelines-ui", &ui).await {
+        Err(ClientError::RequestFailed(_, error)) => assert!(
+            error.starts_with("Unresolved references:"),
+            "Unexpected error {}",
+            error
+        ),
+        other => panic!("Expected the request to fail, got {:?}", other),
+    }
+    assert!(kubernetes
+        .deployment("test-kubernetes", "pipelines-ui")
+        .is_none());
+    let model = client.get_model(&model.id).await.unwrap();
+    assert!(!model.state.runes.contains_key("pipelines-ui"));
+
+    client.destroy_model_wait(&model.id).await.unwrap();
+    assert!(kubernetes.namespace("test-kubernetes").is_none());
+    assert!(kubernetes
+        .deployment("test-kubernetes", "pipelines-api")
+        .is_none());
+}
+
+async fn test_registered_clouds(kubernetes: FakeKubernetes, kubeconfig: String) {
+    let client = Client::new(URL);
+    let credentials = CloudCredentials::Kubernetes {
+        kubeconfig: Some(kubeconfig),
+        context: Some("fake".into()),
+    };
+
+    // Builtin names are taken
+    assert!(client
+        .create_cloud("kubernetes", &credentials)
+        .await
+        .is_err());
+
+    let staging = client.create_cloud("staging", &credentials).await.unwrap();
+    assert_eq!(
+        staging,
+        RegisteredCloud {
+            name: "staging".into(),
+            cloud: "kubernetes".into(),
+            builtin: false,
+        }
+    );
+    assert!(client.create_cloud("staging", &credentials).await.is_err());
+    assert!(client.list_clouds().await.unwrap().contains(&staging));
+    assert!(client.get_cloud("kubernetes").await.unwrap().builtin);
+
+    let model = client
+        .create_model(&ModelCreate {
+            name: "test-staging".into(),
+            cloud: "staging".into(),
+        })
+        .await
+        .unwrap();
+    assert_eq!(model.cloud, "staging");
+
+    let rune = Rune::load("../example-runes/mariadb/").unwrap();
+    add_mariadb(&client, &model.id, "mariadb", rune).await;
+    assert!(kubernetes.deployment("test-staging", "mariadb").is_some());
+
+    // Can't be removed while a model still uses it
+    assert!(client.delete_cloud("staging").await.is_err());
+    client.destroy_model_wait(&model.id).await.unwrap();
+    client.delete_cloud("staging").await.unwrap();
+    assert!(client.get_cloud("staging").await.is_err());
+    assert!(client
+        .create_model(&ModelCreate {
+            name: "test-staging-gone".into(),
+            cloud: "staging".into(),
+        })
+        .await
+        .is_err());
+}
+
+async fn test_aws(cloudformation: FakeCloudFormation) {
+    let client = Client::new(URL);
+    let model = client
+        .create_model(&ModelCreate {
+            name: "test-aws".into(),
+            cloud: "aws".into(),
+        })
+        .await
+        .unwrap();
+    let rune = Rune::load("../example-runes/mariadb/").unwrap();
+    add_mariadb(&client, &model.id, "mariadb", rune).await;
+    client
+        .add_rune_wait(&model.id, "pipelines-api", &pipelines_api())
+        .await
+        .unwrap();
+
+    let stack = cloudformation.stack("test-aws").unwrap();
+    let resources = &stack["Resources"];
+    assert_eq!(
+        resources["Cluster"]["Properties"]["ClusterName"],
+        "test-aws"
+    );
+    let container =
+        &resources["PipelinesApiTaskDefinition"]["Properties"]["ContainerDefinitions"][0];
+    assert_eq!(container["Image"], "gcr.io/ml-pipeline/api-server:0.1.14");
+    assert!(container["Environment"]
+        .as_array()
+        .unwrap()
+        .contains(&json!({"Name": "MYSQL_SERVICE_HOST", "Value": "mariadb"})));
+    assert_eq!(
+        resources["PipelinesApiService"]["Properties"]["TaskDefinition"]["Ref"],
+        "PipelinesApiTaskDefinition"
+    );
+    assert!(resources["MariadbService"].is_object());
+
+    client.destroy_model_wait(&model.id).await.unwrap();
+    assert!(cloudformation.stack("test-aws").is_none());
+}
+
+async fn test_gce(deploymentmanager: FakeDeploymentManager) {
+    let client = Client::new(URL);
+    let model = client
+        .create_model(&ModelCreate {
+            name: "test-gce".into(),
+            cloud: "gce".into(),
+        })
+        .await
+        .unwrap();
+    let rune = Rune::load("../example-runes/mariadb/").unwrap();
+    add_mariadb(&client, &model.id, "mariadb", rune).await;
+    client
+        .add_rune_wait(&model.id, "pipelines-api", &pipelines_api())
+        .await
+        .unwrap();
+
+    let deployment = deploymentmanager.deployment("test-gce").unwrap();
+    let resources = deployment["resources"].as_array().unwrap();
+    let names = |kind: &str| {
+        resources
+            .iter()
+            .filter(|resource| resource["type"] == kind)
+            .map(|resource| resource["name"].as_str().unwrap())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(
+        names("compute.v1.instance"),
+        vec!["test-gce-mariadb", "test-gce-pipelines-api"]
+    );
+    assert_eq!(
+        names("compute.v1.firewall"),
+        vec!["test-gce-mariadb-ports", "test-gce-pipelines-api-ports"]
+    );
+
+    client.destroy_model_wait(&model.id).await.unwrap();
+    assert!(deploymentmanager.deployment("test-gce").is_none());
+}
+
+async fn test_local(local_dir: PathBuf) {
+    let client = Client::new(URL);
+    let model = client
+        .create_model(&ModelCreate {
+            name: "test-local".into(),
+            cloud: "local".into(),
+        })
+        .await
+        .unwrap();
+
+    // The program is swapped out for the configured local binary
+    let mut rune = Rune::load("../example-runes/mariadb/").unwrap();
+    rune.template[0].command = vec![
+        "/does/not/exist".into(),
+        "-c".into(),
+        "echo $GREETING $MYSQL_DATABASE $PORT_MARIADB > greeting; echo $$ > pid; exec sleep 60"
+            .into(),
+    ];
+    rune.template[0].args = vec![];
+    rune.template[0]
+        .environment
+        .insert("GREETING".into(), "hello".into());
+    add_mariadb(&client, &model.id, "local-rune", rune).await;
+
+    let rune_dir = local_dir.join("test-local").join("local-rune");
+    let mut pid = None;
+    for _ in 0u32..10 {
+        if let Ok(p) = read_to_string(rune_dir.join("pid")) {
+            pid = Some(p.trim().to_string());
+            break;
+        }
+        task::sleep(Duration::from_millis(500)).await;
+    }
+    let pid = pid.expect("Local process never started");
+    assert_eq!(
+        read_to_string(rune_dir.join("greeting")).unwrap(),
+        "hello mysql-db 3306\n"
+    );
+    assert!(PathBuf::from("/proc").join(&pid).exists());
+
+    // Configuring the rune restarts it with the new config
+    client
+        .configure_rune_wait(
+            &model.id,
+            "local-rune",
+            &set(&[("database", "other-db".into())]),
+        )
+        .await
+        .unwrap();
+    let mut greeting = None;
+    for _ in 0u32..10 {
+        greeting = read_to_string(rune_dir.join("greeting")).ok();
+        if greeting.as_deref() == Some("hello other-db 3306\n") {
+            break;
+        }
+        task::sleep(Duration::from_millis(500)).await;
+    }
+    assert_eq!(greeting.unwrap(), "hello other-db 3306\n");
+    let pid = read_to_string(rune_dir.join("pid"))
+        .unwrap()
+        .trim()
+        .to_string();
+    assert!(PathBuf::from("/proc").join(&pid).exists());
+
+    // Ports are checked once rendered, before the rune is touched
+    match client
+        .configure_rune_wait(&model.id, "local-rune", &set(&[("port", json!(70000))]))
+        .await
+    {
+        Err(ClientError::RequestFailed(_, error)) => assert_eq!(
+            error,
+            "Port mariadb of mariadb in mariadb is 70000, which isn't a valid port"
+        ),
+        other => panic!("Expected an invalid port, got {:?}", other),
+    }
+
+    // Destroying the model stops its processes, and waits on them
+    client.destroy_model_wait(&model.id).await.unwrap();
+    assert!(!PathBuf::from("/proc").join(&pid).exists());
+}
+
+/// Deploys a local rune that records its pid, returning the model ID and
+/// where the pid is written
+async fn deploy_restored(local_dir: PathBuf) -> (String, PathBuf) {
+    let client = Client::new(URL);
+    let model = client
+        .create_model(&ModelCreate {
+            name: "test-restored".into(),
+            cloud: "local".into(),
+        })
+        .await
+        .unwrap();
+    let mut rune = Rune::load("../example-runes/mariadb/").unwrap();
+    rune.template[0].command = vec![
+        "/does/not/exist".into(),
+        "-c".into(),
+        "echo $$ > pid; exec sleep 60".into(),
+    ];
+    rune.template[0].args = vec![];
+    add_mariadb(&client, &model.id, "local-rune", rune).await;
+    (
+        model.id,
+        local_dir
+            .join("test-restored")
+            .join("local-rune")
+            .join("pid"),
+    )
+}
+
+/// Reads the pid written to `path` once it's something other than `previous`
+async fn read_pid(path: &Path, previous: Option<&str>) -> String {
+    for _ in 0u32..10 {
+        if let Ok(pid) = read_to_string(path) {
+            if Some(pid.trim()) != previous {
+                return pid.trim().to_string();
+            }
+        }
+        task::sleep(Duration::from_millis(500)).await;
+    }
+    panic!("Local process never started");
+}
+
+async fn test_dummy() {
+    let client = Client::new(URL);
+    let model = client
+        .create_model(&ModelCreate {
+            name: "test-dummy".into(),
+            cloud: "dummy".into(),
+        })
+        .await
+        .unwrap();
+
+    // Adding a rune fails twice before the controller's retries get it through
+    let mut failures = HashMap::new();
+    failures.insert("AddRune".to_string(), 2);
+    client
+        .configure_dummy(
+            &model.id,
+            &DummyConfig {
+                latency_ms: 50,
+                failure_probability: 0.0,
+                failures,
+            },
+        )
+        .await
+        .unwrap();
+
+    let rune = Rune::load("../example-runes/mariadb/").unwrap();
+    client
+        .add_rune_wait(&model.id, "mariadb", &rune)
+        .await
+        .unwrap();
+    client
+        .configure_rune_wait(&model.id, "mariadb", &set(&[("user", "admin".into())]))
+        .await
+        .unwrap();
+
+    let world = client.get_dummy_world(&model.id).await.unwrap();
+    let event = |action: &str, succeeded| DummyEvent {
+        action: action.into(),
+        succeeded,
+    };
+    assert_eq!(
+        world.events,
+        vec![
+            event("AddRune", false),
+            event("AddRune", false),
+            event("AddRune", true),
+            event("ConfigureRune", true),
+        ]
+    );
+    assert_eq!(
+        world.runes["mariadb"].state.get("user").unwrap(),
+        &Some("admin".into())
+    );
+
+    // Once it runs out of attempts, a request fails with its error, without
+    // changing the model or holding up the requests after it
+    let mut failures = HashMap::new();
+    failures.insert("ConfigureRune".to_string(), 4);
+    client
+        .configure_dummy(
+            &model.id,
+            &DummyConfig {
+                latency_ms: 0,
+                failure_probability: 0.0,
+                failures,
+            },
+        )
+        .await
+        .unwrap();
+    let failed = client
+        .configure_rune(&model.id, "mariadb", &set(&[("user", "root".into())]))
+        .await
+        .unwrap();
+    client
+        .configure_rune_wait(&model.id, "mariadb", &set(&[("database", "db".into())]))
+        .await
+        .unwrap();
+    match client.wait_for_action(&model.id, failed).await {
+        Err(ClientError::RequestFailed(id, error)) => {
+            assert_eq!(id, failed);
+            assert_eq!(error, "Injected failure of ConfigureRune");
+        }
+        other => panic!("Expected the request to fail, got {:?}", other),
+    }
+    let mariadb = &client.get_model(&model.id).await.unwrap().state.runes["mariadb"];
+    assert_eq!(mariadb.state["user"], Some("admin".into()));
+    assert_eq!(mariadb.state["database"], Some("db".into()));
+
+    client.destroy_model_wait(&model.id).await.unwrap();
+    let world = client.get_dummy_world(&model.id).await.unwrap();
+    assert!(world.destroyed);
+    assert!(world.runes.is_empty());
 }
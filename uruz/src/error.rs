@@ -1,10 +1,33 @@
+use liburuz::client::error::Error as ClientError;
 use liburuz::rune::Error as RuneError;
+use liburuz::server::error::Error as CloudError;
+use serde_json::Error as SerdeJsonError;
+use std::fmt;
 use std::io::Error as IOError;
 
 #[derive(Debug)]
 pub enum Error {
     IOError(IOError),
     RuneError(RuneError),
+    CloudError(CloudError),
+    ClientError(ClientError),
+    SerdeJsonError(SerdeJsonError),
+    Unsupported(String),
+    LintFailed(usize),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IOError(err) => write!(f, "{}", err),
+            Error::RuneError(err) => write!(f, "{}", err),
+            Error::CloudError(err) => write!(f, "{:?}", err),
+            Error::ClientError(err) => write!(f, "{}", err),
+            Error::SerdeJsonError(err) => write!(f, "{}", err),
+            Error::Unsupported(cloud) => write!(f, "Rendering for {} isn't supported", cloud),
+            Error::LintFailed(count) => write!(f, "Found {} problems", count),
+        }
+    }
 }
 
 impl From<IOError> for Error {
@@ -18,3 +41,21 @@ impl From<RuneError> for Error {
         Error::RuneError(err)
     }
 }
+
+impl From<CloudError> for Error {
+    fn from(err: CloudError) -> Self {
+        Error::CloudError(err)
+    }
+}
+
+impl From<ClientError> for Error {
+    fn from(err: ClientError) -> Self {
+        Error::ClientError(err)
+    }
+}
+
+impl From<SerdeJsonError> for Error {
+    fn from(err: SerdeJsonError) -> Self {
+        Error::SerdeJsonError(err)
+    }
+}
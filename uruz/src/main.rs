@@ -1,9 +1,23 @@
 mod error;
 
 use error::Error;
-use liburuz::rune::v1::Rune;
-use std::fs::write;
+use liburuz::api::v1::RuneSource;
+use liburuz::client::api::v1::Client;
+use liburuz::client::error::Error as ClientError;
+use liburuz::client::secrets;
+use liburuz::clouds::{aws, gce, rendered, Cloud};
+use liburuz::rune::v1::lint::Severity;
+use liburuz::rune::v1::{schema, signature, Rune};
+use liburuz::rune::version;
+use liburuz::server::config::{FargateConfig, GceConfig};
+use liburuz::server::model::{Action, Active, Model, Queued};
+use liburuz::server::repository::parse_reference;
+use std::fs::{read, write, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
 use structopt::{self, clap::AppSettings, StructOpt};
+use tokio::runtime::Runtime;
 
 #[derive(StructOpt, Debug)]
 struct BuildConfig {
@@ -13,6 +27,132 @@ struct BuildConfig {
     #[structopt(short = "o", long = "output")]
     #[structopt(help = "Where to output the rune")]
     output_path: Option<String>,
+
+    #[structopt(long = "sign")]
+    #[structopt(help = "Path to a secret key to sign the rune with")]
+    sign: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+struct RenderConfig {
+    #[structopt(help = "Path to rune")]
+    path: String,
+
+    #[structopt(short = "c", long = "cloud", default_value = "aws")]
+    #[structopt(help = "Cloud to render the rune for")]
+    cloud: String,
+
+    #[structopt(short = "m", long = "model", default_value = "uruz")]
+    #[structopt(help = "Name of the model the rune is rendered into")]
+    model: String,
+
+    #[structopt(long = "env-dir", default_value = ".")]
+    #[structopt(help = "Directory to read env files for the rune's secrets from")]
+    env_dir: String,
+}
+
+#[derive(StructOpt, Debug)]
+struct LintConfig {
+    #[structopt(help = "Path to rune")]
+    path: String,
+
+    #[structopt(short = "D", long = "deny-warnings")]
+    #[structopt(help = "Fail on warnings as well as errors")]
+    deny_warnings: bool,
+}
+
+#[derive(StructOpt, Debug)]
+struct KeygenConfig {
+    #[structopt(help = "Where to write the secret key, with the public key next to it in .pub")]
+    path: String,
+}
+
+#[derive(StructOpt, Debug)]
+struct MigrateConfig {
+    #[structopt(help = "Path to rune")]
+    path: String,
+}
+
+#[derive(StructOpt, Debug)]
+struct SchemaConfig {
+    #[structopt(possible_values = &["metadata", "rune"])]
+    #[structopt(help = "Which file to print the schema for")]
+    file: String,
+
+    #[structopt(short = "o", long = "output")]
+    #[structopt(help = "Where to write the schema, instead of printing it")]
+    output_path: Option<String>,
+}
+
+#[derive(StructOpt, Debug)]
+struct PushConfig {
+    #[structopt(help = "Path to a rune built with `uruz build`")]
+    path: String,
+
+    #[structopt(help = "Name to push the rune as, such as mariadb")]
+    name: String,
+
+    #[structopt(short = "t", long = "tag")]
+    #[structopt(help = "Tag to point at the pushed revision, such as stable")]
+    tag: Option<String>,
+
+    #[structopt(
+        short = "e",
+        long = "endpoint",
+        default_value = "http://localhost:8000"
+    )]
+    #[structopt(help = "Controller to push the rune to")]
+    endpoint: String,
+}
+
+#[derive(StructOpt, Debug)]
+struct PullConfig {
+    #[structopt(
+        help = "Rune to pull, as name@revision, name@tag, or just name for its latest revision"
+    )]
+    reference: String,
+
+    #[structopt(short = "o", long = "output")]
+    #[structopt(help = "Where to write the rune")]
+    output_path: Option<String>,
+
+    #[structopt(long = "digest")]
+    #[structopt(help = "Digest the rune must have, as printed by `uruz build`")]
+    digest: Option<String>,
+
+    #[structopt(
+        short = "e",
+        long = "endpoint",
+        default_value = "http://localhost:8000"
+    )]
+    #[structopt(help = "Controller to pull the rune from")]
+    endpoint: String,
+}
+
+#[derive(StructOpt, Debug)]
+struct AddConfig {
+    #[structopt(help = "ID of the model to add the rune to")]
+    model: String,
+
+    #[structopt(help = "Name to add the rune as")]
+    name: String,
+
+    #[structopt(
+        help = "Path to a rune built with `uruz build`, or else a rune in the controller's repository, as name@revision, name@tag, or just name"
+    )]
+    rune: String,
+
+    #[structopt(long = "env-dir", default_value = ".")]
+    #[structopt(help = "Directory to read env files for the rune's secrets from")]
+    env_dir: String,
+
+    #[structopt(
+        short = "e",
+        long = "endpoint",
+        default_value = "http://localhost:8000"
+    )]
+    #[structopt(help = "Controller to add the rune with")]
+    endpoint: String,
 }
 
 /// Interact with a bundle and the runes contained therein.
@@ -22,21 +162,205 @@ struct BuildConfig {
 enum Config {
     #[structopt(name = "build")]
     Build(BuildConfig),
+
+    /// Print the template a cloud would deploy for a rune, without deploying it
+    #[structopt(name = "render")]
+    Render(RenderConfig),
+
+    /// Check a rune for problems, exiting with an error if any are found
+    #[structopt(name = "lint")]
+    Lint(LintConfig),
+
+    /// Generate a key pair for signing runes
+    #[structopt(name = "keygen")]
+    Keygen(KeygenConfig),
+
+    /// Rewrite a rune directory in the latest rune format
+    #[structopt(name = "migrate")]
+    Migrate(MigrateConfig),
+
+    /// Print the JSON Schema for metadata.yaml or rune.yaml
+    #[structopt(name = "schema")]
+    Schema(SchemaConfig),
+
+    /// Upload a built rune to a controller's repository as its next revision
+    #[structopt(name = "push")]
+    Push(PushConfig),
+
+    /// Download a rune from a controller's repository
+    #[structopt(name = "pull")]
+    Pull(PullConfig),
+
+    /// Add a rune to a model, resolving any of its secrets read from env files
+    #[structopt(name = "add")]
+    Add(AddConfig),
 }
 
 fn build(c: BuildConfig) -> Result<(), Error> {
     let rune = Rune::load(c.path)?;
-    let zipped = rune.zip()?;
-    write(
-        c.output_path
-            .unwrap_or_else(|| format!("{}.rune", rune.metadata.name)),
-        zipped,
-    )?;
+    let zipped = match c.sign {
+        Some(key) => rune.zip_signed(&signature::read_key(Path::new(&key))?)?,
+        None => rune.zip()?,
+    };
+    let output_path = c
+        .output_path
+        .unwrap_or_else(|| format!("{}.rune", rune.metadata.name));
+    write(&output_path, zipped)?;
+    println!("{} {}", rune.digest()?, output_path);
+    Ok(())
+}
+
+/// Renders a rune as it would be deployed when added to an empty model, with
+/// its default config and any secrets it reads from env files or generates
+fn render(c: RenderConfig) -> Result<(), Error> {
+    let mut rune = Rune::load(c.path)?;
+    rune.secrets = secrets::resolve_env(&rune, Path::new(&c.env_dir))?;
+    rune.generate_secrets();
+    let name = rune.metadata.name.clone();
+    let model = Model::with_name(c.model.clone(), c.cloud.clone());
+    let request = Active::from_queued(Queued::from_action(Action::AddRune { name, rune }, 0), 0);
+    let runes = rendered(&model, &request)?;
+
+    let rendered = match Cloud::from_str(&c.cloud)? {
+        Cloud::AWS => aws::render(&c.model, &FargateConfig::default(), &runes)?,
+        Cloud::GCE => gce::render(&c.model, &GceConfig::default().zone, &runes)?,
+        cloud => return Err(Error::Unsupported(cloud.to_string())),
+    };
+    println!("{}", serde_json::to_string_pretty(&rendered)?);
+    Ok(())
+}
+
+fn lint(c: LintConfig) -> Result<(), Error> {
+    let rune = Rune::read(&c.path)?;
+    let problems = rune.lint();
+    for problem in &problems {
+        println!("{}/{}", c.path.trim_end_matches('/'), problem);
+    }
+
+    let failures = problems
+        .iter()
+        .filter(|p| c.deny_warnings || p.severity == Severity::Error)
+        .count();
+    if failures > 0 {
+        return Err(Error::LintFailed(failures));
+    }
+    Ok(())
+}
+
+fn keygen(c: KeygenConfig) -> Result<(), Error> {
+    let key = signature::generate_key();
+    let public = hex::encode(key.public.as_bytes());
+    // Only the owner should be able to read the secret key
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&c.path)?
+        .write_all(hex::encode(key.secret.as_bytes()).as_bytes())?;
+    write(format!("{}.pub", c.path), &public)?;
+    println!("{}", public);
     Ok(())
 }
 
-fn main() -> Result<(), Error> {
+fn migrate(c: MigrateConfig) -> Result<(), Error> {
+    let migrated = version::migrate(Path::new(&c.path))?;
+    if migrated.version == version::LATEST {
+        println!("{} is already at version {}", c.path, version::LATEST);
+    } else {
+        println!(
+            "Migrated {} from version {} to {}",
+            c.path,
+            migrated.version,
+            version::LATEST
+        );
+    }
+    for file in &migrated.reformatted {
+        eprintln!(
+            "Warning: {} couldn't be rewritten in place, so it was reformatted and lost its comments",
+            file
+        );
+    }
+    Ok(())
+}
+
+fn schema(c: SchemaConfig) -> Result<(), Error> {
+    let schema = match c.file.as_str() {
+        "metadata" => schema::metadata(),
+        _ => schema::template(),
+    };
+    let schema = serde_json::to_string_pretty(&schema)?;
+    match c.output_path {
+        Some(path) => write(path, schema)?,
+        None => println!("{}", schema),
+    }
+    Ok(())
+}
+
+fn push(c: PushConfig) -> Result<(), Error> {
+    let client = Client::new(c.endpoint);
+    let pushed =
+        Runtime::new()?.block_on(client.push_rune(&c.name, read(&c.path)?, c.tag.as_deref()))?;
+    println!("{} {}@{}", pushed.digest, pushed.name, pushed.revision);
+    Ok(())
+}
+
+fn pull(c: PullConfig) -> Result<(), Error> {
+    let (name, selector) = parse_reference(&c.reference)?;
+    let client = Client::new(c.endpoint);
+    let pulled = Runtime::new()?.block_on(client.pull_rune(name, &selector))?;
+    if let Some(expected) = c.digest {
+        if expected != pulled.digest {
+            return Err(ClientError::DigestMismatch(expected, pulled.digest).into());
+        }
+    }
+
+    let output_path = c.output_path.unwrap_or_else(|| format!("{}.rune", name));
+    write(&output_path, &pulled.archive)?;
+    println!("{} {}", pulled.digest, output_path);
+    Ok(())
+}
+
+fn add(c: AddConfig) -> Result<(), Error> {
+    let client = Client::new(c.endpoint);
+    let mut rt = Runtime::new()?;
+    let (rune, source) = if Path::new(&c.rune).is_file() {
+        let archive = read(&c.rune)?;
+        (Rune::unzip(&archive)?, RuneSource::Rune(archive))
+    } else {
+        // Pinned to the pulled revision, so the secrets match the rune added
+        let (name, selector) = parse_reference(&c.rune)?;
+        let pulled = rt.block_on(client.pull_rune(name, &selector))?;
+        let reference = format!("{}@{}", pulled.name, pulled.revision);
+        (
+            Rune::unzip(&pulled.archive)?,
+            RuneSource::Reference(reference),
+        )
+    };
+
+    let secrets = secrets::resolve_env(&rune, Path::new(&c.env_dir))?;
+    let id = rt.block_on(client.add_rune_with_secrets(&c.model, &c.name, source, &secrets))?;
+    println!("{}", id);
+    Ok(())
+}
+
+fn run() -> Result<(), Error> {
     match Config::from_args() {
         Config::Build(c) => build(c),
+        Config::Render(c) => render(c),
+        Config::Lint(c) => lint(c),
+        Config::Keygen(c) => keygen(c),
+        Config::Migrate(c) => migrate(c),
+        Config::Schema(c) => schema(c),
+        Config::Push(c) => push(c),
+        Config::Pull(c) => pull(c),
+        Config::Add(c) => add(c),
+    }
+}
+
+fn main() {
+    if let Err(err) = run() {
+        eprintln!("{}", err);
+        std::process::exit(1);
     }
 }